@@ -1,5 +1,7 @@
+use std::time::Duration;
+
 use clap::Parser;
-use dbfs2::fuse::DbfsFuse;
+use dbfs2::fuse::{mkfs::Durability, DbfsFuse};
 use fuser::MountOption;
 
 #[derive(Parser, Debug)]
@@ -8,6 +10,24 @@ struct Args {
     /// Mount point
     #[arg(long)]
     mount_point: String,
+    /// Path of the image
+    #[arg(long, default_value = "./my-database.db")]
+    image: String,
+    /// Don't create the image if it doesn't exist
+    #[arg(long)]
+    no_create: bool,
+    /// Size of the image in GB
+    #[arg(long, default_value_t = 20)]
+    capacity: usize,
+    /// Attribute cache timeout of the kernel in seconds
+    #[arg(long, default_value_t = 1.0)]
+    attr_timeout: f64,
+    /// Name cache timeout of the kernel in seconds
+    #[arg(long, default_value_t = 1.0)]
+    entry_timeout: f64,
+    /// Size of the write-back cache in MB, 0 disables it
+    #[arg(long, default_value_t = 8)]
+    write_cache: usize,
     /// Automatically unmount on process exit
     #[arg(long)]
     auto_unmount: bool,
@@ -20,6 +40,42 @@ struct Args {
     /// Enable setuid support when run as root
     #[arg(long)]
     suid: bool,
+    /// Commit this many operations in one transaction, 0 disables group commit
+    #[arg(long, default_value_t = 0)]
+    group_commit: usize,
+    /// When the data reaches the disk: none, on-fsync or every-commit
+    #[arg(long, default_value = "on-fsync")]
+    durability: String,
+    /// Threads handling the reads and the writes
+    #[arg(long, default_value_t = 1)]
+    threads: usize,
+    /// Let the kernel cache the writes
+    #[arg(long)]
+    writeback_cache: bool,
+    /// Drop the pages of the files on every open
+    #[arg(long)]
+    no_keep_cache: bool,
+    /// Max size of a write request in KB, 0 keeps the default
+    #[arg(long, default_value_t = 0)]
+    max_write: u32,
+    /// Max readahead of the kernel in KB, 0 keeps the default
+    #[arg(long, default_value_t = 0)]
+    max_readahead: u32,
+    /// Max number of the background requests, 0 keeps the default
+    #[arg(long, default_value_t = 0)]
+    max_background: u16,
+    /// Let the kernel choose between readdir and readdirplus
+    #[arg(long)]
+    readdirplus_auto: bool,
+    /// Truncate the files with a setattr after open
+    #[arg(long)]
+    no_atomic_o_trunc: bool,
+    /// Let the kernel check the POSIX ACLs
+    #[arg(long)]
+    acl: bool,
+    /// Allow the mount to be exported by NFS
+    #[arg(long)]
+    export: bool,
     /// Other FUSE options
     #[arg(long)]
     other: Vec<String>,
@@ -49,7 +105,42 @@ fn main() {
     }
 
     // 初始化文件系统
-    let dbfs = DbfsFuse::new(args.direct_io, args.suid);
+    let durability = match args.durability.parse::<Durability>() {
+        Ok(durability) => durability,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let dbfs = DbfsFuse::builder()
+        .path(&args.image)
+        .create(!args.no_create)
+        .capacity(args.capacity * 1024 * 1024 * 1024)
+        .attr_ttl(Duration::from_secs_f64(args.attr_timeout))
+        .entry_ttl(Duration::from_secs_f64(args.entry_timeout))
+        .write_cache_size(args.write_cache * 1024 * 1024)
+        .group_commit(args.group_commit)
+        .durability(durability)
+        .direct_io(args.direct_io)
+        .suid_support(args.suid)
+        .threads(args.threads)
+        .writeback_cache(args.writeback_cache)
+        .keep_cache(!args.no_keep_cache)
+        .max_write(args.max_write * 1024)
+        .max_readahead(args.max_readahead * 1024)
+        .max_background(args.max_background)
+        .readdirplus_auto(args.readdirplus_auto)
+        .atomic_o_trunc(!args.no_atomic_o_trunc)
+        .posix_acl(args.acl)
+        .export_support(args.export)
+        .build();
+    let dbfs = match dbfs {
+        Ok(dbfs) => dbfs,
+        Err(e) => {
+            eprintln!("Failed to open the image {}: {}", args.image, e);
+            std::process::exit(1);
+        }
+    };
 
     // 打印挂载选项供调试
     println!("Mount options: {:?}", options);
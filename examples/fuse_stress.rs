@@ -0,0 +1,191 @@
+//! Run mixed workloads in parallel on a mounted dbfs and check the results
+//!
+//! ```shell
+//! cargo run --release --example fuse -- --mount-point ./bench --threads 8
+//! cargo run --release --example fuse_stress -- --dir ./bench
+//! ```
+//!
+//! Every worker writes its own files and reads them back, renames and removes them,
+//! while all of them append to one shared file and list the shared directory.
+//! It exits with 1 if any data or entry is wrong.
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Directory on the mounted dbfs
+    #[arg(long)]
+    dir: PathBuf,
+    /// Number of the workers
+    #[arg(long, default_value_t = 8)]
+    threads: usize,
+    /// Rounds every worker runs
+    #[arg(long, default_value_t = 50)]
+    rounds: usize,
+    /// Size of the files in KB
+    #[arg(long, default_value_t = 256)]
+    size: usize,
+}
+
+/// The size of one record appended to the shared file
+const RECORD: usize = 64;
+
+/// The content of the file of a worker in a round
+fn pattern(worker: usize, round: usize, len: usize) -> Vec<u8> {
+    let seed = (worker * 7919 + round * 104729) as u64;
+    (0..len as u64)
+        .map(|i| (seed.wrapping_mul(31).wrapping_add(i * 13) % 251) as u8)
+        .collect()
+}
+
+fn record(worker: usize, round: usize) -> Vec<u8> {
+    let mut record = format!("{}:{}", worker, round).into_bytes();
+    record.resize(RECORD - 1, b'.');
+    record.push(b'\n');
+    record
+}
+
+fn run(worker: usize, args: &Args, shared: &Path, errors: &AtomicUsize) -> std::io::Result<()> {
+    let size = args.size * 1024;
+    let fail = |msg: String| {
+        eprintln!("worker {}: {}", worker, msg);
+        errors.fetch_add(1, Ordering::SeqCst);
+    };
+    for round in 0..args.rounds {
+        let name = args.dir.join(format!("w{}-{}", worker, round));
+        let data = pattern(worker, round, size);
+
+        // write in pieces, then read it back in other pieces
+        let mut file = File::create(&name)?;
+        for chunk in data.chunks(12345) {
+            file.write_all(chunk)?;
+        }
+        file.sync_all()?;
+        drop(file);
+        let mut read = vec![];
+        File::open(&name)?.read_to_end(&mut read)?;
+        if read != data {
+            fail(format!("the data of {:?} is wrong", name));
+        }
+
+        // overwrite the middle and read a range of it
+        let mut file = OpenOptions::new().read(true).write(true).open(&name)?;
+        let middle = size / 2;
+        let patch = pattern(round, worker, (size - middle).min(4096));
+        file.seek(SeekFrom::Start(middle as u64))?;
+        file.write_all(&patch)?;
+        let mut buf = vec![0u8; patch.len()];
+        file.seek(SeekFrom::Start(middle as u64))?;
+        file.read_exact(&mut buf)?;
+        if buf != patch {
+            fail(format!("the overwrite of {:?} is wrong", name));
+        }
+        drop(file);
+
+        let mut shared_file = OpenOptions::new().append(true).open(shared)?;
+        shared_file.write_all(&record(worker, round))?;
+        drop(shared_file);
+
+        // the directory is changed by the others while it is listed
+        let mut names = HashSet::new();
+        for entry in fs::read_dir(&args.dir)? {
+            let entry = entry?.file_name();
+            if !names.insert(entry.clone()) {
+                fail(format!("{:?} is listed twice", entry));
+            }
+        }
+        if !names.contains(name.file_name().unwrap()) {
+            fail(format!("{:?} is not listed", name));
+        }
+
+        let renamed = args.dir.join(format!("r{}-{}", worker, round));
+        fs::rename(&name, &renamed)?;
+        if name.exists() {
+            fail(format!("{:?} exists after rename", name));
+        }
+        if fs::metadata(&renamed)?.len() != size as u64 {
+            fail(format!("the size of {:?} is wrong", renamed));
+        }
+        fs::remove_file(&renamed)?;
+        if renamed.exists() {
+            fail(format!("{:?} exists after unlink", renamed));
+        }
+    }
+    Ok(())
+}
+
+/// Every record is whole and appears once
+fn check_shared(args: &Args, shared: &Path) -> usize {
+    let data = fs::read(shared).unwrap();
+    let mut errors = 0;
+    if data.len() != args.threads * args.rounds * RECORD {
+        eprintln!(
+            "the shared file has {} bytes, {} expected",
+            data.len(),
+            args.threads * args.rounds * RECORD
+        );
+        errors += 1;
+    }
+    let mut seen = HashSet::new();
+    for chunk in data.chunks(RECORD) {
+        let text = String::from_utf8_lossy(chunk);
+        let id = text.split('.').next().unwrap_or_default().to_string();
+        let valid = id
+            .split_once(':')
+            .and_then(|(w, r)| Some((w.parse::<usize>().ok()?, r.parse::<usize>().ok()?)))
+            .map(|(w, r)| chunk == record(w, r).as_slice());
+        if valid != Some(true) || !seen.insert(id.clone()) {
+            eprintln!("the record {:?} of the shared file is wrong", text);
+            errors += 1;
+        }
+    }
+    errors
+}
+
+fn main() {
+    let args = Arc::new(Args::parse());
+    let shared = args.dir.join("shared");
+    File::create(&shared).unwrap();
+    let errors = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    let workers = (0..args.threads)
+        .map(|worker| {
+            let args = args.clone();
+            let shared = shared.clone();
+            let errors = errors.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = run(worker, &args, &shared, &errors) {
+                    eprintln!("worker {}: {}", worker, e);
+                    errors.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    let errors = errors.load(Ordering::SeqCst) + check_shared(&args, &shared);
+    fs::remove_file(&shared).unwrap();
+    println!(
+        "{} workers x {} rounds in {:?}, {} errors",
+        args.threads,
+        args.rounds,
+        start.elapsed(),
+        errors
+    );
+    if errors != 0 {
+        std::process::exit(1);
+    }
+}
@@ -1,13 +1,13 @@
 use log::error;
 
 use crate::{
-    clone_db,
     common::{
-        DbfsAttr, DbfsError, DbfsPermission, DbfsResult, DbfsTimeSpec, XattrNamespace, ACCESS_R_OK,
-        ACCESS_W_OK,
+        check_mutable, get_flags, get_u16, get_u32, DbfsAttr, DbfsError, DbfsPermission,
+        DbfsResult, DbfsTimeSpec, XattrNamespace, ACCESS_R_OK, ACCESS_W_OK, DBFS_FLAGS_MASK,
+        FS_APPEND_FL, FS_IMMUTABLE_FL,
     },
     inode::{checkout_access, dbfs_common_attr},
-    u16, u32,
+    tx::dbfs_tx,
 };
 
 pub fn dbfs_common_setxattr(
@@ -18,17 +18,17 @@ pub fn dbfs_common_setxattr(
     value: &[u8],
     ctime: DbfsTimeSpec,
 ) -> DbfsResult<()> {
-    let db = clone_db();
-    let tx = db.tx(true).unwrap();
-    let bucket = tx.get_bucket(ino.to_be_bytes())?;
-    // checkout access
-    let uid = bucket.get_kv("uid").unwrap();
-    let uid = u32!(uid.value());
-    let gid = bucket.get_kv("gid").unwrap();
-    let gid = u32!(gid.value());
-    let mode = bucket.get_kv("mode").unwrap();
-    let mode = u16!(mode.value()) & 0o777;
-    xattr_access_check(key, ACCESS_R_OK, r_uid, r_gid, uid, gid, mode)?;
+    let tx = dbfs_tx(true)?;
+    let bucket = tx.check(|tx| {
+        let bucket = tx.get_bucket(ino.to_be_bytes())?;
+        // checkout access
+        let uid = get_u32(&bucket, ino, "uid")?;
+        let gid = get_u32(&bucket, ino, "gid")?;
+        let mode = get_u16(&bucket, ino, "mode")? & 0o777;
+        xattr_access_check(key, ACCESS_W_OK, r_uid, r_gid, uid, gid, mode)?;
+        check_mutable(get_flags(&bucket, ino)?)?;
+        Ok(bucket)
+    })?;
     bucket.put(key, value)?;
     // update ctime
     bucket.put("ctime", ctime.to_be_bytes())?;
@@ -43,23 +43,15 @@ pub fn dbfs_common_getxattr(
     key: &str,
     buf: &mut [u8],
 ) -> DbfsResult<usize> {
-    let db = clone_db();
-    let tx = db.tx(false).unwrap();
+    let tx = dbfs_tx(false)?;
     let bucket = tx.get_bucket(ino.to_be_bytes())?;
     // checkout access
-    let uid = bucket.get_kv("uid").unwrap();
-    let uid = u32!(uid.value());
-    let gid = bucket.get_kv("gid").unwrap();
-    let gid = u32!(gid.value());
-    let mode = bucket.get_kv("mode").unwrap();
-    let mode = u16!(mode.value()) & 0o777;
+    let uid = get_u32(&bucket, ino, "uid")?;
+    let gid = get_u32(&bucket, ino, "gid")?;
+    let mode = get_u16(&bucket, ino, "mode")? & 0o777;
     xattr_access_check(key, ACCESS_R_OK, r_uid, r_gid, uid, gid, mode)?;
-    let value = bucket.get_kv(key);
-    if value.is_none() {
-        return Err(DbfsError::NoData);
-    }
-    let value = value.unwrap();
-    if buf.len() == 0 {
+    let value = bucket.get_kv(key).ok_or(DbfsError::NoData)?;
+    if buf.is_empty() {
         return Ok(value.value().len());
     }
     let val_len = value.value().len();
@@ -71,36 +63,38 @@ pub fn dbfs_common_getxattr(
     Ok(val_len)
 }
 
+/// List the names of the xattrs, if the buf is empty, return the size the names need
 pub fn dbfs_common_listxattr(
-    _r_uid: u32,
+    r_uid: u32,
     _r_gid: u32,
     ino: usize,
     buf: &mut [u8],
 ) -> DbfsResult<usize> {
-    let db = clone_db();
-    let tx = db.tx(false).unwrap();
+    let tx = dbfs_tx(false)?;
     let bucket = tx.get_bucket(ino.to_be_bytes())?;
-    // TODO! checkout access
     let mut size = 0;
     // find all xattr
-    let buf = &mut buf[..];
-    bucket.kv_pairs().for_each(|x| {
-        if x.key().starts_with(b"user.")
-            || x.key().starts_with(b"system.")
-            || x.key().starts_with(b"trusted.")
-            || x.key().starts_with(b"security.")
-        {
-            let tmp = size;
-            size += x.key().len() + 1;
-            if buf.len() >= size {
-                buf[tmp..size - 1].copy_from_slice(x.key());
-                buf[size - 1] = 0;
-            } else {
-                size = tmp;
-                return;
-            }
+    for x in bucket.kv_pairs() {
+        let key = x.key();
+        let visible = key.starts_with(b"user.")
+            || key.starts_with(b"system.")
+            || key.starts_with(b"security.")
+            // only root can see the trusted xattrs
+            || (key.starts_with(b"trusted.") && r_uid == 0);
+        if !visible {
+            continue;
+        }
+        let tmp = size;
+        size += key.len() + 1;
+        if buf.is_empty() {
+            continue;
+        }
+        if buf.len() < size {
+            return Err(DbfsError::RangeError);
         }
-    });
+        buf[tmp..size - 1].copy_from_slice(key);
+        buf[size - 1] = 0;
+    }
     Ok(size)
 }
 
@@ -111,20 +105,22 @@ pub fn dbfs_common_removexattr(
     key: &str,
     ctime: DbfsTimeSpec,
 ) -> DbfsResult<()> {
-    let db = clone_db();
-    let tx = db.tx(true).unwrap();
-    let bucket = tx.get_bucket(ino.to_be_bytes())?;
-    // checkout access
-    let uid = bucket.get_kv("uid").unwrap();
-    let uid = u32!(uid.value());
-    let gid = bucket.get_kv("gid").unwrap();
-    let gid = u32!(gid.value());
-    let mode = bucket.get_kv("mode").unwrap();
-    let mode = u16!(mode.value()) & 0o777;
-    xattr_access_check(key, ACCESS_W_OK, r_uid, r_gid, uid, gid, mode)?;
+    let tx = dbfs_tx(true)?;
+    let bucket = tx.check(|tx| {
+        let bucket = tx.get_bucket(ino.to_be_bytes())?;
+        // checkout access
+        let uid = get_u32(&bucket, ino, "uid")?;
+        let gid = get_u32(&bucket, ino, "gid")?;
+        let mode = get_u16(&bucket, ino, "mode")? & 0o777;
+        xattr_access_check(key, ACCESS_W_OK, r_uid, r_gid, uid, gid, mode)?;
+        check_mutable(get_flags(&bucket, ino)?)?;
+        bucket.get_kv(key).ok_or(DbfsError::NoData)?;
+        Ok(bucket)
+    })?;
     bucket.delete(key)?;
     //update ctime
     bucket.put("ctime", ctime.to_be_bytes())?;
+    tx.commit()?;
     Ok(())
 }
 
@@ -135,7 +131,7 @@ pub fn dbfs_common_chmod(
     mode: u16,
     ctime: DbfsTimeSpec,
 ) -> DbfsResult<DbfsAttr> {
-    let mut attr = dbfs_common_attr(ino).map_err(|_| DbfsError::Other)?;
+    let mut attr = dbfs_common_attr(ino)?;
     // checkout access
     let uid = attr.uid;
     let gid = attr.gid;
@@ -148,13 +144,13 @@ pub fn dbfs_common_chmod(
     if r_uid != 0 && r_gid != gid {
         return Err(DbfsError::PermissionDenied);
     }
+    check_mutable(attr.flags)?;
     //update mode, the i_mode include file type but mode not include file type
     i_mode = (i_mode & 0o170000) | (mode & 0o777);
 
     if i_mode != attr.perm {
-        let db = clone_db();
-        let tx = db.tx(true).unwrap();
-        let bucket = tx.get_bucket(ino.to_be_bytes())?;
+        let tx = dbfs_tx(true)?;
+        let bucket = tx.check(|tx| Ok(tx.get_bucket(ino.to_be_bytes())?))?;
         bucket.put("mode", i_mode.to_be_bytes())?;
         //update ctime
         bucket.put("ctime", ctime.to_be_bytes())?;
@@ -173,7 +169,8 @@ pub fn dbfs_common_chown(
     gid: Option<u32>,
     c_time: DbfsTimeSpec,
 ) -> DbfsResult<DbfsAttr> {
-    let mut attr = dbfs_common_attr(ino).map_err(|_| DbfsError::Other)?;
+    let mut attr = dbfs_common_attr(ino)?;
+    check_mutable(attr.flags)?;
     if let Some(gid) = gid {
         // Non-root users can only change gid to a group they're in
         if r_uid != 0 && r_gid != gid {
@@ -207,9 +204,8 @@ pub fn dbfs_common_chown(
     }
     attr.perm = perm.bits();
     // we need update the uid and gid and ctime
-    let db = clone_db();
-    let tx = db.tx(true).unwrap();
-    let bucket = tx.get_bucket(ino.to_be_bytes())?;
+    let tx = dbfs_tx(true)?;
+    let bucket = tx.check(|tx| Ok(tx.get_bucket(ino.to_be_bytes())?))?;
     bucket.put("uid", attr.uid.to_be_bytes())?;
     bucket.put("gid", attr.gid.to_be_bytes())?;
     bucket.put("mode", attr.perm.to_be_bytes())?;
@@ -241,10 +237,10 @@ pub fn dbfs_common_utimens(
     {
         return Err(DbfsError::AccessError);
     }
+    check_mutable(attr.flags)?;
     // update atime / mtime / ctime
-    let db = clone_db();
-    let tx = db.tx(true).unwrap();
-    let bucket = tx.get_bucket(ino.to_be_bytes())?;
+    let tx = dbfs_tx(true)?;
+    let bucket = tx.check(|tx| Ok(tx.get_bucket(ino.to_be_bytes())?))?;
     if let Some(atime) = atime {
         bucket.put("atime", atime.to_be_bytes())?;
         attr.atime = DbfsTimeSpec::from(atime);
@@ -266,6 +262,36 @@ pub fn dbfs_common_utimens(
     Ok(attr)
 }
 
+/// Change the inode flags like chattr, only root can change immutable and append-only
+pub fn dbfs_common_set_flags(
+    r_uid: u32,
+    _r_gid: u32,
+    ino: usize,
+    flags: u32,
+    ctime: DbfsTimeSpec,
+) -> DbfsResult<DbfsAttr> {
+    let mut attr = dbfs_common_attr(ino)?;
+    if r_uid != 0 && r_uid != attr.uid {
+        return Err(DbfsError::PermissionDenied);
+    }
+    if flags & !DBFS_FLAGS_MASK != 0 {
+        return Err(DbfsError::NotSupported);
+    }
+    if (flags ^ attr.flags) & (FS_IMMUTABLE_FL | FS_APPEND_FL) != 0 && r_uid != 0 {
+        return Err(DbfsError::PermissionDenied);
+    }
+    if flags != attr.flags {
+        let tx = dbfs_tx(true)?;
+        let bucket = tx.check(|tx| Ok(tx.get_bucket(ino.to_be_bytes())?))?;
+        bucket.put("flags", flags.to_be_bytes())?;
+        bucket.put("ctime", ctime.to_be_bytes())?;
+        tx.commit()?;
+        attr.flags = flags;
+        attr.ctime = ctime;
+    }
+    Ok(attr)
+}
+
 pub fn clear_suid_sgid(mut perm: DbfsPermission) -> DbfsPermission {
     perm -= DbfsPermission::S_ISUID;
     if perm.contains(DbfsPermission::S_IXGRP) {
@@ -316,9 +342,9 @@ fn xattr_access_check(
             }
         }
         XattrNamespace::System => {
-            if key.eq("system.posix_acl_access") {
-                let bool = checkout_access(uid, gid, mode, r_uid, r_gid, access_mask);
-                if !bool {
+            if key.eq("system.posix_acl_access") || key.eq("system.posix_acl_default") {
+                // the ACLs can be read by everyone like the mode, and changed by the owner
+                if access_mask != ACCESS_R_OK && r_uid != 0 && r_uid != uid {
                     return Err(DbfsError::PermissionDenied);
                 }
             } else if r_uid != 0 {
@@ -0,0 +1,271 @@
+//! dbfs-debug, inspect a dbfs image without mounting it
+//!
+//! cargo run --features fuse --bin dbfs-debug -- ./my-database.db stat 1
+//! cargo run --features fuse --bin dbfs-debug -- --json ./my-database.db ls /
+use clap::{Parser, Subcommand};
+use dbfs2::{
+    fuse::mkfs::{dbfs_fuse_open_image, format_uuid},
+    DbfsError, DbfsResult,
+};
+use jammdb::{Bucket, Data, DB};
+use serde_json::{json, Map, Value};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Path of the image
+    image: String,
+    /// Print JSON instead of text
+    #[arg(long)]
+    json: bool,
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Print the super block
+    Super,
+    /// Print the attributes of an inode, by number or path
+    Stat { inode: String },
+    /// List the entries of a directory, by number or path
+    Ls { inode: String },
+    /// Print the slices of a file, by number or path
+    Slices { inode: String },
+    /// Resolve a path to the inode number
+    Resolve { path: String },
+    /// Dump the raw keys of a bucket, an inode number or a name like super_blk
+    Dump { bucket: String },
+}
+
+fn hex(value: &[u8]) -> String {
+    value.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn time(value: &[u8]) -> Value {
+    if value.len() != 12 {
+        return json!(hex(value));
+    }
+    json!({
+        "sec": dbfs2::u64!(value[..8]),
+        "nsec": dbfs2::u32!(value[8..12]),
+    })
+}
+
+fn inode_bucket<'a, 'tx>(tx: &'a jammdb::Tx<'tx>, ino: usize) -> DbfsResult<Bucket<'a, 'tx>> {
+    tx.get_bucket(ino.to_be_bytes()).map_err(|e| match e {
+        jammdb::Error::BucketMissing => DbfsError::NotFound,
+        e => e.into(),
+    })
+}
+
+fn resolve(db: &DB, path: &str) -> DbfsResult<usize> {
+    if let Ok(ino) = path.parse::<usize>() {
+        return Ok(ino);
+    }
+    let tx = db.tx(false)?;
+    let mut ino = 1;
+    for name in path.split('/').filter(|x| !x.is_empty()) {
+        let bucket = inode_bucket(&tx, ino)?;
+        let key = format!("data:{}", name);
+        let kv = bucket.get_kv(&key).ok_or(DbfsError::NotFound)?;
+        ino = std::str::from_utf8(kv.value())
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .ok_or_else(|| DbfsError::corrupted(ino, &key))?;
+    }
+    Ok(ino)
+}
+
+fn super_blk(db: &DB) -> DbfsResult<Value> {
+    let tx = db.tx(false)?;
+    let bucket = tx.get_bucket("super_blk")?;
+    let mut map = Map::new();
+    for data in bucket.cursor() {
+        if let Data::KeyValue(kv) = data {
+            let key = String::from_utf8_lossy(kv.key()).to_string();
+            let value = kv.value();
+            let value = match key.as_str() {
+                "continue_number" | "disk_size" | "capacity" if value.len() == 8 => {
+                    json!(dbfs2::u64!(value))
+                }
+                "magic" | "blk_size" if value.len() == 4 => json!(dbfs2::u32!(value)),
+                "label" => json!(String::from_utf8_lossy(value)),
+                "uuid" if value.len() == 16 => json!(format_uuid(value.try_into().unwrap())),
+                _ => json!(hex(value)),
+            };
+            map.insert(key, value);
+        }
+    }
+    Ok(Value::Object(map))
+}
+
+fn stat(db: &DB, ino: usize) -> DbfsResult<Value> {
+    let tx = db.tx(false)?;
+    let bucket = inode_bucket(&tx, ino)?;
+    let mut map = Map::new();
+    map.insert("ino".to_string(), json!(ino));
+    let (mut entries, mut slices, mut xattrs) = (0, 0, 0);
+    for data in bucket.cursor() {
+        let kv = match data {
+            Data::KeyValue(kv) => kv,
+            Data::Bucket(_) => continue,
+        };
+        let key = kv.key();
+        let value = kv.value();
+        if key.starts_with(b"data:") {
+            entries += 1;
+            continue;
+        }
+        if key.starts_with(b"zdata:") {
+            slices += 1;
+            continue;
+        }
+        let key = String::from_utf8_lossy(key).to_string();
+        let value = match key.as_str() {
+            "mode" if value.len() == 2 => json!(format!("{:o}", dbfs2::u16!(value))),
+            "size" if value.len() == 8 => json!(dbfs2::usize!(value)),
+            "hard_links" | "uid" | "gid" | "block_size" | "dev" | "flags" if value.len() == 4 => {
+                json!(dbfs2::u32!(value))
+            }
+            "atime" | "mtime" | "ctime" => time(value),
+            "data" => json!(String::from_utf8_lossy(value)),
+            _ if key.contains('.') => {
+                xattrs += 1;
+                continue;
+            }
+            _ => json!(hex(value)),
+        };
+        map.insert(key, value);
+    }
+    map.insert("entries".to_string(), json!(entries));
+    map.insert("slices".to_string(), json!(slices));
+    map.insert("xattrs".to_string(), json!(xattrs));
+    Ok(Value::Object(map))
+}
+
+fn ls(db: &DB, ino: usize) -> DbfsResult<Value> {
+    let tx = db.tx(false)?;
+    let bucket = inode_bucket(&tx, ino)?;
+    let entries = bucket
+        .cursor()
+        .filter_map(|data| match data {
+            Data::KeyValue(kv) if kv.key().starts_with(b"data:") => Some(json!({
+                "name": String::from_utf8_lossy(&kv.key()[5..]),
+                "ino": String::from_utf8_lossy(kv.value()).parse::<usize>().ok(),
+            })),
+            _ => None,
+        })
+        .collect();
+    Ok(Value::Array(entries))
+}
+
+fn slices(db: &DB, ino: usize) -> DbfsResult<Value> {
+    let tx = db.tx(false)?;
+    let bucket = inode_bucket(&tx, ino)?;
+    let slices = bucket
+        .cursor()
+        .filter_map(|data| match data {
+            Data::KeyValue(kv) if kv.key().starts_with(b"zdata:") && kv.key().len() == 10 => {
+                let index = dbfs2::u32!(kv.key()[6..10]);
+                let value = kv.value();
+                Some(json!({
+                    "index": index,
+                    "offset": index as usize * dbfs2::SLICE_SIZE,
+                    "len": value.len(),
+                    "zero": value.iter().all(|x| *x == 0),
+                }))
+            }
+            _ => None,
+        })
+        .collect();
+    Ok(Value::Array(slices))
+}
+
+fn dump_bucket(bucket: &Bucket) -> Value {
+    let mut map = Map::new();
+    for data in bucket.cursor() {
+        match data {
+            Data::Bucket(x) => {
+                let name = x.name().to_vec();
+                let value = bucket
+                    .get_bucket(name.as_slice())
+                    .map_or(Value::Null, |x| dump_bucket(&x));
+                map.insert(hex(&name), value);
+            }
+            Data::KeyValue(kv) => {
+                let key = String::from_utf8_lossy(kv.key()).to_string();
+                map.insert(key, json!(hex(kv.value())));
+            }
+        }
+    }
+    Value::Object(map)
+}
+
+fn dump(db: &DB, name: &str) -> DbfsResult<Value> {
+    let tx = db.tx(false)?;
+    let bucket = match name.parse::<usize>() {
+        Ok(ino) => inode_bucket(&tx, ino)?,
+        Err(_) => tx.get_bucket(name)?,
+    };
+    Ok(dump_bucket(&bucket))
+}
+
+fn print(value: &Value, indent: usize) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                match value {
+                    Value::Object(_) | Value::Array(_) => {
+                        println!("{:indent$}{}:", "", key, indent = indent);
+                        print(value, indent + 2);
+                    }
+                    _ => println!("{:indent$}{}: {}", "", key, value, indent = indent),
+                }
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                match value {
+                    Value::Object(map) => {
+                        let line = map
+                            .iter()
+                            .map(|(key, value)| format!("{}={}", key, value))
+                            .collect::<Vec<_>>()
+                            .join(" ");
+                        println!("{:indent$}{}", "", line, indent = indent);
+                    }
+                    _ => println!("{:indent$}{}", "", value, indent = indent),
+                }
+            }
+        }
+        _ => println!("{:indent$}{}", "", value, indent = indent),
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    let db = match dbfs_fuse_open_image(&args.image, true) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("dbfs-debug: {}: {}", args.image, e);
+            std::process::exit(1);
+        }
+    };
+    let res = match &args.command {
+        Commands::Super => super_blk(&db),
+        Commands::Stat { inode } => resolve(&db, inode).and_then(|ino| stat(&db, ino)),
+        Commands::Ls { inode } => resolve(&db, inode).and_then(|ino| ls(&db, ino)),
+        Commands::Slices { inode } => resolve(&db, inode).and_then(|ino| slices(&db, ino)),
+        Commands::Resolve { path } => resolve(&db, path).map(|ino| json!({ "ino": ino })),
+        Commands::Dump { bucket } => dump(&db, bucket),
+    };
+    match res {
+        Ok(value) if args.json => println!("{}", serde_json::to_string_pretty(&value).unwrap()),
+        Ok(value) => print(&value, 0),
+        Err(e) => {
+            eprintln!("dbfs-debug: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! Build a dbfs image from a host directory or a tar stream, and export it to tar
+//!
+//! cargo run --features fuse --bin mkfs-dbfs -- ./rootfs.db
+//! cargo run --features fuse --bin dbfs-image -- ./rootfs.db import-dir ./rootfs
+//! cargo run --features fuse --bin dbfs-image -- ./rootfs.db export-tar rootfs.tar
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::{Component, Path},
+};
+
+use clap::{Parser, Subcommand};
+use dbfs2::{
+    fuse::{
+        tool::{dbfs_fuse_attach_image, dbfs_fuse_detach_image},
+        tree::{dbfs_fuse_export, dbfs_fuse_import_dir, DbfsImporter, DbfsTreeEntry},
+    },
+    DbfsError, DbfsResult, DbfsTimeSpec,
+};
+use tar::{Archive, Builder, EntryType, Header};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Path of the image, create it with mkfs-dbfs first
+    image: String,
+    /// Commit this many operations in one transaction
+    #[arg(long, default_value_t = 4096)]
+    batch: usize,
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Import a host directory
+    ImportDir {
+        dir: String,
+        /// The directory of the image to import to
+        #[arg(long, default_value = "/")]
+        dest: String,
+    },
+    /// Import a tar archive, - is stdin
+    ImportTar { tar: String },
+    /// Export the image to a tar archive, - is stdout
+    ExportTar { tar: String },
+}
+
+fn io_error<E>(_: E) -> DbfsError {
+    DbfsError::Io
+}
+
+/// The path of the image for a path in the archive, the root is ""
+fn image_path(path: &Path) -> String {
+    path.components()
+        .filter_map(|x| match x {
+            Component::Normal(x) => Some(x.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn import_tar<R: Read>(reader: R, importer: &mut DbfsImporter) -> DbfsResult<()> {
+    let mut archive = Archive::new(reader);
+    for file in archive.entries().map_err(io_error)? {
+        let mut file = file.map_err(io_error)?;
+        let header = file.header();
+        let kind = match header.entry_type() {
+            EntryType::Regular | EntryType::Continuous | EntryType::Link => libc::S_IFREG,
+            EntryType::Directory => libc::S_IFDIR,
+            EntryType::Symlink => libc::S_IFLNK,
+            EntryType::Char => libc::S_IFCHR,
+            EntryType::Block => libc::S_IFBLK,
+            EntryType::Fifo => libc::S_IFIFO,
+            // the pax and gnu extensions are handled by tar
+            _ => continue,
+        };
+        let major = header.device_major().map_err(io_error)?.unwrap_or(0);
+        let minor = header.device_minor().map_err(io_error)?.unwrap_or(0);
+        let mtime = DbfsTimeSpec::new(header.mtime().map_err(io_error)?, 0);
+        let mut entry = DbfsTreeEntry {
+            path: image_path(&file.path().map_err(io_error)?),
+            mode: kind | (header.mode().map_err(io_error)? & 0o7777),
+            uid: header.uid().map_err(io_error)? as u32,
+            gid: header.gid().map_err(io_error)? as u32,
+            atime: mtime,
+            mtime,
+            rdev: (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12),
+            ..Default::default()
+        };
+        let link = file.link_name().map_err(io_error)?;
+        match header.entry_type() {
+            EntryType::Link => entry.hard_link = link.map(|x| image_path(&x)),
+            EntryType::Symlink => entry.link_target = link.map(|x| x.to_string_lossy().to_string()),
+            _ => {}
+        }
+        if let Some(extensions) = file.pax_extensions().map_err(io_error)? {
+            for extension in extensions {
+                let extension = extension.map_err(io_error)?;
+                let key = extension.key().map_err(io_error)?;
+                if let Some(name) = key.strip_prefix("SCHILY.xattr.") {
+                    entry
+                        .xattrs
+                        .push((name.to_string(), extension.value_bytes().to_vec()));
+                }
+            }
+        }
+        importer.add(&entry, &mut file)?;
+    }
+    Ok(())
+}
+
+fn export_tar<W: Write>(writer: W) -> DbfsResult<()> {
+    let mut builder = Builder::new(writer);
+    dbfs_fuse_export(|entry, reader| {
+        let path = match entry.path.is_empty() {
+            true => "./".to_string(),
+            false => entry.path.clone(),
+        };
+        if !entry.xattrs.is_empty() {
+            let xattrs = entry
+                .xattrs
+                .iter()
+                .map(|(key, value)| (format!("SCHILY.xattr.{}", key), value.as_slice()))
+                .collect::<Vec<_>>();
+            let xattrs = xattrs.iter().map(|(key, value)| (key.as_str(), *value));
+            builder.append_pax_extensions(xattrs).map_err(io_error)?;
+        }
+        let mut header = Header::new_ustar();
+        header.set_mode(entry.mode & 0o7777);
+        header.set_uid(entry.uid as u64);
+        header.set_gid(entry.gid as u64);
+        header.set_mtime(entry.mtime.sec);
+        header.set_size(0);
+        if let Some(target) = &entry.hard_link {
+            header.set_entry_type(EntryType::Link);
+            return builder
+                .append_link(&mut header, &path, target)
+                .map_err(io_error);
+        }
+        let kind = match entry.mode & libc::S_IFMT {
+            libc::S_IFDIR => EntryType::Directory,
+            libc::S_IFLNK => EntryType::Symlink,
+            libc::S_IFCHR => EntryType::Char,
+            libc::S_IFBLK => EntryType::Block,
+            libc::S_IFIFO => EntryType::Fifo,
+            libc::S_IFREG => EntryType::Regular,
+            // tar can't store sockets
+            _ => return Ok(()),
+        };
+        header.set_entry_type(kind);
+        match kind {
+            EntryType::Symlink => {
+                let target = entry.link_target.clone().unwrap_or_default();
+                builder
+                    .append_link(&mut header, &path, target)
+                    .map_err(io_error)
+            }
+            EntryType::Regular => {
+                let mut data = vec![];
+                reader.read_to_end(&mut data).map_err(io_error)?;
+                header.set_size(data.len() as u64);
+                builder
+                    .append_data(&mut header, &path, data.as_slice())
+                    .map_err(io_error)
+            }
+            _ => {
+                header
+                    .set_device_major((entry.rdev >> 8) & 0xfff)
+                    .map_err(io_error)?;
+                header
+                    .set_device_minor((entry.rdev & 0xff) | ((entry.rdev >> 12) & 0xfff00))
+                    .map_err(io_error)?;
+                builder
+                    .append_data(&mut header, &path, io::empty())
+                    .map_err(io_error)
+            }
+        }
+    })?;
+    builder.finish().map_err(io_error)
+}
+
+fn run(args: &Args) -> DbfsResult<()> {
+    match &args.command {
+        Commands::ImportDir { dir, dest } => {
+            let mut importer = DbfsImporter::new(args.batch)?;
+            dbfs_fuse_import_dir(Path::new(dir), dest, &mut importer)?;
+            importer.finish()
+        }
+        Commands::ImportTar { tar } => {
+            let mut importer = DbfsImporter::new(args.batch)?;
+            match tar.as_str() {
+                "-" => import_tar(io::stdin().lock(), &mut importer)?,
+                _ => import_tar(fs::File::open(tar).map_err(io_error)?, &mut importer)?,
+            }
+            importer.finish()
+        }
+        Commands::ExportTar { tar } => match tar.as_str() {
+            "-" => export_tar(io::stdout().lock()),
+            _ => export_tar(fs::File::create(tar).map_err(io_error)?),
+        },
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = dbfs_fuse_attach_image(&args.image) {
+        eprintln!("dbfs-image: {}: {}", args.image, e);
+        std::process::exit(1);
+    }
+    let res = run(&args);
+    let detach = dbfs_fuse_detach_image();
+    if let Err(e) = res.and(detach) {
+        eprintln!("dbfs-image: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,188 @@
+//! dbfs-tool, work with the files of a dbfs image without mounting it
+//!
+//! cargo run --features fuse --bin dbfs-tool -- ./my-database.db put ./local.txt /remote.txt
+//! cargo run --features fuse --bin dbfs-tool -- ./my-database.db ls /
+//! cargo run --features fuse --bin dbfs-tool -- --uid 1000 --gid 1000 ./my-database.db mkdir /home
+use std::{fs, io};
+
+use clap::{Parser, Subcommand};
+use dbfs2::{
+    dbfs_common_label, dbfs_common_set_label, dbfs_common_uuid,
+    fuse::{
+        attr::dbfs_fuse_statfs,
+        ioctl::dbfs_fuse_fiemap,
+        mkfs::{dbfs_fuse_image_size, dbfs_fuse_resize, format_uuid},
+        tool::{
+            dbfs_fuse_attach_image, dbfs_fuse_chmod_path, dbfs_fuse_detach_image,
+            dbfs_fuse_list_dir, dbfs_fuse_make_dir, dbfs_fuse_move, dbfs_fuse_read_file,
+            dbfs_fuse_remove, dbfs_fuse_resolve, dbfs_fuse_stat_path, dbfs_fuse_write_file,
+        },
+    },
+    DbfsAttr, DbfsFileType, DbfsResult,
+};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Path of the image
+    image: String,
+    /// The user the commands run as, the owner of the new files
+    #[arg(long, default_value_t = 0)]
+    uid: u32,
+    /// The group the commands run as
+    #[arg(long, default_value_t = 0)]
+    gid: u32,
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// List a directory
+    Ls {
+        #[arg(default_value = "/")]
+        path: String,
+    },
+    /// Print a file to stdout
+    Cat { path: String },
+    /// Copy a local file into the image
+    Put {
+        local: String,
+        path: String,
+        /// Mode of the new file, in octal
+        #[arg(long, default_value = "644")]
+        mode: String,
+    },
+    /// Copy a file of the image to a local file
+    Get { path: String, local: String },
+    /// Create a directory
+    Mkdir {
+        path: String,
+        /// Mode of the directory, in octal
+        #[arg(long, default_value = "755")]
+        mode: String,
+    },
+    /// Remove a file or an empty directory
+    Rm { path: String },
+    /// Rename a file or a directory
+    Mv { from: String, to: String },
+    /// Change the mode of a file, in octal
+    Chmod { mode: String, path: String },
+    /// Change the capacity of the image, in MB. A mounted image is resized with
+    /// `setfattr -n trusted.dbfs.capacity -v <bytes> <mount point>`
+    Resize { capacity: usize },
+    /// Print the capacity and the used size of the image, in MB
+    Df,
+    /// Print the label and the uuid of the image, or change the label
+    Label { label: Option<String> },
+    /// Print the extents of the data of a file
+    Map { path: String },
+}
+
+fn type_char(kind: DbfsFileType) -> char {
+    match kind {
+        DbfsFileType::Directory => 'd',
+        DbfsFileType::Symlink => 'l',
+        DbfsFileType::CharDevice => 'c',
+        DbfsFileType::BlockDevice => 'b',
+        DbfsFileType::NamedPipe => 'p',
+        DbfsFileType::Socket => 's',
+        DbfsFileType::RegularFile => '-',
+    }
+}
+
+fn print_attr(name: &str, attr: &DbfsAttr) {
+    println!(
+        "{}{:04o} {:>3} {:>5} {:>5} {:>10} {:>8} {}",
+        type_char(attr.kind),
+        attr.perm & 0o7777,
+        attr.nlink,
+        attr.uid,
+        attr.gid,
+        attr.size,
+        attr.ino,
+        name
+    );
+}
+
+fn parse_mode(mode: &str) -> u16 {
+    u16::from_str_radix(mode, 8).unwrap_or_else(|_| {
+        eprintln!("dbfs-tool: invalid mode {}", mode);
+        std::process::exit(1);
+    })
+}
+
+fn run(args: &Args) -> DbfsResult<()> {
+    let (uid, gid) = (args.uid, args.gid);
+    match &args.command {
+        Commands::Ls { path } => {
+            let attr = dbfs_fuse_stat_path(path)?;
+            if attr.kind != DbfsFileType::Directory {
+                print_attr(path, &attr);
+                return Ok(());
+            }
+            for (name, attr) in dbfs_fuse_list_dir(path)? {
+                print_attr(&name, &attr);
+            }
+        }
+        Commands::Cat { path } => {
+            dbfs_fuse_read_file(path, &mut io::stdout().lock())?;
+        }
+        Commands::Put { local, path, mode } => {
+            let mut file = fs::File::open(local).map_err(|_| dbfs2::DbfsError::NotFound)?;
+            dbfs_fuse_write_file(uid, gid, path, &mut file, parse_mode(mode))?;
+        }
+        Commands::Get { path, local } => {
+            let mut file = fs::File::create(local).map_err(|_| dbfs2::DbfsError::Io)?;
+            dbfs_fuse_read_file(path, &mut file)?;
+        }
+        Commands::Mkdir { path, mode } => {
+            dbfs_fuse_make_dir(uid, gid, path, parse_mode(mode))?;
+        }
+        Commands::Rm { path } => dbfs_fuse_remove(uid, gid, path)?,
+        Commands::Mv { from, to } => dbfs_fuse_move(uid, gid, from, to)?,
+        Commands::Chmod { mode, path } => {
+            dbfs_fuse_chmod_path(uid, gid, path, parse_mode(mode))?;
+        }
+        Commands::Resize { capacity } => dbfs_fuse_resize(capacity * 1024 * 1024)?,
+        Commands::Df => {
+            let stat = dbfs_fuse_statfs()?;
+            let used = dbfs_fuse_image_size()?;
+            println!(
+                "capacity: {}MB, used: {}MB",
+                stat.f_blocks * stat.f_bsize / 1024 / 1024,
+                used / 1024 / 1024
+            );
+        }
+        Commands::Label { label: Some(label) } => dbfs_common_set_label(label)?,
+        Commands::Map { path } => {
+            let ino = dbfs_fuse_resolve(path)?;
+            for extent in dbfs_fuse_fiemap(ino as u64, 0, u64::MAX)? {
+                let last = if extent.last { " last" } else { "" };
+                println!("{:>12} {:>12}{}", extent.logical, extent.length, last);
+            }
+        }
+        Commands::Label { label: None } => {
+            println!("label: {}", dbfs_common_label()?);
+            if let Some(uuid) = dbfs_common_uuid()? {
+                println!("uuid: {}", format_uuid(&uuid));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = dbfs_fuse_attach_image(&args.image) {
+        eprintln!("dbfs-tool: {}: {}", args.image, e);
+        std::process::exit(1);
+    }
+    let res = run(&args);
+    // write back the image even if the command failed halfway
+    let detach = dbfs_fuse_detach_image();
+    if let Err(e) = res.and(detach) {
+        eprintln!("dbfs-tool: {}", e);
+        std::process::exit(1);
+    }
+}
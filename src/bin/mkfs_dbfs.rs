@@ -0,0 +1,66 @@
+//! mkfs.dbfs, create a formatted dbfs image
+//!
+//! cargo run --features fuse --bin mkfs-dbfs -- --capacity 4 --label data ./data.db
+//!
+//! Cargo doesn't allow dots in the names of the binaries, install it as `/sbin/mkfs.dbfs`
+//! so `mkfs -t dbfs` finds it.
+use clap::Parser;
+use dbfs2::{
+    fuse::mkfs::{dbfs_fuse_mkfs, format_uuid, parse_uuid, MkfsOptions},
+    SLICE_SIZE,
+};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Path of the image
+    image: String,
+    /// Size of the image in GB
+    #[arg(long, default_value_t = 20)]
+    capacity: usize,
+    /// Size of the slice in bytes, it must match the build of dbfs
+    #[arg(long, default_value_t = SLICE_SIZE)]
+    slice_size: usize,
+    /// Label of the image
+    #[arg(short = 'L', long, default_value = "")]
+    label: String,
+    /// UUID of the image, a random one is generated if it is not given
+    #[arg(short = 'U', long)]
+    uuid: Option<String>,
+    /// Owner of the root directory
+    #[arg(long, default_value_t = 0)]
+    uid: u32,
+    /// Group of the root directory
+    #[arg(long, default_value_t = 0)]
+    gid: u32,
+    /// Overwrite the image if it exists
+    #[arg(short, long)]
+    force: bool,
+}
+
+fn main() {
+    let args = Args::parse();
+    let uuid = match args.uuid.as_deref().map(parse_uuid).transpose() {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            eprintln!("invalid uuid {}", args.uuid.unwrap());
+            std::process::exit(1);
+        }
+    };
+    let options = MkfsOptions {
+        capacity: args.capacity * 1024 * 1024 * 1024,
+        slice_size: args.slice_size,
+        label: args.label,
+        uuid,
+        uid: args.uid,
+        gid: args.gid,
+        force: args.force,
+    };
+    match dbfs_fuse_mkfs(&args.image, &options) {
+        Ok(uuid) => println!("created {} with UUID {}", args.image, format_uuid(&uuid)),
+        Err(e) => {
+            eprintln!("mkfs.dbfs: {}: {}", args.image, e);
+            std::process::exit(1);
+        }
+    }
+}
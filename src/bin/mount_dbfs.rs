@@ -0,0 +1,227 @@
+//! mount.dbfs, the mount helper of dbfs
+//!
+//! It follows the convention of the mount helpers, so an image can be mounted
+//! with `mount -t fuse.dbfs` or from /etc/fstab:
+//!
+//! /srv/data.db  /mnt/data  fuse.dbfs  noatime,commit=10,allow_other  0  0
+//!
+//! mount(8) runs `mount.fuse.dbfs /srv/data.db /mnt/data -o <options>`, so the
+//! binary built as `mount-dbfs` should be installed as `/sbin/mount.fuse.dbfs`:
+//!
+//! cargo install --path . --features fuse --bin mount-dbfs --root /tmp/dbfs
+//! install /tmp/dbfs/bin/mount-dbfs /sbin/mount.fuse.dbfs
+use std::{
+    ffi::CString,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+use clap::Parser;
+use dbfs2::fuse::{mkfs::Durability, DbfsFuse, DbfsFuseBuilder};
+use fuser::{MountOption, Session};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Path of the image
+    image: PathBuf,
+    /// Mount point
+    mount_point: PathBuf,
+    /// Mount options, separated by commas
+    #[arg(short = 'o')]
+    options: Vec<String>,
+    /// Stay in the foreground
+    #[arg(short = 'f')]
+    foreground: bool,
+    /// Ignore the unknown options
+    #[arg(short = 's')]
+    sloppy: bool,
+    /// Don't write to /etc/mtab, it is always the case
+    #[arg(short = 'n')]
+    #[allow(unused)]
+    no_mtab: bool,
+    /// Print the parsed options
+    #[arg(short = 'v')]
+    verbose: bool,
+}
+
+fn fail(msg: String) -> ! {
+    eprintln!("mount.dbfs: {}", msg);
+    std::process::exit(1);
+}
+
+fn parse_num<T: std::str::FromStr>(key: &str, value: Option<&str>) -> T {
+    match value.map(|value| value.parse::<T>()) {
+        Some(Ok(value)) => value,
+        _ => fail(format!("invalid value of option {}", key)),
+    }
+}
+
+fn parse_options(args: &Args) -> (DbfsFuseBuilder, Vec<MountOption>) {
+    let mut builder = DbfsFuse::builder().path(&args.image).create(false);
+    let mut options = vec![
+        MountOption::FSName(args.image.to_string_lossy().to_string()),
+        MountOption::Subtype("dbfs".to_string()),
+        MountOption::DefaultPermissions,
+    ];
+    for option in args.options.iter().flat_map(|x| x.split(',')) {
+        let (key, value) = match option.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (option, None),
+        };
+        match key {
+            "ro" => {
+                options.push(MountOption::RO);
+                builder = builder.read_only(true);
+            }
+            "rw" => options.push(MountOption::RW),
+            "atime" => options.push(MountOption::Atime),
+            "noatime" => options.push(MountOption::NoAtime),
+            "dev" => options.push(MountOption::Dev),
+            "nodev" => options.push(MountOption::NoDev),
+            "suid" => {
+                options.push(MountOption::Suid);
+                builder = builder.suid_support(true);
+            }
+            "nosuid" => options.push(MountOption::NoSuid),
+            "exec" => options.push(MountOption::Exec),
+            "noexec" => options.push(MountOption::NoExec),
+            "dirsync" => options.push(MountOption::DirSync),
+            "async" => options.push(MountOption::Async),
+            "sync" => {
+                options.push(MountOption::Sync);
+                builder = builder.durability(Durability::EveryCommit);
+            }
+            "allow_other" => options.push(MountOption::AllowOther),
+            "allow_root" => options.push(MountOption::AllowRoot),
+            "auto_unmount" => options.push(MountOption::AutoUnmount),
+            "uid" => builder = builder.uid(Some(parse_num(key, value))),
+            "gid" => builder = builder.gid(Some(parse_num(key, value))),
+            "commit" => {
+                builder = builder.commit_interval(Duration::from_secs(parse_num(key, value)))
+            }
+            "group_commit" => builder = builder.group_commit(parse_num(key, value)),
+            "durability" => {
+                let durability = parse_num::<Durability>(key, value);
+                builder = builder.durability(durability);
+            }
+            "attr_timeout" => {
+                builder = builder.attr_ttl(Duration::from_secs_f64(parse_num(key, value)))
+            }
+            "entry_timeout" => {
+                builder = builder.entry_ttl(Duration::from_secs_f64(parse_num(key, value)))
+            }
+            "write_cache" => {
+                builder = builder.write_cache_size(parse_num::<usize>(key, value) * 1024 * 1024)
+            }
+            "direct_io" => builder = builder.direct_io(true),
+            "threads" => builder = builder.threads(parse_num(key, value)),
+            "writeback_cache" => builder = builder.writeback_cache(true),
+            "keep_cache" => builder = builder.keep_cache(true),
+            "nokeep_cache" => builder = builder.keep_cache(false),
+            "max_write" => builder = builder.max_write(parse_num(key, value)),
+            "max_readahead" => builder = builder.max_readahead(parse_num(key, value)),
+            "max_background" => builder = builder.max_background(parse_num(key, value)),
+            "readdirplus_auto" => builder = builder.readdirplus_auto(true),
+            "noatomic_o_trunc" => builder = builder.atomic_o_trunc(false),
+            "acl" => builder = builder.posix_acl(true),
+            "noacl" => builder = builder.posix_acl(false),
+            "export" => builder = builder.export_support(true),
+            // handled by mount(8)
+            "defaults"
+            | "auto"
+            | "noauto"
+            | "user"
+            | "nouser"
+            | "users"
+            | "_netdev"
+            | "nofail"
+            | "default_permissions" => {}
+            _ if key.starts_with("x-") => {}
+            _ if args.sloppy => {}
+            _ => fail(format!("unknown option {}", option)),
+        }
+    }
+    (builder, options)
+}
+
+/// Unmount on SIGTERM, SIGINT and SIGHUP, so the filesystem is always destroyed
+fn handle_signals(mount_point: PathBuf) {
+    let mut set: libc::sigset_t = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        // the threads spawned later inherit the mask
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+    }
+    std::thread::spawn(move || {
+        let mut sig = 0;
+        unsafe { libc::sigwait(&set, &mut sig) };
+        let unmounted = Command::new("fusermount")
+            .arg("-u")
+            .arg(&mount_point)
+            .status()
+            .map_or(false, |status| status.success());
+        if !unmounted {
+            let _ = Command::new("umount").arg(&mount_point).status();
+        }
+    });
+}
+
+fn daemonize() {
+    match unsafe { libc::fork() } {
+        -1 => fail("fork failed".to_string()),
+        // the parent must not unmount the filesystem
+        0 => {}
+        _ => unsafe { libc::_exit(0) },
+    }
+    unsafe {
+        libc::setsid();
+        let root = CString::new("/").unwrap();
+        libc::chdir(root.as_ptr());
+        let null = CString::new("/dev/null").unwrap();
+        let fd = libc::open(null.as_ptr(), libc::O_RDWR);
+        if fd >= 0 {
+            libc::dup2(fd, 0);
+            libc::dup2(fd, 1);
+            libc::dup2(fd, 2);
+            if fd > 2 {
+                libc::close(fd);
+            }
+        }
+    }
+}
+
+fn canonicalize(path: &Path) -> PathBuf {
+    path.canonicalize()
+        .unwrap_or_else(|e| fail(format!("{}: {}", path.display(), e)))
+}
+
+fn main() {
+    let mut args = Args::parse();
+    args.image = canonicalize(&args.image);
+    args.mount_point = canonicalize(&args.mount_point);
+    let (builder, options) = parse_options(&args);
+    if args.verbose {
+        println!("{:?}", builder);
+        println!("{:?}", options);
+    }
+    let dbfs = builder
+        .build()
+        .unwrap_or_else(|e| fail(format!("{}: {}", args.image.display(), e)));
+    let mut session = Session::new(dbfs, &args.mount_point, &options)
+        .unwrap_or_else(|e| fail(format!("{}: {}", args.mount_point.display(), e)));
+    if !args.foreground {
+        daemonize();
+    }
+    handle_signals(args.mount_point.clone());
+    let res = session.run();
+    // destroy the filesystem before exiting
+    drop(session);
+    if let Err(e) = res {
+        fail(format!("{:?}", e));
+    }
+}
@@ -0,0 +1,245 @@
+//! The write-back cache of the file data
+//!
+//! Writes are absorbed by the dirty slices in memory and committed to the db
+//! in one transaction when the file is synced, the cache is full, or the
+//! filesystem is unmounted.
+use alloc::{boxed::Box, collections::BTreeMap, vec, vec::Vec};
+use core::cmp::{max, min};
+
+use spin::Mutex;
+
+use crate::{
+    common::{generate_data_key_with_number, get_usize, DbfsError, DbfsResult},
+    tx::dbfs_tx,
+    SLICE_SIZE,
+};
+
+/// The size of the cache of a FUSE mount, 8MB
+pub const DEFAULT_CACHE_SIZE: usize = 8 * 1024 * 1024;
+
+/// The cache is disabled until [`dbfs_common_cache_resize`] enables it, the rvfs
+/// side doesn't flush it on fsync or release
+static SLICE_CACHE: Mutex<SliceCache> = Mutex::new(SliceCache::new(0));
+
+/// The dirty data of an inode
+struct DirtyInode {
+    /// The file size including the dirty data
+    size: usize,
+    slices: BTreeMap<u32, Box<[u8]>>,
+}
+
+struct SliceCache {
+    /// The max number of dirty slices
+    capacity: usize,
+    count: usize,
+    inodes: BTreeMap<usize, DirtyInode>,
+}
+
+impl SliceCache {
+    const fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            count: 0,
+            inodes: BTreeMap::new(),
+        }
+    }
+
+    /// Commit the dirty data of `ino`, or all the dirty data if `ino` is None
+    fn flush(&mut self, ino: Option<usize>) -> DbfsResult<()> {
+        if self.inodes.is_empty() {
+            return Ok(());
+        }
+        let tx = dbfs_tx(true)?;
+        for (&number, inode) in self.inodes.iter() {
+            if matches!(ino, Some(ino) if ino != number) {
+                continue;
+            }
+            let bucket = match tx.get_bucket(number.to_be_bytes()) {
+                Ok(bucket) => bucket,
+                // the inode has been removed, drop its data
+                Err(jammdb::Error::BucketMissing) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for (&index, slice) in inode.slices.iter() {
+                // a shared transaction is committed after the slices are dropped
+                if tx.is_shared() {
+                    bucket.put(generate_data_key_with_number(index), slice.to_vec())?;
+                } else {
+                    bucket.put(generate_data_key_with_number(index), slice.as_ref())?;
+                }
+            }
+            let size = get_usize(&bucket, number, "size")?;
+            if inode.size > size {
+                bucket.put("size", inode.size.to_be_bytes())?;
+            }
+        }
+        // the slices are referenced by the transaction until it is committed
+        tx.commit()?;
+        match ino {
+            Some(ino) => {
+                if let Some(inode) = self.inodes.remove(&ino) {
+                    self.count -= inode.slices.len();
+                }
+            }
+            None => {
+                self.inodes.clear();
+                self.count = 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Set the size of the cache in bytes, 0 disables the cache
+pub fn dbfs_common_cache_resize(size: usize) -> DbfsResult<()> {
+    let mut cache = SLICE_CACHE.lock();
+    cache.flush(None)?;
+    cache.capacity = size / SLICE_SIZE;
+    Ok(())
+}
+
+/// Commit the dirty data of the inode
+pub fn dbfs_common_cache_flush(ino: usize) -> DbfsResult<()> {
+    SLICE_CACHE.lock().flush(Some(ino))
+}
+
+/// Commit all the dirty data
+pub fn dbfs_common_cache_flush_all() -> DbfsResult<()> {
+    SLICE_CACHE.lock().flush(None)
+}
+
+/// The writes go to the cache
+pub(crate) fn cache_enabled() -> bool {
+    SLICE_CACHE.lock().capacity != 0
+}
+
+/// Get the file size including the dirty data
+pub(crate) fn cached_size(ino: usize) -> Option<usize> {
+    SLICE_CACHE.lock().inodes.get(&ino).map(|inode| inode.size)
+}
+
+/// Write the data to the cache, return None if the cache is disabled or the data
+/// doesn't fit in it
+///
+/// The old dirty data is committed before the write is absorbed, so a failed commit
+/// doesn't take the data of this write.
+pub(crate) fn cached_write(ino: usize, buf: &[u8], offset: u64) -> DbfsResult<Option<usize>> {
+    let mut cache = SLICE_CACHE.lock();
+    if cache.capacity == 0 {
+        return Ok(None);
+    }
+    let first = (offset / SLICE_SIZE as u64) as u32;
+    let end = (offset + buf.len() as u64).div_ceil(SLICE_SIZE as u64) as u32;
+    let new = match cache.inodes.get(&ino) {
+        Some(inode) => (first..end)
+            .filter(|x| !inode.slices.contains_key(x))
+            .count(),
+        None => (end - first) as usize,
+    };
+    if cache.count + new > cache.capacity {
+        cache.flush(None)?;
+        // the cache is empty, the write goes to the db directly
+        if (end - first) as usize > cache.capacity {
+            return Ok(None);
+        }
+    }
+    if !cache.inodes.contains_key(&ino) {
+        let tx = dbfs_tx(false)?;
+        let bucket = tx.get_bucket(ino.to_be_bytes())?;
+        let size = get_usize(&bucket, ino, "size")?;
+        cache.inodes.insert(
+            ino,
+            DirtyInode {
+                size,
+                slices: BTreeMap::new(),
+            },
+        );
+    }
+    let mut num = first;
+    let mut slice_offset = (offset % SLICE_SIZE as u64) as usize;
+    let mut count = 0;
+    let SliceCache {
+        inodes,
+        count: dirty,
+        ..
+    } = &mut *cache;
+    let inode = inodes.get_mut(&ino).unwrap();
+    while count < buf.len() {
+        let len = min(buf.len() - count, SLICE_SIZE - slice_offset);
+        if !inode.slices.contains_key(&num) {
+            // a whole slice write doesn't need the old data
+            let slice = if len == SLICE_SIZE {
+                vec![0u8; SLICE_SIZE].into_boxed_slice()
+            } else {
+                load_slice(ino, num)?
+            };
+            inode.slices.insert(num, slice);
+            *dirty += 1;
+        }
+        let slice = inode.slices.get_mut(&num).unwrap();
+        slice[slice_offset..slice_offset + len].copy_from_slice(&buf[count..count + len]);
+        count += len;
+        slice_offset = 0;
+        num += 1;
+    }
+    inode.size = max(inode.size, offset as usize + count);
+    Ok(Some(count))
+}
+
+/// Read the data with the dirty slices on top of the committed data,
+/// `read` reads the committed data
+///
+/// The dirty slices in the range are copied out first, so the cache isn't locked
+/// while the db is read.
+pub(crate) fn cached_read<F>(ino: usize, buf: &mut [u8], offset: u64, read: F) -> DbfsResult<usize>
+where
+    F: FnOnce(&mut [u8], u64) -> DbfsResult<usize>,
+{
+    let cache = SLICE_CACHE.lock();
+    let inode = match cache.inodes.get(&ino) {
+        Some(inode) => inode,
+        None => {
+            drop(cache);
+            return read(buf, offset);
+        }
+    };
+    if offset >= inode.size as u64 {
+        return Ok(0);
+    }
+    let offset = offset as usize;
+    let len = min(buf.len(), inode.size - offset);
+    if len == 0 {
+        return Ok(0);
+    }
+    let first = (offset / SLICE_SIZE) as u32;
+    let last = ((offset + len - 1) / SLICE_SIZE) as u32;
+    let dirty = inode
+        .slices
+        .range(first..=last)
+        .map(|(&index, slice)| (index, slice.clone()))
+        .collect::<Vec<_>>();
+    drop(cache);
+    let buf = &mut buf[..len];
+    // the data after the committed size is a hole or dirty
+    let read_len = read(buf, offset as u64)?;
+    buf[read_len..].fill(0);
+    for (index, slice) in dirty {
+        let slice_start = index as usize * SLICE_SIZE;
+        let start = max(slice_start, offset);
+        let end = min(slice_start + SLICE_SIZE, offset + len);
+        buf[start - offset..end - offset]
+            .copy_from_slice(&slice[start - slice_start..end - slice_start]);
+    }
+    Ok(len)
+}
+
+fn load_slice(ino: usize, index: u32) -> DbfsResult<Box<[u8]>> {
+    let tx = dbfs_tx(false)?;
+    let bucket = tx.get_bucket(ino.to_be_bytes())?;
+    let key = generate_data_key_with_number(index);
+    match bucket.get_kv(&key) {
+        Some(kv) if kv.value().len() == SLICE_SIZE => Ok(kv.value().into()),
+        Some(_) => Err(DbfsError::corrupted(ino, &key)),
+        None => Ok(vec![0u8; SLICE_SIZE].into_boxed_slice()),
+    }
+}
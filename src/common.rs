@@ -6,6 +6,7 @@ use core::{
 };
 
 use bitflags::bitflags;
+use jammdb::{Bucket, KVPair};
 use onlyerror::Error;
 use rvfs::dentry::DirentType;
 use spin::{Once, RwLock};
@@ -20,7 +21,23 @@ pub const ACCESS_F_OK: u16 = 0;
 pub const ACCESS_W_OK: u16 = 2;
 pub const ACCESS_X_OK: u16 = 1;
 
+/// The flags of renameat2
+pub const RENAME_NOREPLACE: u32 = 0x1;
 pub const RENAME_EXCHANGE: u32 = 0x2;
+/// Leave a whiteout at the source, a char device with the device number 0
+pub const RENAME_WHITEOUT: u32 = 0x4;
+
+/// The inode flags of chattr, stored as `flags` in the inode bucket
+pub const FS_IMMUTABLE_FL: u32 = 0x10;
+pub const FS_APPEND_FL: u32 = 0x20;
+pub const FS_NODUMP_FL: u32 = 0x40;
+pub const FS_NOATIME_FL: u32 = 0x80;
+/// The flags DBFS keeps, the others are refused
+pub const DBFS_FLAGS_MASK: u32 = FS_IMMUTABLE_FL | FS_APPEND_FL | FS_NODUMP_FL | FS_NOATIME_FL;
+
+/// The whence of lseek to find the next data or hole
+pub const SEEK_DATA: i32 = 3;
+pub const SEEK_HOLE: i32 = 4;
 
 #[derive(Default, Clone)]
 pub struct DbfsDirEntry {
@@ -47,37 +64,149 @@ impl Debug for DbfsDirEntry {
 #[derive(Error, Debug)]
 pub enum DbfsError {
     #[error("DbfsError::PermissionDenied")]
-    PermissionDenied = 1,
+    PermissionDenied,
     #[error("DbfsError::NotFound")]
-    NotFound = 2,
+    NotFound,
     #[error("DbfsError::AccessError")]
-    AccessError = 13,
+    AccessError,
     #[error("DbfsError::FileExists")]
-    FileExists = 17,
+    FileExists,
     #[error("DbfsError::InvalidArgument")]
-    InvalidArgument = 22,
+    InvalidArgument,
     #[error("DbfsError::NoSpace")]
-    NoSpace = 28,
+    NoSpace,
     #[error("DbfsError::RangeError")]
-    RangeError = 34,
+    RangeError,
     #[error("DbfsError::NameTooLong")]
-    NameTooLong = 36,
+    NameTooLong,
     #[error("DbfsError::NoSys")]
-    NoSys = 38,
+    NoSys,
     #[error("DbfsError::NotEmpty")]
-    NotEmpty = 39,
+    NotEmpty,
     #[error("DbfsError::Io")]
-    Io = 5,
+    Io,
     #[error("DbfsError::NotSupported")]
-    NotSupported = 95,
+    NotSupported,
     #[error("DbfsError::NoData")]
-    NoData = 61,
+    NoData,
+    /// The file handle isn't open, or not open for the operation
+    #[error("DbfsError::BadFd")]
+    BadFd,
+    /// The operation would block, e.g. a lock held by another owner
+    #[error("DbfsError::WouldBlock")]
+    WouldBlock,
+    /// The offset is beyond the end of the file
+    #[error("DbfsError::NoAddress")]
+    NoAddress,
+    /// The ioctl isn't supported
+    #[error("DbfsError::NoTty")]
+    NoTty,
+    /// A key of an inode bucket is missing or can't be decoded, ino 0 stands for the super block
+    #[error("DbfsError::Corrupted(ino: {ino}, key: {key})")]
+    Corrupted { ino: usize, key: String },
     #[error("DbfsError::Other")]
-    Other = 999,
+    Other,
+}
+
+impl DbfsError {
+    /// The errno value reported to the caller
+    pub fn errno(&self) -> i32 {
+        match self {
+            DbfsError::PermissionDenied => 1,
+            DbfsError::NotFound => 2,
+            DbfsError::Io => 5,
+            DbfsError::NoAddress => 6,
+            DbfsError::BadFd => 9,
+            DbfsError::WouldBlock => 11,
+            DbfsError::AccessError => 13,
+            DbfsError::NoTty => 25,
+            DbfsError::FileExists => 17,
+            DbfsError::InvalidArgument => 22,
+            DbfsError::NoSpace => 28,
+            DbfsError::RangeError => 34,
+            DbfsError::NameTooLong => 36,
+            DbfsError::NoSys => 38,
+            DbfsError::NotEmpty => 39,
+            DbfsError::NoData => 61,
+            DbfsError::NotSupported => 95,
+            // EUCLEAN, the same value ext4 uses for EFSCORRUPTED
+            DbfsError::Corrupted { .. } => 117,
+            // EIO, like the message of `as_str`
+            DbfsError::Other => 5,
+        }
+    }
+
+    /// The message reported through the `StrResult` of rvfs.
+    ///
+    /// The message is the name of the errno, e.g. `EACCES` for [`DbfsError::AccessError`],
+    /// so the kernel can get the errno back with [`DbfsError::errno_from_str`].
+    /// [`DbfsError::Other`] is reported as `EIO`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DbfsError::PermissionDenied => "EPERM",
+            DbfsError::NotFound => "ENOENT",
+            DbfsError::Io => "EIO",
+            DbfsError::NoAddress => "ENXIO",
+            DbfsError::BadFd => "EBADF",
+            DbfsError::WouldBlock => "EAGAIN",
+            DbfsError::AccessError => "EACCES",
+            DbfsError::NoTty => "ENOTTY",
+            DbfsError::FileExists => "EEXIST",
+            DbfsError::InvalidArgument => "EINVAL",
+            DbfsError::NoSpace => "ENOSPC",
+            DbfsError::RangeError => "ERANGE",
+            DbfsError::NameTooLong => "ENAMETOOLONG",
+            DbfsError::NoSys => "ENOSYS",
+            DbfsError::NotEmpty => "ENOTEMPTY",
+            DbfsError::NoData => "ENODATA",
+            DbfsError::NotSupported => "EOPNOTSUPP",
+            DbfsError::Corrupted { .. } => "EUCLEAN",
+            DbfsError::Other => "EIO",
+        }
+    }
+
+    /// Get the errno of a message returned by the rvfs callbacks of DBFS
+    pub fn errno_from_str(msg: &str) -> Option<i32> {
+        let errno = match msg {
+            "EPERM" => 1,
+            "ENOENT" => 2,
+            "EIO" => 5,
+            "ENXIO" => 6,
+            "EBADF" => 9,
+            "EAGAIN" => 11,
+            "EACCES" => 13,
+            "ENOTTY" => 25,
+            "EEXIST" => 17,
+            "EINVAL" => 22,
+            "ENOSPC" => 28,
+            "ERANGE" => 34,
+            "ENAMETOOLONG" => 36,
+            "ENOSYS" => 38,
+            "ENOTEMPTY" => 39,
+            "ENODATA" => 61,
+            "EOPNOTSUPP" => 95,
+            "EUCLEAN" => 117,
+            _ => return None,
+        };
+        Some(errno)
+    }
+
+    pub fn corrupted<T: AsRef<[u8]>>(ino: usize, key: T) -> Self {
+        DbfsError::Corrupted {
+            ino,
+            key: String::from_utf8_lossy(key.as_ref()).into_owned(),
+        }
+    }
 }
 
 pub type DbfsResult<T> = Result<T, DbfsError>;
 
+impl From<DbfsError> for &'static str {
+    fn from(value: DbfsError) -> Self {
+        value.as_str()
+    }
+}
+
 impl From<jammdb::Error> for DbfsError {
     fn from(value: jammdb::Error) -> Self {
         match value {
@@ -141,13 +270,13 @@ pub enum DbfsFileType {
 impl Into<DirentType> for DbfsFileType {
     fn into(self) -> DirentType {
         match self {
-            DbfsFileType::NamedPipe => DirentType::DT_UNKNOWN,
-            DbfsFileType::CharDevice => DirentType::DT_UNKNOWN,
-            DbfsFileType::BlockDevice => DirentType::DT_UNKNOWN,
+            DbfsFileType::NamedPipe => DirentType::DT_FIFO,
+            DbfsFileType::CharDevice => DirentType::DT_CHR,
+            DbfsFileType::BlockDevice => DirentType::DT_BLK,
             DbfsFileType::Directory => DirentType::DT_DIR,
             DbfsFileType::RegularFile => DirentType::DT_REG,
             DbfsFileType::Symlink => DirentType::DT_LNK,
-            DbfsFileType::Socket => DirentType::DT_UNKNOWN,
+            DbfsFileType::Socket => DirentType::DT_SOCK,
         }
     }
 }
@@ -158,39 +287,42 @@ impl Default for DbfsFileType {
     }
 }
 
-impl From<DbfsPermission> for DbfsFileType {
-    fn from(value: DbfsPermission) -> Self {
-        if value.contains(DbfsPermission::S_IFSOCK) {
-            DbfsFileType::Socket
-        } else if value.contains(DbfsPermission::S_IFLNK) {
-            DbfsFileType::Symlink
-        } else if value.contains(DbfsPermission::S_IFREG) {
-            DbfsFileType::RegularFile
-        } else if value.contains(DbfsPermission::S_IFBLK) {
-            DbfsFileType::BlockDevice
-        } else if value.contains(DbfsPermission::S_IFDIR) {
-            DbfsFileType::Directory
-        } else if value.contains(DbfsPermission::S_IFCHR) {
-            DbfsFileType::CharDevice
-        } else if value.contains(DbfsPermission::S_IFIFO) {
-            DbfsFileType::NamedPipe
+impl TryFrom<DbfsPermission> for DbfsFileType {
+    type Error = DbfsError;
+    fn try_from(value: DbfsPermission) -> Result<Self, Self::Error> {
+        let kind = value & DbfsPermission::S_IFMT;
+        if kind == DbfsPermission::S_IFSOCK {
+            Ok(DbfsFileType::Socket)
+        } else if kind == DbfsPermission::S_IFLNK {
+            Ok(DbfsFileType::Symlink)
+        } else if kind == DbfsPermission::S_IFREG {
+            Ok(DbfsFileType::RegularFile)
+        } else if kind == DbfsPermission::S_IFBLK {
+            Ok(DbfsFileType::BlockDevice)
+        } else if kind == DbfsPermission::S_IFDIR {
+            Ok(DbfsFileType::Directory)
+        } else if kind == DbfsPermission::S_IFCHR {
+            Ok(DbfsFileType::CharDevice)
+        } else if kind == DbfsPermission::S_IFIFO {
+            Ok(DbfsFileType::NamedPipe)
         } else {
-            panic!("Invalid file type");
+            Err(DbfsError::InvalidArgument)
         }
     }
 }
 
-impl From<&[u8]> for DbfsFileType {
-    fn from(value: &[u8]) -> Self {
+impl TryFrom<&[u8]> for DbfsFileType {
+    type Error = DbfsError;
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
         match value {
-            b"p" => DbfsFileType::NamedPipe,
-            b"c" => DbfsFileType::CharDevice,
-            b"b" => DbfsFileType::BlockDevice,
-            b"d" => DbfsFileType::Directory,
-            b"f" => DbfsFileType::RegularFile,
-            b"l" => DbfsFileType::Symlink,
-            b"s" => DbfsFileType::Socket,
-            _ => panic!("Invalid file type"),
+            b"p" => Ok(DbfsFileType::NamedPipe),
+            b"c" => Ok(DbfsFileType::CharDevice),
+            b"b" => Ok(DbfsFileType::BlockDevice),
+            b"d" => Ok(DbfsFileType::Directory),
+            b"f" => Ok(DbfsFileType::RegularFile),
+            b"l" => Ok(DbfsFileType::Symlink),
+            b"s" => Ok(DbfsFileType::Socket),
+            _ => Err(DbfsError::InvalidArgument),
         }
     }
 }
@@ -292,6 +424,15 @@ pub struct DbfsAttr {
     pub flags: u32,
 }
 
+/// The bytes `logical..logical + length` of a file which are stored in slices
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DbfsExtent {
+    pub logical: u64,
+    pub length: u64,
+    /// It is the last extent of the file
+    pub last: bool,
+}
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct DbfsFsStat {
@@ -319,6 +460,76 @@ pub fn generate_data_key(value: &str) -> String {
     format!("data:{}", value)
 }
 
+fn get_bytes<const N: usize>(
+    bucket: &Bucket<'_, '_>,
+    ino: usize,
+    key: &str,
+) -> DbfsResult<[u8; N]> {
+    let kv = bucket
+        .get_kv(key)
+        .ok_or_else(|| DbfsError::corrupted(ino, key))?;
+    kv.value()
+        .try_into()
+        .map_err(|_| DbfsError::corrupted(ino, key))
+}
+
+/// Read a u16 value stored in the bucket of inode `ino`
+pub fn get_u16(bucket: &Bucket<'_, '_>, ino: usize, key: &str) -> DbfsResult<u16> {
+    get_bytes(bucket, ino, key).map(u16::from_be_bytes)
+}
+
+/// Read a u32 value stored in the bucket of inode `ino`
+pub fn get_u32(bucket: &Bucket<'_, '_>, ino: usize, key: &str) -> DbfsResult<u32> {
+    get_bytes(bucket, ino, key).map(u32::from_be_bytes)
+}
+
+/// Read a u64 value stored in the bucket of inode `ino`
+pub fn get_u64(bucket: &Bucket<'_, '_>, ino: usize, key: &str) -> DbfsResult<u64> {
+    get_bytes(bucket, ino, key).map(u64::from_be_bytes)
+}
+
+/// Read a usize value stored in the bucket of inode `ino`
+pub fn get_usize(bucket: &Bucket<'_, '_>, ino: usize, key: &str) -> DbfsResult<usize> {
+    get_bytes(bucket, ino, key).map(usize::from_be_bytes)
+}
+
+/// Read a time value stored in the bucket of inode `ino`
+pub fn get_time(bucket: &Bucket<'_, '_>, ino: usize, key: &str) -> DbfsResult<DbfsTimeSpec> {
+    get_bytes::<12>(bucket, ino, key).map(|x| DbfsTimeSpec::from(x.as_slice()))
+}
+
+/// Read the inode flags, the inodes made before they were stored have none
+pub fn get_flags(bucket: &Bucket<'_, '_>, ino: usize) -> DbfsResult<u32> {
+    match bucket.get_kv("flags") {
+        Some(_) => get_u32(bucket, ino, "flags"),
+        None => Ok(0),
+    }
+}
+
+/// Refuse to change an inode with these flags if it is immutable or append-only
+pub fn check_mutable(flags: u32) -> DbfsResult<()> {
+    if flags & (FS_IMMUTABLE_FL | FS_APPEND_FL) != 0 {
+        return Err(DbfsError::PermissionDenied);
+    }
+    Ok(())
+}
+
+/// Read the file type and permission stored in the bucket of inode `ino`
+pub fn get_mode(bucket: &Bucket<'_, '_>, ino: usize) -> DbfsResult<(DbfsFileType, DbfsPermission)> {
+    let mode = get_u16(bucket, ino, "mode")?;
+    let mode = DbfsPermission::from_bits_truncate(mode);
+    let kind = DbfsFileType::try_from(mode).map_err(|_| DbfsError::corrupted(ino, "mode"))?;
+    Ok((kind, mode))
+}
+
+/// The value of a `data:` entry in directory `dir` is the inode number as a decimal string
+pub fn parse_entry_ino(dir: usize, kv: &KVPair<'_, '_>) -> DbfsResult<usize> {
+    core::str::from_utf8(kv.value())
+        .ok()
+        .and_then(|x| x.parse::<usize>().ok())
+        .ok_or_else(|| DbfsError::corrupted(dir, kv.key()))
+}
+
 #[derive(Debug, Clone)]
 pub struct ReadDirInfo {
     pub offset: usize,
@@ -333,23 +544,26 @@ impl ReadDirInfo {
 
 /// When readdir firstly, we need to store the offset and key for the ino so that
 /// we can continue to read the directory when the fuse call readdir again.
-pub static GLOBAL_READDIR_TABLE: RwLock<BTreeMap<usize, ReadDirInfo>> =
+///
+/// The key is the ino and the handle of the open directory, the opens don't share
+/// their positions.
+pub static GLOBAL_READDIR_TABLE: RwLock<BTreeMap<(usize, usize), ReadDirInfo>> =
     RwLock::new(BTreeMap::new());
 
-pub fn push_readdir_table(ino: usize, info: ReadDirInfo) {
+pub fn push_readdir_table(ino: usize, fh: usize, info: ReadDirInfo) {
     let mut table = GLOBAL_READDIR_TABLE.write();
-    table.insert(ino, info);
+    table.insert((ino, fh), info);
 }
 
-pub fn pop_readdir_table(ino: usize) -> Option<ReadDirInfo> {
+pub fn pop_readdir_table(ino: usize, fh: usize) -> Option<ReadDirInfo> {
     let mut table = GLOBAL_READDIR_TABLE.write();
-    table.remove(&ino)
+    table.remove(&(ino, fh))
 }
 
 /// This function will be called when the fuse call readdir.
-pub fn get_readdir_table(ino: usize) -> Option<ReadDirInfo> {
+pub fn get_readdir_table(ino: usize, fh: usize) -> Option<ReadDirInfo> {
     let table = GLOBAL_READDIR_TABLE.read();
-    table.get(&ino).cloned()
+    table.get(&(ino, fh)).cloned()
 }
 
 #[cfg(feature = "fuse")]
@@ -360,6 +574,7 @@ mod impl_fuse {
     use fuser::{FileAttr, FileType};
 
     use super::*;
+    use crate::fuse::attr::{local_gid, local_uid};
 
     impl From<DbfsFileType> for FileType {
         fn from(value: DbfsFileType) -> Self {
@@ -404,8 +619,8 @@ mod impl_fuse {
                 kind: value.kind.into(),
                 perm: value.perm & 0o777,
                 nlink: value.nlink,
-                uid: value.uid,
-                gid: value.gid,
+                uid: local_uid(value.uid),
+                gid: local_gid(value.gid),
                 rdev: value.rdev,
                 blksize: value.blksize,
                 flags: 0,
@@ -426,8 +641,8 @@ mod impl_fuse {
                 kind: value.kind.into(),
                 perm: value.perm & 0o777,
                 nlink: value.nlink,
-                uid: value.uid,
-                gid: value.gid,
+                uid: local_uid(value.uid),
+                gid: local_gid(value.gid),
                 rdev: value.rdev,
                 blksize: value.blksize,
                 flags: 0,
@@ -448,7 +663,10 @@ mod impl_rvfs {
                 DbfsFileType::Directory => InodeMode::S_DIR,
                 DbfsFileType::RegularFile => InodeMode::S_FILE,
                 DbfsFileType::Symlink => InodeMode::S_SYMLINK,
-                _ => panic!("Invalid file type"),
+                DbfsFileType::NamedPipe => InodeMode::S_FIFO,
+                DbfsFileType::CharDevice => InodeMode::S_CHARDEV,
+                DbfsFileType::BlockDevice => InodeMode::S_BLKDEV,
+                DbfsFileType::Socket => InodeMode::S_SOCK,
             }
         }
     }
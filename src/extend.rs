@@ -12,13 +12,12 @@ use jammdb::{Bucket, Data};
 use preprint::pprintln;
 use rvfs::{info, warn, StrResult};
 
-use crate::clone_db;
+use crate::tx::dbfs_tx;
 
 /// bucket: root:key1:key2:key3
 pub fn execute_operate(bucket: &str, operate: OperateSet) -> isize {
     info!("execute_operate");
-    let db = clone_db();
-    let tx = db.tx(true).unwrap();
+    let tx = dbfs_tx(true).unwrap();
     let path = bucket.split(":").collect::<Vec<&str>>();
     let mut bucket = tx.get_bucket(path[0]).unwrap();
 
@@ -120,8 +119,7 @@ fn copy_bucket_data_recursive<'a, 'tx>(old_bucket: Bucket<'a, 'tx>, new_bucket:
 }
 
 pub fn extend_create_global_bucket(key: &str) -> StrResult<()> {
-    let db = clone_db();
-    let tx = db.tx(true).unwrap();
+    let tx = dbfs_tx(true).unwrap();
     let bucket = tx.create_bucket(key);
     if bucket.is_err() {
         Err("create bucket failed")
@@ -132,8 +130,7 @@ pub fn extend_create_global_bucket(key: &str) -> StrResult<()> {
 }
 
 pub fn show_dbfs() -> StrResult<()> {
-    let db = clone_db();
-    let tx = db.tx(true).unwrap();
+    let tx = dbfs_tx(true).unwrap();
     tx.buckets().for_each(|(name, x)| {
         let key = name.name();
         let key = String::from_utf8_lossy(key).to_string();
@@ -180,8 +177,7 @@ pub fn execute<T, R>(key: &str, func: T, buf: &mut [u8]) -> R
 where
     T: FnOnce(&str, MyPara, &mut [u8]) -> R,
 {
-    let db = clone_db();
-    let tx = db.tx(true).unwrap();
+    let tx = dbfs_tx(true).unwrap();
     let component = key.split(":").collect::<Vec<&str>>();
     let mut bucket = tx.get_bucket(component[0]).unwrap();
     for i in 1..component.len() - 1 {
@@ -7,8 +7,8 @@ use core::{
     sync::atomic::AtomicBool,
 };
 
-use jammdb::Data;
-use log::{error, trace, warn};
+use jammdb::{Bucket, Data};
+use log::{trace, warn};
 use rvfs::{
     dentry::{Dirent64, DirentType},
     file::{File, FileOps},
@@ -16,15 +16,17 @@ use rvfs::{
 };
 
 use crate::{
-    clone_db,
+    cache::{cache_enabled, cached_read, cached_size, cached_write, dbfs_common_cache_flush},
     common::{
-        generate_data_key_with_number, get_readdir_table, pop_readdir_table, push_readdir_table,
-        DbfsDirEntry, DbfsError, DbfsFileType, DbfsPermission, DbfsResult, DbfsTimeSpec,
-        ReadDirInfo,
+        generate_data_key_with_number, get_flags, get_mode, get_readdir_table, get_usize,
+        parse_entry_ino, pop_readdir_table, push_readdir_table, DbfsDirEntry, DbfsError,
+        DbfsExtent, DbfsResult, DbfsTimeSpec, ReadDirInfo, FS_APPEND_FL, FS_IMMUTABLE_FL,
+        SEEK_DATA, SEEK_HOLE,
     },
     copy_data,
     inode::{checkout_access, dbfs_common_attr},
-    u16, u32, usize, BUDDY_ALLOCATOR, SLICE_SIZE,
+    tx::dbfs_tx,
+    BUDDY_ALLOCATOR, SLICE_SIZE,
 };
 
 pub const DBFS_DIR_FILE_OPS: FileOps = {
@@ -57,13 +59,13 @@ fn dbfs_file_write(file: Arc<File>, buf: &[u8], offset: u64) -> StrResult<usize>
     let dentry = file.f_dentry.clone();
     let inode = dentry.access_inner().d_inode.clone();
     let numer = inode.number;
-    dbfs_common_write(numer, buf, offset).map_err(|_| "dbfs_common_write error")
+    Ok(dbfs_common_write(numer, buf, offset)?)
 }
 fn dbfs_file_read(file: Arc<File>, buf: &mut [u8], offset: u64) -> StrResult<usize> {
     let dentry = file.f_dentry.clone();
     let inode = dentry.access_inner().d_inode.clone();
     let numer = inode.number;
-    dbfs_common_read(numer, buf, offset).map_err(|_| "dbfs_common_read error")
+    Ok(dbfs_common_read(numer, buf, offset)?)
 }
 
 /// the file data in dbfs is stored as a set of key-value pairs
@@ -79,114 +81,107 @@ pub fn dbfs_common_read(number: usize, buf: &mut [u8], offset: u64) -> DbfsResul
         buf.len(),
         SLICE_SIZE
     );
-    let db = clone_db();
-    let tx = db.tx(false)?;
-    let bucket = tx.get_bucket(number.to_be_bytes())?;
-    let size = bucket.get_kv("size").unwrap();
-    let size = usize!(size.value());
-    if offset >= size as u64 {
-        return Ok(0);
-    }
-    // TODO! second version
-    let tmp = [0u8; SLICE_SIZE];
-    let mut start_num = offset / SLICE_SIZE as u64;
-    // let mut offset = offset % SLICE_SIZE as u64;
-    // let mut count = 0;
-    // loop {
-    //     let key = generate_data_key_with_number(start_num as u32);
-    //     let value = bucket.get_kv(key);
-    //     let real_size = min(size - start_num as usize * SLICE_SIZE, SLICE_SIZE);
-    //     if value.is_none(){
-    //         // copy tmp buf to buf
-    //         let len = min(buf.len() - count, real_size.saturating_sub(offset as usize));
-    //         buf[count..count + len].copy_from_slice(&tmp[offset as usize..offset as usize + len]);
-    //         count += len;
-    //         offset  = (offset + len as u64) % SLICE_SIZE as u64;
-    //     }else {
-    //         let value = value.unwrap();
-    //         let value = value.value();
-    //         let len = min(buf.len() - count, real_size.saturating_sub(offset as usize));
-    //         buf[count..count + len].copy_from_slice(&value[offset as usize..offset as usize + len]);
-    //         count += len;
-    //         offset  = (offset + len as u64) % SLICE_SIZE as u64;
-    //     }
-    //     if count == buf.len() || count == size {
-    //         break;
-    //     }
-    //     start_num += 1;
-    // }
-
-    // TODO! first version
-    let end_num = (offset + buf.len() as u64) / SLICE_SIZE as u64 + 1;
-    let offset = offset % SLICE_SIZE as u64;
-    let mut buf_offset = 0;
-
-    let f_end_num = size / SLICE_SIZE + 1;
-
-    let end_num = min(end_num, f_end_num as u64);
+    cached_read(number, buf, offset, |buf, offset| {
+        dbfs_read_slices(number, buf, offset)
+    })
+}
 
-    warn!(
-        "start_num: {:?}, end_num: {:?}, file_end_num:{:?}",
-        start_num, end_num, f_end_num
-    );
+/// The zero slice the holes of the files are read from
+static ZERO_SLICE: [u8; SLICE_SIZE] = [0; SLICE_SIZE];
 
-    let start_key = generate_data_key_with_number(start_num as u32);
-    let end_key = generate_data_key_with_number(end_num as u32);
+/// Read the committed data of the file
+fn dbfs_read_slices(number: usize, buf: &mut [u8], offset: u64) -> DbfsResult<usize> {
+    dbfs_common_read_parts(number, offset, buf.len(), |parts| {
+        let mut count = 0;
+        for part in parts {
+            buf[count..count + part.len()].copy_from_slice(part);
+            count += part.len();
+        }
+        count
+    })
+}
 
-    let range = Range {
-        start: start_key.as_slice(),
-        end: end_key.as_slice(),
-    };
-    let iter = bucket.range(range);
-    for data in iter {
-        match data {
-            Data::Bucket(_) => {
-                panic!("bucket in bucket")
-            }
-            Data::KeyValue(kv) => {
-                let value = kv.value();
-                let key = kv.key();
+/// Read `offset..offset + len` of the file without copying it, the data is flushed first.
+///
+/// `f` gets the parts in order, they borrow the values of the read transaction and
+/// the holes are a shared zero slice. The parts stop at the end of the file.
+/// The transaction is open while `f` runs, so it can't call into the filesystem.
+pub fn dbfs_common_read_borrowed<F, R>(
+    number: usize,
+    offset: u64,
+    len: usize,
+    f: F,
+) -> DbfsResult<R>
+where
+    F: FnOnce(&[&[u8]]) -> R,
+{
+    // the dirty slices aren't in the bucket yet
+    dbfs_common_cache_flush(number)?;
+    dbfs_common_read_parts(number, offset, len, f)
+}
 
-                let index = key.splitn(2, |c| *c == b':').nth(1).unwrap();
-                let index = u32!(index);
-                // debug!("key: {:?}", index);
-                if index as u64 != start_num {
-                    for i in start_num as u32..index {
-                        let current_size = i as usize * SLICE_SIZE; // offset = 1000 ,current_size >= SLICE_SIZE,1024 => offset= 1000 - SLICE_SIZE = 488
-                        let value_offset = offset.saturating_sub(current_size as u64) as usize; // 一定位于(0,SLICE_SIZE)范围
-                        let real_size = min(size - current_size, SLICE_SIZE);
-                        let len = min(
-                            buf.len() - buf_offset,
-                            real_size.saturating_sub(value_offset),
-                        );
-                        buf[buf_offset..buf_offset + len]
-                            .copy_from_slice(&tmp[value_offset..value_offset + len]);
-                        buf_offset += len;
-                    }
-                    start_num = index as u64 + 1;
-                }
-                let current_size = index as usize * SLICE_SIZE; // offset = 1000 ,current_size >= SLICE_SIZE,1024 => offset= 1000 - SLICE_SIZE = 488
-                let value_offset = offset.saturating_sub(current_size as u64) as usize; // 一定位于(0,SLICE_SIZE)范围
-                let real_size = min(size - current_size, SLICE_SIZE);
-                let len = min(
-                    buf.len() - buf_offset,
-                    real_size.saturating_sub(value_offset),
-                );
-                buf[buf_offset..buf_offset + len]
-                    .copy_from_slice(&value[value_offset..value_offset + len]);
-                buf_offset += len;
-                start_num += 1;
-                warn!("read len: {}", len);
+/// [`dbfs_common_read_borrowed`] without the dirty data in the cache
+pub(crate) fn dbfs_common_read_parts<F, R>(
+    number: usize,
+    offset: u64,
+    len: usize,
+    f: F,
+) -> DbfsResult<R>
+where
+    F: FnOnce(&[&[u8]]) -> R,
+{
+    let tx = dbfs_tx(false)?;
+    let bucket = tx.get_bucket(number.to_be_bytes())?;
+    let size = get_usize(&bucket, number, "size")? as u64;
+    let end = min(offset.saturating_add(len as u64), size);
+    let mut parts: Vec<&[u8]> = vec![];
+    if offset < end {
+        let start_num = offset / SLICE_SIZE as u64;
+        let end_num = (end - 1) / SLICE_SIZE as u64 + 1;
+        let start_key = generate_data_key_with_number(start_num as u32);
+        let end_key = generate_data_key_with_number(end_num as u32);
+        let range = Range {
+            start: start_key.as_slice(),
+            end: end_key.as_slice(),
+        };
+        // the end of the parts
+        let mut pos = offset;
+        for data in bucket.range(range) {
+            let kv = match data {
+                Data::KeyValue(kv) => kv,
+                Data::Bucket(name) => return Err(DbfsError::corrupted(number, name.name())),
+            };
+            let index = slice_index(number, kv.key())? as u64;
+            if kv.value().len() != SLICE_SIZE {
+                return Err(DbfsError::corrupted(number, kv.key()));
             }
+            let slice_start = index * SLICE_SIZE as u64;
+            push_hole(&mut parts, pos, slice_start);
+            // SAFETY: the value lives in the pages of the transaction, and `tx` is
+            // dropped only after `f` returns. The guard holds the group lock or owns the
+            // read transaction, so no commit frees or reuses the pages while the parts
+            // are borrowed, and `parts` doesn't escape `f`
+            let value = unsafe { core::slice::from_raw_parts(kv.value().as_ptr(), SLICE_SIZE) };
+            let from = (pos.max(slice_start) - slice_start) as usize;
+            let to = (min(end, slice_start + SLICE_SIZE as u64) - slice_start) as usize;
+            parts.push(&value[from..to]);
+            pos = slice_start + to as u64;
         }
-        if buf_offset == buf.len() {
-            break;
-        }
+        push_hole(&mut parts, pos, end);
     }
-    Ok(buf_offset)
+    Ok(f(&parts))
+}
 
-    // Ok(count)
+/// Add the zero parts of the hole `start..end`
+fn push_hole(parts: &mut Vec<&[u8]>, start: u64, end: u64) {
+    let mut pos = start;
+    while pos < end {
+        let len = min(end - pos, SLICE_SIZE as u64) as usize;
+        parts.push(&ZERO_SLICE[..len]);
+        pos += len as u64;
+    }
 }
+
 #[cfg(feature = "fuse")]
 pub static FLAG: AtomicBool = AtomicBool::new(false);
 /// we need think about how to write data to dbfs
@@ -203,11 +198,54 @@ pub fn dbfs_common_write(number: usize, buf: &[u8], offset: u64) -> DbfsResult<u
         offset,
         buf.len()
     );
-    let db = clone_db();
-    let tx = db.tx(true)?;
-    let bucket = tx.get_bucket(number.to_be_bytes())?;
-    let size = bucket.get_kv("size").unwrap();
-    let size = usize!(size.value());
+    if cache_enabled() {
+        let cached_size = cached_size(number);
+        let tx = dbfs_tx(false)?;
+        let bucket = tx.get_bucket(number.to_be_bytes())?;
+        check_write_flags(&bucket, number, offset, cached_size)?;
+        drop(bucket);
+        drop(tx);
+        if let Some(count) = cached_write(number, buf, offset)? {
+            return Ok(count);
+        }
+    }
+    dbfs_write_slices(number, buf, offset)
+}
+
+/// An immutable file can't be written, and an append-only one only at its end
+/// `cached_size` is taken before the transaction, the cache lock is taken before the group lock
+fn check_write_flags(
+    bucket: &Bucket<'_, '_>,
+    number: usize,
+    offset: u64,
+    cached_size: Option<usize>,
+) -> DbfsResult<()> {
+    let flags = get_flags(bucket, number)?;
+    if flags & FS_IMMUTABLE_FL != 0 {
+        return Err(DbfsError::PermissionDenied);
+    }
+    if flags & FS_APPEND_FL != 0 {
+        let size = match cached_size {
+            Some(size) => size,
+            None => get_usize(bucket, number, "size")?,
+        };
+        if offset < size as u64 {
+            return Err(DbfsError::PermissionDenied);
+        }
+    }
+    Ok(())
+}
+
+/// Write the data to the db directly
+fn dbfs_write_slices(number: usize, buf: &[u8], offset: u64) -> DbfsResult<usize> {
+    let cached_size = cached_size(number);
+    let tx = dbfs_tx(true)?;
+    let (bucket, size) = tx.check(|tx| {
+        let bucket = tx.get_bucket(number.to_be_bytes())?;
+        check_write_flags(&bucket, number, offset, cached_size)?;
+        let size = get_usize(&bucket, number, "size")?;
+        Ok((bucket, size))
+    })?;
     let o_offset = offset;
     let mut num = offset / SLICE_SIZE as u64;
     let mut offset = offset % SLICE_SIZE as u64;
@@ -220,23 +258,14 @@ pub fn dbfs_common_write(number: usize, buf: &[u8], offset: u64) -> DbfsResult<u
         let data = if len == SLICE_SIZE && offset == 0 {
             unsafe { buf.as_ptr().add(count) }
         } else {
-            #[cfg(feature = "fuse")]
-            let start = std::time::SystemTime::now();
             let kv = bucket.get_kv(key.as_slice());
-            #[cfg(feature = "fuse")]
-            {
-                let end = std::time::SystemTime::now();
-                let duration = end.duration_since(start).unwrap();
-                if FLAG.load(core::sync::atomic::Ordering::SeqCst) {
-                    std::println!("get_kv:{} cost {:?}", num, duration);
-                }
-            }
             if kv.is_none() {
-                let ptr = unsafe {
-                    let ptr = BUDDY_ALLOCATOR
-                        .lock()
-                        .alloc(Layout::from_size_align_unchecked(SLICE_SIZE, 8));
-                    ptr.unwrap().as_ptr()
+                let ptr = match alloc_slice() {
+                    Some(ptr) => ptr,
+                    None => {
+                        dealloc_slices(ptrs);
+                        return Err(DbfsError::NoSpace);
+                    }
                 };
                 unsafe {
                     copy_data(buf.as_ptr().add(count), ptr.add(offset as usize), len);
@@ -245,11 +274,16 @@ pub fn dbfs_common_write(number: usize, buf: &[u8], offset: u64) -> DbfsResult<u
                 ptr as *const u8
             } else {
                 let value = kv.as_ref().unwrap().value();
-                let ptr = unsafe {
-                    let ptr = BUDDY_ALLOCATOR
-                        .lock()
-                        .alloc(Layout::from_size_align_unchecked(SLICE_SIZE, 8));
-                    ptr.unwrap().as_ptr()
+                if value.len() != SLICE_SIZE {
+                    dealloc_slices(ptrs);
+                    return Err(DbfsError::corrupted(number, &key));
+                }
+                let ptr = match alloc_slice() {
+                    Some(ptr) => ptr,
+                    None => {
+                        dealloc_slices(ptrs);
+                        return Err(DbfsError::NoSpace);
+                    }
                 };
                 unsafe {
                     copy_data(value.as_ptr(), ptr, offset as usize);
@@ -266,8 +300,16 @@ pub fn dbfs_common_write(number: usize, buf: &[u8], offset: u64) -> DbfsResult<u
         };
 
         let data = unsafe { core::slice::from_raw_parts(data, SLICE_SIZE) };
-
-        bucket.put(key, data)?;
+        // a shared transaction is committed after the buffers are freed
+        let res = if tx.is_shared() {
+            bucket.put(key, data.to_vec())
+        } else {
+            bucket.put(key, data)
+        };
+        if let Err(e) = res {
+            dealloc_slices(ptrs);
+            return Err(e.into());
+        }
         count += len;
         offset = (offset + len as u64) % SLICE_SIZE as u64;
         num += 1;
@@ -278,28 +320,156 @@ pub fn dbfs_common_write(number: usize, buf: &[u8], offset: u64) -> DbfsResult<u
 
     let new_size = max(size, (o_offset as usize + count) as usize);
     if new_size > size {
-        bucket.put("size", new_size.to_be_bytes())?;
+        if let Err(e) = bucket.put("size", new_size.to_be_bytes()) {
+            dealloc_slices(ptrs);
+            return Err(e.into());
+        }
     }
-    tx.commit()?;
+    // the slices are referenced by the transaction until it is committed
+    let res = tx.commit();
+    dealloc_slices(ptrs);
+    res?;
+    Ok(count)
+}
+
+fn alloc_slice() -> Option<*mut u8> {
+    let layout = unsafe { Layout::from_size_align_unchecked(SLICE_SIZE, 8) };
+    BUDDY_ALLOCATOR
+        .lock()
+        .alloc(layout)
+        .ok()
+        .map(|ptr| ptr.as_ptr())
+}
+
+fn dealloc_slices(ptrs: Vec<*mut u8>) {
     ptrs.into_iter().for_each(|ptr| unsafe {
         BUDDY_ALLOCATOR.lock().dealloc(
-            NonNull::new(ptr).unwrap(),
+            NonNull::new_unchecked(ptr),
             Layout::from_size_align_unchecked(SLICE_SIZE, 8),
         )
     });
-    Ok(count)
+}
+
+/// Find the next data or hole at or after `offset`, `whence` is [`SEEK_DATA`] or [`SEEK_HOLE`].
+///
+/// A missing slice is a hole and there is a hole at the end of the file.
+pub fn dbfs_common_lseek(number: usize, offset: u64, whence: i32) -> DbfsResult<u64> {
+    warn!(
+        "dbfs_common_lseek ino: {}, offset: {}, whence: {}",
+        number, offset, whence
+    );
+    if whence != SEEK_DATA && whence != SEEK_HOLE {
+        return Err(DbfsError::InvalidArgument);
+    }
+    // the dirty slices aren't in the bucket yet
+    dbfs_common_cache_flush(number)?;
+    let tx = dbfs_tx(false)?;
+    let bucket = tx.get_bucket(number.to_be_bytes())?;
+    let size = get_usize(&bucket, number, "size")? as u64;
+    if offset >= size {
+        return Err(DbfsError::NoAddress);
+    }
+    let start_num = offset / SLICE_SIZE as u64;
+    let end_num = size / SLICE_SIZE as u64 + 1;
+    let start_key = generate_data_key_with_number(start_num as u32);
+    let end_key = generate_data_key_with_number(end_num as u32);
+    let range = Range {
+        start: start_key.as_slice(),
+        end: end_key.as_slice(),
+    };
+    // the next slice which may be a hole
+    let mut next = start_num;
+    for data in bucket.range(range) {
+        let key = match data {
+            Data::KeyValue(kv) => kv.key().to_vec(),
+            Data::Bucket(name) => return Err(DbfsError::corrupted(number, name.name())),
+        };
+        let index = slice_index(number, &key)? as u64;
+        if whence == SEEK_DATA {
+            let data = max(offset, index * SLICE_SIZE as u64);
+            return if data < size {
+                Ok(data)
+            } else {
+                Err(DbfsError::NoAddress)
+            };
+        }
+        if index != next {
+            break;
+        }
+        next = index + 1;
+    }
+    match whence {
+        SEEK_DATA => Err(DbfsError::NoAddress),
+        _ => Ok(min(max(offset, next * SLICE_SIZE as u64), size)),
+    }
+}
+
+/// Map the slices of the file in `start..start + len` to extents, adjacent slices are merged.
+///
+/// Every slice is stored as a whole value, so the extents are never shared, compressed or inline.
+pub fn dbfs_common_fiemap(number: usize, start: u64, len: u64) -> DbfsResult<Vec<DbfsExtent>> {
+    warn!(
+        "dbfs_common_fiemap ino: {}, start: {}, len: {}",
+        number, start, len
+    );
+    dbfs_common_cache_flush(number)?;
+    let tx = dbfs_tx(false)?;
+    let bucket = tx.get_bucket(number.to_be_bytes())?;
+    let size = get_usize(&bucket, number, "size")? as u64;
+    let end = min(start.saturating_add(len), size);
+    let mut extents: Vec<DbfsExtent> = vec![];
+    if start >= end {
+        return Ok(extents);
+    }
+    let start_key = generate_data_key_with_number((start / SLICE_SIZE as u64) as u32);
+    let end_key = generate_data_key_with_number(((end - 1) / SLICE_SIZE as u64 + 1) as u32);
+    let range = Range {
+        start: start_key.as_slice(),
+        end: end_key.as_slice(),
+    };
+    for data in bucket.range(range) {
+        let key = match data {
+            Data::KeyValue(kv) => kv.key().to_vec(),
+            Data::Bucket(name) => return Err(DbfsError::corrupted(number, name.name())),
+        };
+        let logical = slice_index(number, &key)? as u64 * SLICE_SIZE as u64;
+        let length = min(SLICE_SIZE as u64, size - logical);
+        match extents.last_mut() {
+            Some(last) if last.logical + last.length == logical => last.length += length,
+            _ => extents.push(DbfsExtent {
+                logical,
+                length,
+                last: false,
+            }),
+        }
+    }
+    // the range covers the end of the file, so nothing is after the last one
+    if end == size {
+        if let Some(last) = extents.last_mut() {
+            last.last = true;
+        }
+    }
+    Ok(extents)
+}
+
+/// The key of a slice is `zdata:` followed by the big-endian slice index
+fn slice_index(ino: usize, key: &[u8]) -> DbfsResult<u32> {
+    key.strip_prefix(b"zdata:".as_slice())
+        .and_then(|x| x.try_into().ok())
+        .map(u32::from_be_bytes)
+        .ok_or_else(|| DbfsError::corrupted(ino, key))
 }
 
 fn dbfs_readdir(file: Arc<File>, dirents: &mut [u8]) -> StrResult<usize> {
     let dentry = file.f_dentry.clone();
     let inode = dentry.access_inner().d_inode.clone();
     let numer = inode.number;
-    let db = clone_db();
-    let tx = db.tx(false).unwrap();
-    let bucket = tx.get_bucket(numer.to_be_bytes()).unwrap();
-
     let res: usize = if dirents.is_empty() {
-        bucket
+        let tx = dbfs_tx(false)?;
+        let bucket = tx
+            .get_bucket(numer.to_be_bytes())
+            .map_err(DbfsError::from)?;
+        let size = bucket
             .kv_pairs()
             .map(|x| {
                 if x.key().starts_with("data:".as_bytes()) {
@@ -312,20 +482,17 @@ fn dbfs_readdir(file: Arc<File>, dirents: &mut [u8]) -> StrResult<usize> {
                     0
                 }
             })
-            .sum()
+            .sum();
+        size
     } else {
-        pop_readdir_table(numer);
+        pop_readdir_table(numer, 0);
         let mut count = 0;
         let buf_len = dirents.len();
         let mut ptr = dirents.as_mut_ptr();
         let mut offset = 0;
         loop {
             let mut entries = vec![DbfsDirEntry::default(); 16]; // we read 16 entries at a time
-            let res = dbfs_common_readdir(numer as usize, &mut entries, offset as u64, false);
-            if res.is_err() {
-                return Err("dbfs_common_readdir error");
-            }
-            let res = res.unwrap();
+            let res = dbfs_common_readdir(numer as usize, 0, &mut entries, offset as u64, false)?;
             if res == 0 {
                 trace!("There is no entry in the directory.");
                 return Ok(count);
@@ -354,81 +521,92 @@ fn dbfs_readdir(file: Arc<File>, dirents: &mut [u8]) -> StrResult<usize> {
                 break;
             }
             let x = &entries[res - 1];
-            push_readdir_table(numer, ReadDirInfo::new(x.offset as usize, x.name.clone()));
+            push_readdir_table(
+                numer,
+                0,
+                ReadDirInfo::new(x.offset as usize, x.name.clone()),
+            );
         }
         count
     };
     Ok(res)
 }
 
+/// `fh` is the handle of the open directory, the position of the last read is kept for it
 pub fn dbfs_common_readdir(
     ino: usize,
+    fh: usize,
     buf: &mut Vec<DbfsDirEntry>,
     offset: u64,
     is_readdir_plus: bool,
 ) -> DbfsResult<usize> {
-    let db = clone_db();
-    let tx = db.tx(false)?;
+    let tx = dbfs_tx(false)?;
     let bucket = tx.get_bucket(ino.to_be_bytes())?;
+    // the caller passes the number of entries it wants to read as the length of buf
+    let buf_len = buf.len();
     buf.clear();
     let mut count = 0;
 
-    let buf_len = buf.len();
-
     let mut cursor = bucket.cursor();
-    let readdir_info = get_readdir_table(ino);
-    if readdir_info.is_some() {
-        let info = readdir_info.unwrap();
-        let name = info.key;
-        let save_offset = info.offset;
-        assert_eq!(offset, (save_offset as u64 + 1));
-        let key = format!("data:{}", name);
-        let res = cursor.seek(key);
-        assert_eq!(res, true);
-        let val = cursor.next();
-        assert!(val.is_some());
+    // entries we need to skip when the position of the last readdir is unknown
+    let mut skip = offset;
+    if let Some(info) = get_readdir_table(ino, fh) {
+        if offset == info.offset as u64 + 1 {
+            let key = format!("data:{}", info.key);
+            if cursor.seek(key) {
+                // skip the entry we have returned last time
+                cursor.next();
+            }
+            skip = 0;
+        }
     }
     let mut offset = offset;
-    cursor.for_each(|x| {
-        if let Data::KeyValue(kv) = x {
-            let key = kv.key();
-            if key.starts_with(b"data:") {
-                let key = key.splitn(2, |x| *x == b':').collect::<Vec<&[u8]>>();
-                let name = key[1];
-                let name = core::str::from_utf8(name).unwrap();
-                let value = kv.value();
-                let ino = core::str::from_utf8(value).unwrap();
-                let inode_number = ino.parse::<usize>().unwrap();
-                let mut entry = DbfsDirEntry::default();
-                entry.name = name.to_string();
-                entry.ino = inode_number as u64;
-                entry.offset = offset;
+    for x in cursor {
+        let kv = match x {
+            Data::KeyValue(kv) => kv,
+            Data::Bucket(_) => continue,
+        };
+        let key = kv.key();
+        if !key.starts_with(b"data:") {
+            if key > b"data:".as_slice() {
+                // all entries have been read
+                break;
+            }
+            continue;
+        }
+        if skip > 0 {
+            skip -= 1;
+            continue;
+        }
+        let name = core::str::from_utf8(&key[5..]).map_err(|_| DbfsError::corrupted(ino, key))?;
+        let inode_number = parse_entry_ino(ino, &kv)?;
+        let mut entry = DbfsDirEntry::default();
+        entry.name = name.to_string();
+        entry.ino = inode_number as u64;
+        entry.offset = offset;
 
-                offset += 1;
-                if !is_readdir_plus {
-                    let inode = tx.get_bucket(inode_number.to_be_bytes()).unwrap();
-                    let mode = inode.get_kv("mode").unwrap();
-                    let mode = u16!(mode.value());
-                    let perm = DbfsPermission::from_bits_truncate(mode);
-                    entry.kind = DbfsFileType::from(perm);
-                } else {
-                    let attr = dbfs_common_attr(inode_number).unwrap();
-                    entry.kind = attr.kind;
-                    entry.attr = Some(attr);
-                }
+        offset += 1;
+        let inode = tx
+            .get_bucket(inode_number.to_be_bytes())
+            .map_err(|_| DbfsError::corrupted(ino, key))?;
+        let (kind, _) = get_mode(&inode, inode_number)?;
+        entry.kind = kind;
 
-                buf.push(entry);
-                count += 1;
+        buf.push(entry);
+        count += 1;
 
-                if buf.len() == buf_len {
-                    return;
-                }
-            } else {
-                return;
-            }
+        if buf.len() == buf_len {
+            break;
         }
-    });
-    error!(
+    }
+    drop(tx);
+    // the transaction can't be held when getting the attr
+    if is_readdir_plus {
+        for entry in buf.iter_mut() {
+            entry.attr = Some(dbfs_common_attr(entry.ino as usize)?);
+        }
+    }
+    trace!(
         "dbfs_common_readdir: offset: {}, count: {}, buf:{:?}",
         offset,
         count,
@@ -438,7 +616,7 @@ pub fn dbfs_common_readdir(
 }
 
 pub fn dbfs_common_open(ino: usize, uid: u32, gid: u32, access_mask: u16) -> Result<(), DbfsError> {
-    let attr = dbfs_common_attr(ino as usize).map_err(|_| DbfsError::NotFound)?;
+    let attr = dbfs_common_attr(ino as usize)?;
     let bool = checkout_access(attr.uid, attr.gid, attr.perm, uid, gid, access_mask);
     if bool {
         Ok(())
@@ -458,13 +636,11 @@ pub fn dbfs_common_copy_file_range(
     ctime: DbfsTimeSpec,
 ) -> DbfsResult<usize> {
     // now we ignore the uid and gid
-    let db = clone_db();
+    dbfs_common_cache_flush(src)?;
     let src_size = {
-        let tx = db.tx(false)?;
+        let tx = dbfs_tx(false)?;
         let bucket = tx.get_bucket(src.to_be_bytes())?;
-        let size = bucket.get_kv("size").unwrap();
-        let size = usize!(size.value());
-        size
+        get_usize(&bucket, src, "size")?
     };
     let read_size = min(src_size.saturating_sub(offset_src), len);
     let mut buf = vec![0; read_size];
@@ -474,8 +650,8 @@ pub fn dbfs_common_copy_file_range(
 
     // update dest ctime/mtime
     {
-        let tx = db.tx(true)?;
-        let bucket = tx.get_bucket(dest.to_be_bytes())?;
+        let tx = dbfs_tx(true)?;
+        let bucket = tx.check(|tx| Ok(tx.get_bucket(dest.to_be_bytes())?))?;
         bucket.put("ctime", ctime.to_be_bytes())?;
         bucket.put("mtime", ctime.to_be_bytes())?;
         tx.commit()?;
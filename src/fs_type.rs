@@ -1,10 +1,12 @@
 use alloc::{
     boxed::Box,
-    string::ToString,
+    string::{String, ToString},
     sync::{Arc, Weak},
     vec,
 };
 
+use jammdb::Bucket;
+use log::error;
 use rvfs::{
     ddebug,
     dentry::{DirEntry, DirEntryOps, DirFlags},
@@ -20,12 +22,16 @@ use rvfs::{
 use spin::Mutex;
 
 use crate::{
-    clone_db,
-    common::{generate_data_key, DbfsFsStat, DbfsResult, DbfsTimeSpec},
+    cache::dbfs_common_cache_flush_all,
+    common::{
+        generate_data_key, get_u32, get_u64, get_usize, DbfsError, DbfsFsStat, DbfsResult,
+        DbfsTimeSpec,
+    },
     file::DBFS_DIR_FILE_OPS,
     init_cache,
     inode::{permission_from_mode, DBFS_DIR_INODE_OPS, DBFS_INODE_NUMBER},
-    u32, u64, usize, SLICE_SIZE,
+    tx::{dbfs_common_group_commit, dbfs_tx},
+    SLICE_SIZE,
 };
 
 pub const DBFS: FileSystemType = FileSystemType {
@@ -45,14 +51,17 @@ const DBFS_SB_BLK_OPS: SuperBlockOps = {
 };
 
 fn dbfs_sync_fs(_sb_blk: Arc<SuperBlock>) -> StrResult<()> {
-    let db = clone_db();
-    let tx = db.tx(true).unwrap();
-    let bucket = tx.get_or_create_bucket("super_blk".as_bytes()).unwrap();
+    dbfs_common_cache_flush_all()?;
+    let tx = dbfs_tx(true)?;
+    let bucket = tx
+        .get_or_create_bucket("super_blk".as_bytes())
+        .map_err(DbfsError::from)?;
     let continue_number = DBFS_INODE_NUMBER.load(core::sync::atomic::Ordering::SeqCst);
     bucket
         .put("continue_number".as_bytes(), continue_number.to_be_bytes())
-        .unwrap();
-    tx.commit().map_err(|_| "dbfs_sync_fs error")?;
+        .map_err(DbfsError::from)?;
+    tx.commit()?;
+    dbfs_common_group_commit()?;
     Ok(())
 }
 
@@ -82,7 +91,9 @@ fn dbfs_get_super_blk(
 }
 
 fn dbfs_kill_super_blk(_super_blk: Arc<SuperBlock>) {
-    dbfs_common_umount().unwrap();
+    if let Err(e) = dbfs_common_umount() {
+        error!("dbfs_kill_super_blk: {:?}", e);
+    }
 }
 
 fn dbfs_create_simple_super_blk(
@@ -91,26 +102,14 @@ fn dbfs_create_simple_super_blk(
     dev_name: &str,
     data: Option<Box<dyn DataOps>>,
 ) -> StrResult<Arc<SuperBlock>> {
-    let db = clone_db();
-    let tx = db.tx(false);
-    if tx.is_err() {
-        return Err("dbfs_fill_super_block: get db tx failed");
-    }
-    let tx = tx.unwrap();
-    let bucket = tx.get_bucket("super_blk");
-    if bucket.is_err() {
-        return Err("dbfs_fill_super_block: get bucket failed");
-    }
-    let bucket = bucket.unwrap();
-    let continue_number = bucket.get_kv("continue_number").unwrap();
-    let continue_number = usize!(continue_number.value());
+    let tx = dbfs_tx(false)?;
+    let bucket = tx.get_bucket("super_blk").map_err(DbfsError::from)?;
+    let continue_number = get_usize(&bucket, 0, "continue_number")?;
     // set the next inode number
     DBFS_INODE_NUMBER.store(continue_number, core::sync::atomic::Ordering::SeqCst);
     init_cache();
-    let blk_size = bucket.get_kv("blk_size").unwrap();
-    let blk_size = u32!(blk_size.value());
-    let magic = bucket.get_kv("magic").unwrap();
-    let magic = u32!(magic.value());
+    let blk_size = get_u32(&bucket, 0, "blk_size")?;
+    let magic = get_u32(&bucket, 0, "magic")?;
     let sb_blk = SuperBlock {
         dev_desc: 0,
         device: None,
@@ -139,8 +138,7 @@ fn dbfs_fill_super_block(sb_blk: Arc<SuperBlock>) -> StrResult<()> {
 
 // create root inode for dbfs
 fn dbfs_create_root_inode(sb_blk: Arc<SuperBlock>) -> StrResult<Arc<Inode>> {
-    let count = dbfs_common_root_inode(0, 0, DbfsTimeSpec::default())
-        .map_err(|_| "create root inode failed")?;
+    let count = dbfs_common_root_inode(0, 0, DbfsTimeSpec::default())?;
 
     // let first_number = DBFS_INODE_NUMBER.load(core::sync::atomic::Ordering::SeqCst);
     // assert_eq!(first_number, 2);
@@ -162,38 +160,42 @@ fn dbfs_create_root_inode(sb_blk: Arc<SuperBlock>) -> StrResult<Arc<Inode>> {
 }
 
 pub fn dbfs_common_root_inode(uid: u32, gid: u32, ctime: DbfsTimeSpec) -> DbfsResult<usize> {
-    let db = clone_db();
-    let tx = db.tx(true)?;
+    let tx = dbfs_tx(true)?;
     if tx.get_bucket(1usize.to_be_bytes()).is_err() {
         // The root dir
         let permission = permission_from_mode(FileMode::FMODE_RDWR, InodeMode::S_DIR);
-        let new_inode = tx.create_bucket(1usize.to_be_bytes()).unwrap();
-        let old = DBFS_INODE_NUMBER.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
-        assert_eq!(old, 1);
-        new_inode
-            .put("mode", permission.bits().to_be_bytes())
-            .unwrap();
+        // the root inode must be the first inode of a new image
+        if DBFS_INODE_NUMBER
+            .compare_exchange(
+                1,
+                2,
+                core::sync::atomic::Ordering::SeqCst,
+                core::sync::atomic::Ordering::SeqCst,
+            )
+            .is_err()
+        {
+            return Err(DbfsError::corrupted(0, "continue_number"));
+        }
+        let new_inode = tx.create_bucket(1usize.to_be_bytes())?;
+        new_inode.put("mode", permission.bits().to_be_bytes())?;
         // set the size of inode to 0
         // new_inode.put("size", 0usize.to_be_bytes()).unwrap();
-        new_inode.put("hard_links", 2u32.to_be_bytes()).unwrap();
-        new_inode.put("uid", uid.to_be_bytes()).unwrap();
-        new_inode.put("gid", gid.to_be_bytes()).unwrap();
+        new_inode.put("hard_links", 2u32.to_be_bytes())?;
+        new_inode.put("uid", uid.to_be_bytes())?;
+        new_inode.put("gid", gid.to_be_bytes())?;
         // set time
-        new_inode.put("atime", ctime.to_be_bytes()).unwrap();
-        new_inode.put("mtime", ctime.to_be_bytes()).unwrap();
-        new_inode.put("ctime", ctime.to_be_bytes()).unwrap();
-        new_inode
-            .put("block_size", (SLICE_SIZE as u32).to_be_bytes())
-            .unwrap();
-        new_inode.put("size", 1usize.to_be_bytes()).unwrap();
+        new_inode.put("atime", ctime.to_be_bytes())?;
+        new_inode.put("mtime", ctime.to_be_bytes())?;
+        new_inode.put("ctime", ctime.to_be_bytes())?;
+        new_inode.put("block_size", (SLICE_SIZE as u32).to_be_bytes())?;
+        new_inode.put("size", 1usize.to_be_bytes())?;
 
         // insert dot  file
         let key = generate_data_key(".");
-        new_inode.put(key, "1").unwrap();
+        new_inode.put(key, "1")?;
     }
     let bucket = tx.get_bucket(1usize.to_be_bytes())?;
-    let count = bucket.get_kv("size").unwrap();
-    let count = usize!(count.value());
+    let count = get_usize(&bucket, 1, "size")?;
     tx.commit()?;
     Ok(count)
 }
@@ -216,10 +218,11 @@ fn dbfs_stat_fs(sb_blk: Arc<SuperBlock>) -> StrResult<StatFs> {
         Some(sb_blk.block_size as u64),
         Some(sb_blk.magic),
         Some(sb_blk.mount_flag.bits() as u64),
-    )
-    .map_err(|_| "statfs error")?;
-
-    Ok(stat.into())
+    )?;
+    let mut stat: StatFs = stat.into();
+    // f_fsid is made from the uuid, the type is the magic
+    stat.fs_type = sb_blk.magic;
+    Ok(stat)
 }
 
 pub fn dbfs_common_statfs(
@@ -227,17 +230,17 @@ pub fn dbfs_common_statfs(
     magic: Option<u32>,
     mount_flags: Option<u64>,
 ) -> DbfsResult<DbfsFsStat> {
-    let (disk_size, magic) = {
-        let db = clone_db();
-        let tx = db.tx(false)?;
+    let (disk_size, magic, uuid, label) = {
+        let tx = dbfs_tx(false)?;
         let bucket = tx.get_bucket("super_blk")?;
-        let disk_size = bucket.get_kv("disk_size").unwrap();
-        let disk_size = u64!(disk_size.value());
-        let magic = magic.unwrap_or_else(|| {
-            let magic = bucket.get_kv("magic").unwrap();
-            u32!(magic.value())
-        });
-        (disk_size, magic)
+        let disk_size = get_u64(&bucket, 0, "disk_size")?;
+        let magic = match magic {
+            Some(magic) => magic,
+            None => get_u32(&bucket, 0, "magic")?,
+        };
+        let uuid = get_uuid(&bucket);
+        let label = bucket.get_kv("label").map(|kv| kv.value().to_vec());
+        (disk_size, magic, uuid, label)
     };
 
     let blk_size = blk_size.unwrap_or(SLICE_SIZE as u64);
@@ -245,8 +248,22 @@ pub fn dbfs_common_statfs(
     // TODO! manage the disk_size
 
     let total_inodes = DBFS_INODE_NUMBER.load(core::sync::atomic::Ordering::SeqCst) as u64;
+    // the images made before the label and the uuid were added have neither
     let mut name = [0u8; 32];
-    name[..4].copy_from_slice(b"dbfs");
+    match label {
+        Some(label) if !label.is_empty() => {
+            let len = label.len().min(LABEL_MAX_LEN);
+            name[..len].copy_from_slice(&label[..len]);
+        }
+        _ => name[..4].copy_from_slice(b"dbfs"),
+    }
+    let fsid = match uuid {
+        Some(uuid) => {
+            u64::from_be_bytes(uuid[..8].try_into().unwrap())
+                ^ u64::from_be_bytes(uuid[8..].try_into().unwrap())
+        }
+        None => magic as u64,
+    };
     let mount_flag = mount_flags.unwrap_or(0);
 
     let stat = DbfsFsStat {
@@ -258,7 +275,7 @@ pub fn dbfs_common_statfs(
         f_files: total_inodes,
         f_ffree: 999,
         f_favail: 999,
-        f_fsid: magic as u64,
+        f_fsid: fsid,
         f_flag: mount_flag,
         f_namemax: 255,
         name,
@@ -266,13 +283,62 @@ pub fn dbfs_common_statfs(
     Ok(stat)
 }
 
+/// The max length of the label of an image, it is the `name` of [`DbfsFsStat`]
+pub const LABEL_MAX_LEN: usize = 32;
+
+fn get_uuid(bucket: &Bucket<'_, '_>) -> Option<[u8; 16]> {
+    bucket
+        .get_kv("uuid")
+        .and_then(|kv| kv.value().try_into().ok())
+}
+
+/// Read the label of the image, it is empty if the image has none
+pub fn dbfs_common_label() -> DbfsResult<String> {
+    let tx = dbfs_tx(false)?;
+    let bucket = tx.get_bucket("super_blk")?;
+    let label = bucket
+        .get_kv("label")
+        .map(|kv| String::from_utf8_lossy(kv.value()).to_string())
+        .unwrap_or_default();
+    Ok(label)
+}
+
+/// Change the label of the image
+pub fn dbfs_common_set_label(label: &str) -> DbfsResult<()> {
+    if label.len() > LABEL_MAX_LEN {
+        return Err(DbfsError::NameTooLong);
+    }
+    let tx = dbfs_tx(true)?;
+    let bucket = tx.get_bucket("super_blk")?;
+    bucket.put("label", label.as_bytes())?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Read the uuid of the image, the images made before it was added have none
+pub fn dbfs_common_uuid() -> DbfsResult<Option<[u8; 16]>> {
+    let tx = dbfs_tx(false)?;
+    let bucket = tx.get_bucket("super_blk")?;
+    Ok(get_uuid(&bucket))
+}
+
+/// Load the next inode number from the super block of the image
+pub fn dbfs_common_mount() -> DbfsResult<()> {
+    let tx = dbfs_tx(false)?;
+    let bucket = tx.get_bucket("super_blk")?;
+    let continue_number = get_usize(&bucket, 0, "continue_number")?;
+    DBFS_INODE_NUMBER.store(continue_number, core::sync::atomic::Ordering::SeqCst);
+    Ok(())
+}
+
 pub fn dbfs_common_umount() -> DbfsResult<()> {
-    let db = clone_db();
-    let tx = db.tx(true)?;
+    dbfs_common_cache_flush_all()?;
+    let tx = dbfs_tx(true)?;
     let bucket = tx.get_bucket("super_blk")?;
     // write back continue_number
     let c_number = DBFS_INODE_NUMBER.load(core::sync::atomic::Ordering::SeqCst);
     bucket.put("continue_number", c_number.to_be_bytes())?;
     tx.commit()?;
+    dbfs_common_group_commit()?;
     Ok(())
 }
@@ -1,17 +1,89 @@
+use alloc::string::ToString;
+
 use downcast::_std::time::SystemTime;
 use fuser::{FileAttr, Request, TimeOrNow};
 use log::warn;
+use spin::Once;
 
 use crate::{
     attr::{
         dbfs_common_chmod, dbfs_common_chown, dbfs_common_getxattr, dbfs_common_listxattr,
         dbfs_common_removexattr, dbfs_common_setxattr, dbfs_common_utimens,
     },
-    common::{DbfsAttr, DbfsFsStat, DbfsResult, DbfsTimeSpec},
+    common::{DbfsAttr, DbfsError, DbfsFsStat, DbfsResult, DbfsTimeSpec},
     fs_type::dbfs_common_statfs,
+    fuse::mkfs::{dbfs_fuse_image_size, dbfs_fuse_resize, image_capacity},
     inode::{dbfs_common_access, dbfs_common_attr},
 };
 
+/// Map the owner of the image to a local user, (image id, local id)
+#[derive(Debug, Default, Clone, Copy)]
+struct IdMap {
+    uid: Option<(u32, u32)>,
+    gid: Option<(u32, u32)>,
+}
+
+static ID_MAP: Once<IdMap> = Once::new();
+
+/// Show the files owned by `uid.0`/`gid.0` in the image as owned by `uid.1`/`gid.1`,
+/// like the uid=/gid= mount options. The two ids are swapped so the mapping stays
+/// one to one.
+///
+/// The credentials of the requests and the owners given to chown are mapped to the ids
+/// in the image, so the mapped user owns the files it is shown as owning. Root can't
+/// be mapped since that would turn a user into root.
+pub fn set_id_map(uid: Option<(u32, u32)>, gid: Option<(u32, u32)>) -> DbfsResult<()> {
+    let maps_root = |map: Option<(u32, u32)>| matches!(map, Some((0, _)) | Some((_, 0)));
+    if maps_root(uid) || maps_root(gid) {
+        return Err(DbfsError::InvalidArgument);
+    }
+    ID_MAP.call_once(|| IdMap { uid, gid });
+    Ok(())
+}
+
+fn map_id(id: u32, map: Option<(u32, u32)>) -> u32 {
+    match map {
+        Some((image, local)) if id == image => local,
+        Some((image, local)) if id == local => image,
+        _ => id,
+    }
+}
+
+/// The owner shown to the kernel
+pub(crate) fn local_uid(uid: u32) -> u32 {
+    map_id(uid, ID_MAP.get().and_then(|map| map.uid))
+}
+
+pub(crate) fn local_gid(gid: u32) -> u32 {
+    map_id(gid, ID_MAP.get().and_then(|map| map.gid))
+}
+
+/// The owner stored in the image
+pub(crate) fn image_uid(uid: u32) -> u32 {
+    map_id(uid, ID_MAP.get().and_then(|map| map.uid))
+}
+
+pub(crate) fn image_gid(gid: u32) -> u32 {
+    map_id(gid, ID_MAP.get().and_then(|map| map.gid))
+}
+
+/// The credentials of the request as the ids in the image, the permissions are checked with them
+pub(crate) fn request_ids(req: &Request<'_>) -> (u32, u32) {
+    (image_uid(req.uid()), image_gid(req.gid()))
+}
+
+/// The xattr of the root directory to read and change the capacity of a mounted
+/// image, in bytes
+///
+/// ```shell
+/// setfattr -n trusted.dbfs.capacity -v 8589934592 /mnt/data
+/// ```
+pub const CAPACITY_XATTR: &str = "trusted.dbfs.capacity";
+
+fn is_capacity_xattr(ino: u64, name: &str) -> bool {
+    ino == 1 && name == CAPACITY_XATTR
+}
+
 pub fn dbfs_fuse_getattr(ino: u64) -> DbfsResult<FileAttr> {
     warn!("dbfs_fuse_getattr(ino:{})", ino);
     dbfs_common_attr(ino as usize).map(|x| x.into())
@@ -19,12 +91,19 @@ pub fn dbfs_fuse_getattr(ino: u64) -> DbfsResult<FileAttr> {
 
 pub fn dbfs_fuse_statfs() -> DbfsResult<DbfsFsStat> {
     warn!("dbfs_fuse_statfs)");
-    dbfs_common_statfs(None, None, None)
+    let mut stat = dbfs_common_statfs(None, None, None)?;
+    // the image grows on demand, the free space is what it can still grow
+    let used = dbfs_fuse_image_size()? as u64 / stat.f_bsize;
+    stat.f_blocks = image_capacity() as u64 / stat.f_bsize;
+    stat.f_bfree = stat.f_blocks.saturating_sub(used);
+    stat.f_bavail = stat.f_bfree;
+    Ok(stat)
 }
 
 pub fn dbfs_fuse_access(req: &Request<'_>, ino: u64, mask: i32) -> DbfsResult<bool> {
     warn!("dbfs_fuse_access(ino:{})", ino);
-    dbfs_common_access(req.uid(), req.gid(), ino as usize, mask)
+    let (uid, gid) = request_ids(req);
+    dbfs_common_access(uid, gid, ino as usize, mask)
 }
 
 pub fn dbfs_fuse_setxattr(
@@ -39,8 +118,19 @@ pub fn dbfs_fuse_setxattr(
         "dbfs_fuse_setxattr(ino:{},name:{:?},value:{:?})",
         ino, name, value
     );
+    if is_capacity_xattr(ino, name) {
+        if req.uid() != 0 {
+            return Err(DbfsError::PermissionDenied);
+        }
+        let capacity = core::str::from_utf8(value)
+            .ok()
+            .and_then(|x| x.trim().parse::<usize>().ok())
+            .ok_or(DbfsError::InvalidArgument)?;
+        return dbfs_fuse_resize(capacity);
+    }
     let time = DbfsTimeSpec::from(SystemTime::now());
-    dbfs_common_setxattr(req.uid(), req.gid(), ino as usize, name, value, time)
+    let (uid, gid) = request_ids(req);
+    dbfs_common_setxattr(uid, gid, ino as usize, name, value, time)
 }
 
 pub fn dbfs_fuse_getxattr(
@@ -50,18 +140,32 @@ pub fn dbfs_fuse_getxattr(
     buf: &mut [u8],
 ) -> DbfsResult<usize> {
     warn!("dbfs_fuse_getxattr(ino:{},name:{:?})", ino, name);
-    dbfs_common_getxattr(req.uid(), req.gid(), ino as usize, name, buf)
+    if is_capacity_xattr(ino, name) {
+        let value = image_capacity().to_string();
+        if buf.is_empty() {
+            return Ok(value.len());
+        }
+        if buf.len() < value.len() {
+            return Err(DbfsError::RangeError);
+        }
+        buf[..value.len()].copy_from_slice(value.as_bytes());
+        return Ok(value.len());
+    }
+    let (uid, gid) = request_ids(req);
+    dbfs_common_getxattr(uid, gid, ino as usize, name, buf)
 }
 
 pub fn dbfs_fuse_listxattr(req: &Request<'_>, ino: u64, buf: &mut [u8]) -> DbfsResult<usize> {
     warn!("dbfs_fuse_listxattr(ino:{})", ino);
-    dbfs_common_listxattr(req.uid(), req.gid(), ino as usize, buf)
+    let (uid, gid) = request_ids(req);
+    dbfs_common_listxattr(uid, gid, ino as usize, buf)
 }
 
 pub fn dbfs_fuse_removexattr(req: &Request<'_>, ino: u64, name: &str) -> DbfsResult<()> {
     warn!("dbfs_fuse_removexattr(ino:{},name:{:?})", ino, name);
     let time = DbfsTimeSpec::from(SystemTime::now());
-    dbfs_common_removexattr(req.uid(), req.gid(), ino as usize, name, time)
+    let (uid, gid) = request_ids(req);
+    dbfs_common_removexattr(uid, gid, ino as usize, name, time)
 }
 
 /// Change the permission bits of a file
@@ -70,7 +174,8 @@ pub fn dbfs_fuse_removexattr(req: &Request<'_>, ino: u64, name: &str) -> DbfsRes
 pub fn dbfs_fuse_chmod(req: &Request<'_>, ino: u64, mode: u32) -> DbfsResult<DbfsAttr> {
     warn!("dbfs_fuse_chmod(ino:{},mode:{})", ino, mode);
     let time = DbfsTimeSpec::from(SystemTime::now());
-    dbfs_common_chmod(req.uid(), req.gid(), ino as usize, mode as u16, time)
+    let (uid, gid) = request_ids(req);
+    dbfs_common_chmod(uid, gid, ino as usize, mode as u16, time)
 }
 
 ///
@@ -82,7 +187,15 @@ pub fn dbfs_fuse_chown(
 ) -> DbfsResult<DbfsAttr> {
     warn!("dbfs_fuse_chown(ino:{},uid:{:?},gid:{:?})", ino, uid, gid);
     let time = DbfsTimeSpec::from(SystemTime::now());
-    dbfs_common_chown(req.uid(), req.gid(), ino as usize, uid, gid, time)
+    let (r_uid, r_gid) = request_ids(req);
+    dbfs_common_chown(
+        r_uid,
+        r_gid,
+        ino as usize,
+        uid.map(image_uid),
+        gid.map(image_gid),
+        time,
+    )
 }
 
 pub fn dbfs_fuse_utimens(
@@ -109,5 +222,6 @@ pub fn dbfs_fuse_utimens(
         "dbfs_fuse_utimens(ino:{},atime:{:?},mtime:{:?},ctime:{:?})",
         ino, atime, mtime, ctime
     );
-    dbfs_common_utimens(req.uid(), req.gid(), ino as usize, atime, mtime, ctime)
+    let (uid, gid) = request_ids(req);
+    dbfs_common_utimens(uid, gid, ino as usize, atime, mtime, ctime)
 }
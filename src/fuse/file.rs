@@ -1,24 +1,27 @@
 use alloc::vec;
-use std::{cmp::min, io::IoSlice, println};
+use std::{alloc::Layout, time::Duration};
 
 use downcast::_std::time::SystemTime;
 use fuser::{ReplyData, ReplyDirectory, ReplyDirectoryPlus, Request};
 use log::error;
 use rvfs::warn;
-use smallvec::{smallvec, SmallVec};
 
 use crate::{
-    clone_db,
+    cache::{cached_size, dbfs_common_cache_flush},
     common::{
-        generate_data_key_with_number, pop_readdir_table, push_readdir_table, DbfsDirEntry,
-        DbfsError, DbfsResult, DbfsTimeSpec, ReadDirInfo, FMODE_EXEC,
+        pop_readdir_table, push_readdir_table, DbfsDirEntry, DbfsError, DbfsResult, DbfsTimeSpec,
+        ReadDirInfo, FMODE_EXEC,
     },
     file::{
-        dbfs_common_copy_file_range, dbfs_common_open, dbfs_common_read, dbfs_common_readdir,
-        dbfs_common_write,
+        dbfs_common_copy_file_range, dbfs_common_lseek, dbfs_common_open, dbfs_common_read,
+        dbfs_common_read_parts, dbfs_common_readdir, dbfs_common_write,
     },
-    fuse::TTL,
-    usize, SLICE_SIZE,
+    fuse::{
+        attr::request_ids,
+        mkfs::{dbfs_fuse_sync_image, durability, Durability},
+    },
+    tx::dbfs_common_group_commit,
+    BUDDY_ALLOCATOR,
 };
 
 pub fn dbfs_fuse_read(ino: u64, offset: i64, buf: &mut [u8]) -> DbfsResult<usize> {
@@ -26,110 +29,137 @@ pub fn dbfs_fuse_read(ino: u64, offset: i64, buf: &mut [u8]) -> DbfsResult<usize
     dbfs_common_read(ino as usize, buf, offset as u64)
 }
 
-//
-pub fn dbfs_fuse_special_read(
-    ino: usize,
-    old_offset: i64,
-    need_size: usize,
-    _repl: ReplyData,
-) -> DbfsResult<usize> {
-    assert!(old_offset >= 0);
-    let offset = old_offset as u64;
-    let db = clone_db();
-    let tx = db.tx(false)?;
-    let bucket = tx.get_bucket(ino.to_be_bytes())?;
-    let size = bucket.get_kv("size").unwrap();
-    let size = usize!(size.value());
-    if offset >= size as u64 {
-        return Ok(0);
+/// Run `f` on a buffer of the buddy allocator, the heap is used when it runs out
+fn with_buffer<R>(size: usize, f: impl FnOnce(&mut [u8]) -> R) -> R {
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    let ptr = BUDDY_ALLOCATOR.lock().alloc(layout);
+    let mut heap = vec![];
+    let buf = match ptr {
+        Ok(ptr) => unsafe { std::slice::from_raw_parts_mut(ptr.as_ptr(), size) },
+        Err(_) => {
+            warn!(
+                "the buddy allocator is used up, use {} bytes of the heap",
+                size
+            );
+            heap.resize(size, 0u8);
+            heap.as_mut_slice()
+        }
+    };
+    let res = f(buf);
+    if let Ok(ptr) = ptr {
+        BUDDY_ALLOCATOR.lock().dealloc(ptr, layout);
     }
-    let mut res_slice: SmallVec<[IoSlice<'_>; 1024 * 1024 / SLICE_SIZE]> = smallvec![];
-
-    let tmp = [0u8; SLICE_SIZE];
-    let mut start_num = offset / SLICE_SIZE as u64;
-    let mut offset = offset % SLICE_SIZE as u64;
-
-    let old_start = start_num;
-    let mut count = 0;
-    loop {
-        let key = generate_data_key_with_number(start_num as u32);
-        let value = bucket.get_kv(key);
-        let real_size = min(size - start_num as usize * SLICE_SIZE, SLICE_SIZE);
-        if value.is_none() {
-            // copy tmp buf to buf
-            let len = min(need_size - count, real_size.saturating_sub(offset as usize));
-            let ptr = tmp.as_ptr();
-            let data = unsafe { std::slice::from_raw_parts(ptr, SLICE_SIZE) };
-            res_slice.push(IoSlice::new(&data[offset as usize..offset as usize + len]));
-
-            count += len;
-            offset = (offset + len as u64) % SLICE_SIZE as u64;
-        } else {
-            let value = value.unwrap();
-            let value = value.value();
-            let len = min(need_size - count, real_size.saturating_sub(offset as usize));
-            let ptr = value.as_ptr();
-            let data = unsafe { std::slice::from_raw_parts(ptr, SLICE_SIZE) };
-            res_slice.push(IoSlice::new(&data[offset as usize..offset as usize + len]));
+    res
+}
 
-            count += len;
-            offset = (offset + len as u64) % SLICE_SIZE as u64;
-        }
-        if count == size || count == need_size {
-            break;
-        }
-        start_num += 1;
+/// Reply the data of a clean file from the values of the db.
+///
+/// Only a read within one slice is zero-copy. fuser at this revision has no vectored
+/// `ReplyData`, so a read spanning several slices, e.g. a 128KB read over 32KB
+/// slices, is gathered into one buffer until the dependency is moved to a fuser
+/// which can reply with the parts. A file with dirty data is read with a copy.
+pub fn dbfs_fuse_read_reply(ino: u64, offset: i64, size: u32, repl: ReplyData) {
+    assert!(offset >= 0);
+    // the dirty data is merged with the committed one by the copying read
+    if cached_size(ino as usize).is_some() {
+        with_buffer(size as usize, |buf| {
+            match dbfs_fuse_read(ino, offset, buf) {
+                Ok(x) => repl.data(&buf[..x]),
+                Err(x) => repl.error(x.errno()),
+            }
+        });
+        return;
     }
-    error!("IoSlice len :{}", res_slice.len());
-    if count != need_size {
-        for _i in 0..(need_size - count) / SLICE_SIZE {
-            let ptr = tmp.as_ptr();
-            let data = unsafe { std::slice::from_raw_parts(ptr, SLICE_SIZE) };
-            res_slice.push(IoSlice::new(data));
+    let mut repl = Some(repl);
+    let res = dbfs_common_read_parts(ino as usize, offset as u64, size as usize, |parts| {
+        let repl = repl.take().unwrap();
+        match parts {
+            [] => repl.data(&[]),
+            [part] => repl.data(part),
+            _ => {
+                let len = parts.iter().map(|x| x.len()).sum();
+                with_buffer(len, |buf| {
+                    let mut count = 0;
+                    for part in parts {
+                        buf[count..count + part.len()].copy_from_slice(part);
+                        count += part.len();
+                    }
+                    repl.data(buf)
+                })
+            }
         }
-        let len = (need_size - count) % SLICE_SIZE;
-        let ptr = tmp.as_ptr();
-        let data = unsafe { std::slice::from_raw_parts(ptr, SLICE_SIZE) };
-        res_slice.push(IoSlice::new(&data[..len]));
+    });
+    if let (Err(x), Some(repl)) = (res, repl) {
+        repl.error(x.errno());
     }
-
-    let total = res_slice.iter().fold(0, |acc, x| acc + x.len());
-    println!(
-        "read_num:{},offset:{},count:{},need:{},total:{}",
-        start_num - old_start,
-        old_offset,
-        count,
-        need_size,
-        total
-    );
-
-    // repl.data2(&res_slice);
-    Ok(count)
 }
 
-pub fn dbfs_fuse_write(ino: u64, offset: i64, buf: &[u8]) -> DbfsResult<usize> {
+pub fn dbfs_fuse_write(ino: u64, offset: i64, buf: &[u8], flags: i32) -> DbfsResult<usize> {
     assert!(offset >= 0);
     let res = dbfs_common_write(ino as usize, buf, offset as u64);
     error!("dbfs write res:{:?}", res);
-    res
+    let count = res?;
+    // O_SYNC contains the bits of O_DSYNC
+    if flags & libc::O_DSYNC != 0 {
+        dbfs_fuse_fsync(ino, flags & libc::O_SYNC != libc::O_SYNC)?;
+    }
+    Ok(count)
 }
 
-pub fn dbfs_fuse_releasedir(ino: u64) -> DbfsResult<()> {
-    pop_readdir_table(ino as usize);
+/// The kernel only sends SEEK_DATA and SEEK_HOLE, it handles the others itself
+pub fn dbfs_fuse_lseek(ino: u64, offset: i64, whence: i32) -> DbfsResult<i64> {
+    warn!(
+        "dbfs_fuse_lseek(ino:{},offset:{},whence:{})",
+        ino, offset, whence
+    );
+    if offset < 0 {
+        return Err(DbfsError::NoAddress);
+    }
+    dbfs_common_lseek(ino as usize, offset as u64, whence).map(|x| x as i64)
+}
+
+/// Commit the data of the file, and sync the image if the durability needs
+pub fn dbfs_fuse_fsync(ino: u64, datasync: bool) -> DbfsResult<()> {
+    warn!("dbfs_fuse_fsync(ino:{},datasync:{})", ino, datasync);
+    dbfs_common_cache_flush(ino as usize)?;
+    dbfs_fuse_fsyncdir(ino, datasync)
+}
+
+/// Commit the operations on the directory, and sync the image if the durability needs
+pub fn dbfs_fuse_fsyncdir(ino: u64, datasync: bool) -> DbfsResult<()> {
+    warn!("dbfs_fuse_fsyncdir(ino:{},datasync:{})", ino, datasync);
+    dbfs_common_group_commit()?;
+    // the image has been synced by the commit in every-commit mode
+    if durability() == Durability::OnFsync {
+        dbfs_fuse_sync_image(datasync)?;
+    }
+    Ok(())
+}
+
+pub fn dbfs_fuse_releasedir(ino: u64, fh: u64) -> DbfsResult<()> {
+    pop_readdir_table(ino as usize, fh as usize);
     Ok(())
 }
 
-pub fn dbfs_fuse_readdir(ino: u64, mut offset: i64, mut repl: ReplyDirectory) {
-    warn!("dbfs_fuse_readdir(ino:{},offset:{})", ino, offset);
+pub fn dbfs_fuse_readdir(ino: u64, fh: u64, mut offset: i64, mut repl: ReplyDirectory) {
+    warn!("dbfs_fuse_readdir(ino:{},fh:{},offset:{})", ino, fh, offset);
     assert!(offset >= 0);
     let mut entries = vec![DbfsDirEntry::default(); 16]; // we read 16 entries at a time
     loop {
-        let res = dbfs_common_readdir(ino as usize, &mut entries, offset as u64, false);
-        if res.is_err() {
-            repl.error(libc::ENOENT);
-            return;
-        }
-        let res = res.unwrap();
+        let res = dbfs_common_readdir(
+            ino as usize,
+            fh as usize,
+            &mut entries,
+            offset as u64,
+            false,
+        );
+        let res = match res {
+            Ok(res) => res,
+            Err(x) => {
+                repl.error(x.errno());
+                return;
+            }
+        };
         if res == 0 {
             repl.ok();
             return;
@@ -142,6 +172,7 @@ pub fn dbfs_fuse_readdir(ino: u64, mut offset: i64, mut repl: ReplyDirectory) {
                 // TODO! update GLOBAL_READDIR_TABLE
                 push_readdir_table(
                     ino as usize,
+                    fh as usize,
                     ReadDirInfo::new(x.offset as usize, x.name.clone()),
                 );
                 warn!(
@@ -155,22 +186,31 @@ pub fn dbfs_fuse_readdir(ino: u64, mut offset: i64, mut repl: ReplyDirectory) {
         let x = &entries[res - 1];
         push_readdir_table(
             ino as usize,
+            fh as usize,
             ReadDirInfo::new(x.offset as usize, x.name.clone()),
         );
     }
 }
 
-pub fn dbfs_fuse_readdirplus(ino: u64, mut offset: i64, mut repl: ReplyDirectoryPlus) {
+pub fn dbfs_fuse_readdirplus(
+    ino: u64,
+    fh: u64,
+    mut offset: i64,
+    ttl: &Duration,
+    mut repl: ReplyDirectoryPlus,
+) {
     // panic!("dbfs_fuse_readdirplus(ino:{},offset:{})",ino,offset);
     assert!(offset >= 0);
     let mut entries = vec![DbfsDirEntry::default(); 16]; // we read 16 entries at a time
     loop {
-        let res = dbfs_common_readdir(ino as usize, &mut entries, offset as u64, true);
-        if res.is_err() {
-            repl.error(libc::ENOENT);
-            return;
-        }
-        let res = res.unwrap();
+        let res = dbfs_common_readdir(ino as usize, fh as usize, &mut entries, offset as u64, true);
+        let res = match res {
+            Ok(res) => res,
+            Err(x) => {
+                repl.error(x.errno());
+                return;
+            }
+        };
         if res == 0 {
             repl.ok();
             return;
@@ -182,7 +222,7 @@ pub fn dbfs_fuse_readdirplus(ino: u64, mut offset: i64, mut repl: ReplyDirectory
                 x.ino,
                 x.offset as i64 + 1,
                 x.name.as_str(),
-                &TTL,
+                ttl,
                 &attr.into(),
                 0,
             ) {
@@ -192,6 +232,7 @@ pub fn dbfs_fuse_readdirplus(ino: u64, mut offset: i64, mut repl: ReplyDirectory
                 // TODO! update GLOBAL_READDIR_TABLE
                 push_readdir_table(
                     ino as usize,
+                    fh as usize,
                     ReadDirInfo::new(x.offset as usize, x.name.clone()),
                 );
                 return;
@@ -201,6 +242,7 @@ pub fn dbfs_fuse_readdirplus(ino: u64, mut offset: i64, mut repl: ReplyDirectory
         let x = &entries[res - 1];
         push_readdir_table(
             ino as usize,
+            fh as usize,
             ReadDirInfo::new(x.offset as usize, x.name.clone()),
         );
     }
@@ -230,8 +272,8 @@ pub fn dbfs_fuse_open(req: &Request<'_>, ino: u64, flags: i32) -> Result<(), i32
     };
 
     // checkout the permission
-    dbfs_common_open(ino as usize, req.uid(), req.gid(), access_mask as u16)
-        .map_err(|x| x as i32)?;
+    let (uid, gid) = request_ids(req);
+    dbfs_common_open(ino as usize, uid, gid, access_mask as u16).map_err(|x| x.errno())?;
 
     Ok(())
 }
@@ -253,7 +295,8 @@ pub fn dbfs_fuse_opendir(req: &Request<'_>, ino: u64, flags: i32) -> DbfsResult<
     };
 
     // checkout the permission
-    dbfs_common_open(ino as usize, req.uid(), req.gid(), access_mask as u16)
+    let (uid, gid) = request_ids(req);
+    dbfs_common_open(ino as usize, uid, gid, access_mask as u16)
 }
 
 pub fn dbfs_fuse_copy_file_range(
@@ -269,8 +312,7 @@ pub fn dbfs_fuse_copy_file_range(
         ino_in, offset_in, ino_out, offset_out, len
     );
     let time = DbfsTimeSpec::from(SystemTime::now());
-    let uid = req.uid();
-    let gid = req.gid();
+    let (uid, gid) = request_ids(req);
     dbfs_common_copy_file_range(
         uid,
         gid,
@@ -0,0 +1,97 @@
+//! The table of the files opened through FUSE, the `fh` of the requests is the key of it.
+use alloc::collections::BTreeMap;
+
+use crate::common::{DbfsError, DbfsResult};
+
+/// The state of an open file
+#[derive(Debug, Clone)]
+pub struct DbfsFileHandle {
+    pub ino: usize,
+    /// The flags passed to open/create
+    pub flags: i32,
+    /// The credential of the process which opened the file, in the ids of the image
+    pub uid: u32,
+    pub gid: u32,
+    pub read: bool,
+    pub write: bool,
+    /// Every write goes to the end of the file
+    pub append: bool,
+}
+
+impl DbfsFileHandle {
+    pub fn new(ino: usize, flags: i32, uid: u32, gid: u32) -> Self {
+        let (read, write) = match flags & libc::O_ACCMODE {
+            libc::O_WRONLY => (false, true),
+            libc::O_RDWR => (true, true),
+            _ => (true, false),
+        };
+        Self {
+            ino,
+            flags,
+            uid,
+            gid,
+            read,
+            write,
+            append: flags & libc::O_APPEND != 0,
+        }
+    }
+}
+
+/// The open files of a [`super::DbfsFuse`], 0 is never used as a handle
+#[derive(Debug)]
+pub struct DbfsHandleTable {
+    next: u64,
+    handles: BTreeMap<u64, DbfsFileHandle>,
+}
+
+impl Default for DbfsHandleTable {
+    fn default() -> Self {
+        Self {
+            next: 1,
+            handles: BTreeMap::new(),
+        }
+    }
+}
+
+impl DbfsHandleTable {
+    /// Add the handle and return the fh of it
+    pub fn insert(&mut self, handle: DbfsFileHandle) -> u64 {
+        let fh = self.next;
+        self.next += 1;
+        self.handles.insert(fh, handle);
+        fh
+    }
+
+    pub fn remove(&mut self, fh: u64) -> Option<DbfsFileHandle> {
+        self.handles.remove(&fh)
+    }
+
+    /// Get the handle of `ino`, the handles of other files are rejected too
+    pub fn get(&self, fh: u64, ino: u64) -> DbfsResult<&DbfsFileHandle> {
+        match self.handles.get(&fh) {
+            Some(handle) if handle.ino == ino as usize => Ok(handle),
+            _ => Err(DbfsError::BadFd),
+        }
+    }
+
+    /// Get the handle if it was opened for reading
+    pub fn readable(&self, fh: u64, ino: u64) -> DbfsResult<&DbfsFileHandle> {
+        match self.get(fh, ino)? {
+            handle if handle.read => Ok(handle),
+            _ => Err(DbfsError::BadFd),
+        }
+    }
+
+    /// Get the handle if it was opened for writing
+    pub fn writable(&self, fh: u64, ino: u64) -> DbfsResult<&DbfsFileHandle> {
+        match self.get(fh, ino)? {
+            handle if handle.write => Ok(handle),
+            _ => Err(DbfsError::BadFd),
+        }
+    }
+
+    /// The number of the handles open on `ino`
+    pub fn open_count(&self, ino: usize) -> usize {
+        self.handles.values().filter(|x| x.ino == ino).count()
+    }
+}
@@ -4,6 +4,7 @@ use rvfs::warn;
 
 use crate::{
     common::{DbfsAttr, DbfsError, DbfsPermission, DbfsResult, DbfsTimeSpec, MAX_PATH_LEN},
+    fuse::attr::request_ids,
     inode::{
         dbfs_common_create, dbfs_common_fallocate, dbfs_common_lookup, dbfs_common_rename,
         dbfs_common_rmdir, dbfs_common_truncate,
@@ -42,8 +43,7 @@ pub fn dbfs_fuse_create(
     };
 
     let permission = DbfsPermission::from_bits_truncate(mode as u16);
-    let uid = req.uid();
-    let gid = req.gid();
+    let (uid, gid) = request_ids(req);
     let ctime = DbfsTimeSpec::from(SystemTime::now());
     let res = dbfs_common_create(
         parent as usize,
@@ -66,15 +66,14 @@ pub fn dbfs_fuse_mkdir(
     parent: u64,
     name: &str,
     mode: u32,
-) -> Result<FileAttr, ()> {
+) -> DbfsResult<FileAttr> {
     warn!(
         "dbfs_fuse_mkdir(parent:{},name:{},mode:{})",
         parent, name, mode
     );
     let mut permission = DbfsPermission::from_bits_truncate(mode as u16);
     permission |= DbfsPermission::S_IFDIR;
-    let uid = req.uid();
-    let gid = req.gid();
+    let (uid, gid) = request_ids(req);
     let ctime = DbfsTimeSpec::from(SystemTime::now());
     let res = dbfs_common_create(
         parent as usize,
@@ -86,24 +85,19 @@ pub fn dbfs_fuse_mkdir(
         None,
         None,
     );
-    if res.is_err() {
-        return Err(());
-    }
-    Ok(res.unwrap().into())
+    res.map(|attr| attr.into())
 }
 
 pub fn dbfs_fuse_truncate(req: &Request<'_>, ino: u64, size: u64) -> DbfsResult<DbfsAttr> {
     warn!("dbfs_fuse_truncate(ino:{},size:{})", ino, size);
-    let uid = req.uid();
-    let gid = req.gid();
+    let (uid, gid) = request_ids(req);
     let ctime = DbfsTimeSpec::from(SystemTime::now());
     dbfs_common_truncate(uid, gid, ino as usize, ctime, size as usize)
 }
 
 pub fn dbfs_fuse_rmdir(req: &Request<'_>, parent: u64, name: &str) -> DbfsResult<()> {
     warn!("dbfs_fuse_rmdir(parent:{},name:{})", parent, name);
-    let uid = req.uid();
-    let gid = req.gid();
+    let (uid, gid) = request_ids(req);
     let ctime = DbfsTimeSpec::from(SystemTime::now());
     dbfs_common_rmdir(uid, gid, parent as usize, name, ctime)
 }
@@ -119,8 +113,7 @@ pub fn dbfs_fuse_fallocate(
         "dbfs_fuse_fallocate(ino:{},offset:{},size:{},mode:{})",
         ino, offset, size, mode
     );
-    let uid = req.uid();
-    let gid = req.gid();
+    let (uid, gid) = request_ids(req);
     let ctime = DbfsTimeSpec::from(SystemTime::now());
     dbfs_common_fallocate(
         uid,
@@ -145,8 +138,7 @@ pub fn dbfs_fuse_rename(
         "dbfs_fuse_rename(parent:{},name:{},newparent:{},newname:{})",
         parent, name, newparent, newname
     );
-    let uid = req.uid();
-    let gid = req.gid();
+    let (uid, gid) = request_ids(req);
     let ctime = DbfsTimeSpec::from(SystemTime::now());
     dbfs_common_rename(
         uid,
@@ -178,8 +170,7 @@ pub fn dbfs_fuse_mknod(
     //         return Err(DbfsError::NoSys);
     //     }
     println!("permission:{:?}", permission);
-    let uid = req.uid();
-    let gid = req.gid();
+    let (uid, gid) = request_ids(req);
     let ctime = DbfsTimeSpec::from(SystemTime::now());
     dbfs_common_create(
         parent as usize,
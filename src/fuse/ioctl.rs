@@ -0,0 +1,168 @@
+//! The ioctl commands of the files opened through FUSE.
+//!
+//! The kernel only passes the ioctls whose size is encoded in the command, so the data
+//! is at most the size of the struct of the command. `chattr` and `lsattr` come here
+//! through FS_IOC_GETFLAGS and FS_IOC_SETFLAGS.
+//!
+//! Linux answers FS_IOC_FIEMAP itself through `i_op->fiemap`, which FUSE doesn't
+//! have, so `filefrag` doesn't work on a mount. The extents are read with
+//! [`dbfs_fuse_fiemap`] or `dbfs-tool map` instead.
+use alloc::{vec, vec::Vec};
+use core::mem::size_of;
+
+use downcast::_std::time::SystemTime;
+use fuser::Request;
+use log::warn;
+
+use crate::{
+    attr::dbfs_common_set_flags,
+    common::{DbfsError, DbfsExtent, DbfsResult, DbfsTimeSpec},
+    file::dbfs_common_fiemap,
+    fuse::attr::request_ids,
+    inode::dbfs_common_attr,
+};
+
+/// _IOR('f', 1, long) and _IOW('f', 2, long), the kernel sends them with an int
+pub const FS_IOC_GETFLAGS: u32 = 0x8008_6601;
+pub const FS_IOC_SETFLAGS: u32 = 0x4008_6602;
+/// The same commands of 32-bit processes
+pub const FS_IOC32_GETFLAGS: u32 = 0x8004_6601;
+pub const FS_IOC32_SETFLAGS: u32 = 0x4004_6602;
+/// _IOWR('f', 11, struct fiemap)
+pub const FS_IOC_FIEMAP: u32 = 0xC020_660B;
+
+const FIEMAP_FLAG_SYNC: u32 = 0x1;
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+/// There is no physical address for a slice
+const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
+
+/// struct fiemap without the extents following it
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct Fiemap {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+}
+
+/// struct fiemap_extent
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct FiemapExtent {
+    fe_logical: u64,
+    fe_physical: u64,
+    fe_length: u64,
+    fe_reserved64: [u64; 2],
+    fe_flags: u32,
+    fe_reserved: [u32; 3],
+}
+
+fn read_struct<T: Copy + Default>(data: &[u8]) -> DbfsResult<T> {
+    if data.len() < size_of::<T>() {
+        return Err(DbfsError::InvalidArgument);
+    }
+    let mut value = T::default();
+    // Safety: T is a repr(C) struct of integers
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            data.as_ptr(),
+            &mut value as *mut T as *mut u8,
+            size_of::<T>(),
+        );
+    }
+    Ok(value)
+}
+
+fn write_struct<T: Copy>(buf: &mut Vec<u8>, value: &T) {
+    // Safety: T is a repr(C) struct of integers without padding
+    let bytes =
+        unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+    buf.extend_from_slice(bytes);
+}
+
+/// Get the extents of the file in `start..start + len`
+pub fn dbfs_fuse_fiemap(ino: u64, start: u64, len: u64) -> DbfsResult<Vec<DbfsExtent>> {
+    dbfs_common_fiemap(ino as usize, start, len)
+}
+
+/// FIEMAP with `fm_extent_count` 0 only counts the extents. The data of an ioctl
+/// is at most the struct of its command, so a call asking for extents fails with
+/// ERANGE instead of reporting none.
+fn fiemap(ino: u64, in_data: &[u8], out_size: u32) -> DbfsResult<Vec<u8>> {
+    let mut header: Fiemap = read_struct(in_data)?;
+    if header.fm_flags & !FIEMAP_FLAG_SYNC != 0 {
+        return Err(DbfsError::NotSupported);
+    }
+    // the dirty data is always flushed, so FIEMAP_FLAG_SYNC is the default
+    let extents = dbfs_fuse_fiemap(ino, header.fm_start, header.fm_length)?;
+    let room = (out_size as usize).saturating_sub(size_of::<Fiemap>()) / size_of::<FiemapExtent>();
+    let count = extents.len().min(header.fm_extent_count as usize);
+    if count > room {
+        return Err(DbfsError::RangeError);
+    }
+    header.fm_mapped_extents = match header.fm_extent_count {
+        0 => extents.len() as u32,
+        _ => count as u32,
+    };
+    let mut buf = vec![];
+    write_struct(&mut buf, &header);
+    for extent in &extents[..count] {
+        let mut fe_flags = FIEMAP_EXTENT_UNKNOWN;
+        if extent.last {
+            fe_flags |= FIEMAP_EXTENT_LAST;
+        }
+        let extent = FiemapExtent {
+            fe_logical: extent.logical,
+            fe_length: extent.length,
+            fe_flags,
+            ..Default::default()
+        };
+        write_struct(&mut buf, &extent);
+    }
+    Ok(buf)
+}
+
+/// The flags are an int, the rest of `out_size` is zero
+fn get_flags(ino: u64, out_size: u32) -> DbfsResult<Vec<u8>> {
+    let flags = dbfs_common_attr(ino as usize)?.flags;
+    let mut buf = vec![0u8; (out_size as usize).max(size_of::<u32>())];
+    buf[..size_of::<u32>()].copy_from_slice(&flags.to_ne_bytes());
+    buf.truncate(out_size as usize);
+    Ok(buf)
+}
+
+fn set_flags(req: &Request<'_>, ino: u64, in_data: &[u8]) -> DbfsResult<Vec<u8>> {
+    let flags = in_data
+        .get(..size_of::<u32>())
+        .ok_or(DbfsError::InvalidArgument)?;
+    let flags = u32::from_ne_bytes(flags.try_into().unwrap());
+    let (uid, gid) = request_ids(req);
+    dbfs_common_set_flags(
+        uid,
+        gid,
+        ino as usize,
+        flags,
+        DbfsTimeSpec::from(SystemTime::now()),
+    )?;
+    Ok(vec![])
+}
+
+/// Run the ioctl and return the data copied back to the caller
+pub fn dbfs_fuse_ioctl(
+    req: &Request<'_>,
+    ino: u64,
+    cmd: u32,
+    in_data: &[u8],
+    out_size: u32,
+) -> DbfsResult<Vec<u8>> {
+    warn!("dbfs_fuse_ioctl(ino:{},cmd:{:#x})", ino, cmd);
+    match cmd {
+        FS_IOC_GETFLAGS | FS_IOC32_GETFLAGS => get_flags(ino, out_size),
+        FS_IOC_SETFLAGS | FS_IOC32_SETFLAGS => set_flags(req, ino, in_data),
+        FS_IOC_FIEMAP => fiemap(ino, in_data, out_size),
+        _ => Err(DbfsError::NoTty),
+    }
+}
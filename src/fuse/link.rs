@@ -4,6 +4,7 @@ use log::{error, warn};
 
 use crate::{
     common::{DbfsAttr, DbfsError, DbfsPermission, DbfsResult, DbfsTimeSpec, MAX_PATH_LEN},
+    fuse::attr::request_ids,
     inode::{dbfs_common_create, dbfs_common_link},
     link::{dbfs_common_readlink, dbfs_common_unlink},
 };
@@ -26,14 +27,8 @@ pub fn dbfs_fuse_link(
         return Err(DbfsError::NameTooLong);
     }
     let time = DbfsTimeSpec::from(SystemTime::now());
-    dbfs_common_link(
-        req.uid(),
-        req.gid(),
-        ino as usize,
-        newparent as usize,
-        newname,
-        time,
-    )
+    let (uid, gid) = request_ids(req);
+    dbfs_common_link(uid, gid, ino as usize, newparent as usize, newname, time)
 }
 
 pub fn dbfs_fuse_symlink(
@@ -56,11 +51,12 @@ pub fn dbfs_fuse_symlink(
     let time = DbfsTimeSpec::from(SystemTime::now());
     let mut permission = DbfsPermission::from_bits_truncate(0o777);
     permission |= DbfsPermission::S_IFLNK;
+    let (uid, gid) = request_ids(req);
     let attr = dbfs_common_create(
         parent as usize,
         name,
-        req.uid(),
-        req.gid(),
+        uid,
+        gid,
         time,
         permission,
         Some(link),
@@ -79,5 +75,6 @@ pub fn dbfs_fuse_readlink(ino: u64) -> DbfsResult<[u8; MAX_PATH_LEN]> {
 pub fn dbfs_fuse_unlink(req: &Request<'_>, parent: u64, name: &str) -> DbfsResult<()> {
     error!("dbfs_fuse_unlink(parent:{}, name:{:?})", parent, name);
     let time = DbfsTimeSpec::from(SystemTime::now());
-    dbfs_common_unlink(req.uid(), req.gid(), parent as usize, name, None, time)
+    let (uid, gid) = request_ids(req);
+    dbfs_common_unlink(uid, gid, parent as usize, name, None, time)
 }
@@ -0,0 +1,424 @@
+//! The POSIX byte-range locks of the files opened through FUSE.
+//!
+//! flock is kept by the kernel. fuser at this revision drops FUSE_LK_FLOCK, so a
+//! flock would come as a POSIX lock of the whole file and conflict with the POSIX
+//! locks, which are independent of it.
+//! The locks only live in memory, they are gone when the image is unmounted.
+//!
+//! fuser answers FUSE_INTERRUPT with ENOSYS, so the kernel stops sending them and
+//! a process waiting in F_SETLKW only leaves on a fatal signal. [`signal_pending`]
+//! lets the caller find the waiters which got a signal and answer them with EINTR.
+use alloc::{collections::BTreeMap, vec::Vec};
+use std::sync::{Mutex, MutexGuard};
+
+use fuser::{consts::FUSE_POSIX_LOCKS, ReplyEmpty};
+use libc::c_int;
+use log::warn;
+
+use crate::common::{DbfsError, DbfsResult};
+
+/// The locks the kernel sends to the table, flock isn't one of them
+pub const DBFS_LOCK_CAPABILITIES: u32 = FUSE_POSIX_LOCKS;
+
+/// A lock held by `owner` on the bytes `start..=end`, `typ` is F_RDLCK or F_WRLCK
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DbfsLock {
+    pub owner: u64,
+    pub start: u64,
+    pub end: u64,
+    pub typ: i32,
+    pub pid: u32,
+}
+
+impl DbfsLock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    fn conflicts(&self, other: &DbfsLock) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other.start, other.end)
+            && (self.typ == libc::F_WRLCK || other.typ == libc::F_WRLCK)
+    }
+}
+
+/// The reply of a setlk which waits
+pub trait DbfsLockReply {
+    fn ok(self);
+    fn error(self, errno: c_int);
+}
+
+impl DbfsLockReply for ReplyEmpty {
+    fn ok(self) {
+        ReplyEmpty::ok(self)
+    }
+
+    fn error(self, errno: c_int) {
+        ReplyEmpty::error(self, errno)
+    }
+}
+
+/// A setlk which sleeps until the conflicting locks are released,
+/// `pid` is the thread which sent it
+struct Waiter<R> {
+    ino: u64,
+    lock: DbfsLock,
+    pid: u32,
+    reply: R,
+}
+
+pub struct DbfsLockTable<R = ReplyEmpty> {
+    locks: BTreeMap<u64, Vec<DbfsLock>>,
+    waiters: Vec<Waiter<R>>,
+}
+
+impl<R> Default for DbfsLockTable<R> {
+    fn default() -> Self {
+        Self {
+            locks: BTreeMap::new(),
+            waiters: Vec::new(),
+        }
+    }
+}
+
+impl<R: DbfsLockReply> DbfsLockTable<R> {
+    /// Get the first lock which conflicts with `lock`, or `lock` as F_UNLCK if there is none
+    pub fn getlk(&self, ino: u64, lock: DbfsLock) -> DbfsLock {
+        self.locks
+            .get(&ino)
+            .and_then(|locks| locks.iter().find(|x| x.conflicts(&lock)))
+            .copied()
+            .unwrap_or(DbfsLock {
+                typ: libc::F_UNLCK,
+                ..lock
+            })
+    }
+
+    /// Take or release the lock without waiting
+    pub fn setlk(&mut self, ino: u64, lock: DbfsLock) -> DbfsResult<()> {
+        match lock.typ {
+            libc::F_UNLCK => {
+                self.unlock(ino, lock.owner, lock.start, lock.end);
+                self.wake();
+                Ok(())
+            }
+            libc::F_RDLCK | libc::F_WRLCK => {
+                if self.getlk(ino, lock).typ != libc::F_UNLCK {
+                    return Err(DbfsError::WouldBlock);
+                }
+                // the new lock replaces the range of the old ones of the owner
+                self.unlock(ino, lock.owner, lock.start, lock.end);
+                self.locks.entry(ino).or_default().push(lock);
+                // a write lock may become a read lock
+                self.wake();
+                Ok(())
+            }
+            _ => Err(DbfsError::InvalidArgument),
+        }
+    }
+
+    /// Take the lock, the reply is sent when the conflicting locks are released
+    pub fn setlk_wait(&mut self, ino: u64, lock: DbfsLock, pid: u32, reply: R) {
+        match self.setlk(ino, lock) {
+            Ok(_) => reply.ok(),
+            Err(DbfsError::WouldBlock) => {
+                warn!("setlk(ino:{},owner:{}) waits", ino, lock.owner);
+                self.waiters.push(Waiter {
+                    ino,
+                    lock,
+                    pid,
+                    reply,
+                });
+            }
+            Err(x) => reply.error(x.errno()),
+        }
+    }
+
+    /// Release all the locks of the owner on the file, when it is closed,
+    /// its waiting setlk is answered with EINTR
+    pub fn release_owner(&mut self, ino: u64, owner: u64) {
+        self.cancel(|waiter| waiter.ino == ino && waiter.lock.owner == owner);
+        self.unlock(ino, owner, 0, u64::MAX);
+        self.wake();
+    }
+
+    /// Answer the waiting setlk of the threads which are interrupted with EINTR
+    pub fn interrupt<F: Fn(u32) -> bool>(&mut self, interrupted: F) {
+        self.cancel(|waiter| interrupted(waiter.pid));
+    }
+
+    pub fn has_waiters(&self) -> bool {
+        !self.waiters.is_empty()
+    }
+
+    fn cancel<F: Fn(&Waiter<R>) -> bool>(&mut self, f: F) {
+        let mut index = 0;
+        while index < self.waiters.len() {
+            if f(&self.waiters[index]) {
+                let waiter = self.waiters.remove(index);
+                warn!(
+                    "setlk(ino:{},owner:{}) is interrupted",
+                    waiter.ino, waiter.lock.owner
+                );
+                waiter.reply.error(libc::EINTR);
+            } else {
+                index += 1;
+            }
+        }
+    }
+
+    fn unlock(&mut self, ino: u64, owner: u64, start: u64, end: u64) {
+        let locks = match self.locks.get_mut(&ino) {
+            Some(locks) => locks,
+            None => return,
+        };
+        let mut kept = Vec::with_capacity(locks.len());
+        for lock in locks.drain(..) {
+            if lock.owner != owner || !lock.overlaps(start, end) {
+                kept.push(lock);
+                continue;
+            }
+            // keep the parts out of the range
+            if lock.start < start {
+                kept.push(DbfsLock {
+                    end: start - 1,
+                    ..lock
+                });
+            }
+            if lock.end > end {
+                kept.push(DbfsLock {
+                    start: end + 1,
+                    ..lock
+                });
+            }
+        }
+        if kept.is_empty() {
+            self.locks.remove(&ino);
+        } else {
+            *locks = kept;
+        }
+    }
+
+    /// Retry the waiting locks in the order they came
+    fn wake(&mut self) {
+        let mut index = 0;
+        while index < self.waiters.len() {
+            let waiter = &self.waiters[index];
+            if self.getlk(waiter.ino, waiter.lock).typ != libc::F_UNLCK {
+                index += 1;
+                continue;
+            }
+            let waiter = self.waiters.remove(index);
+            self.unlock(
+                waiter.ino,
+                waiter.lock.owner,
+                waiter.lock.start,
+                waiter.lock.end,
+            );
+            self.locks.entry(waiter.ino).or_default().push(waiter.lock);
+            waiter.reply.ok();
+        }
+    }
+}
+
+/// Lock the table, it is still usable after a panic since every change is done at once
+pub fn lock_table(table: &Mutex<DbfsLockTable>) -> MutexGuard<'_, DbfsLockTable> {
+    table.lock().unwrap_or_else(|x| x.into_inner())
+}
+
+/// The thread has a signal to handle, or has exited
+#[cfg(target_os = "linux")]
+pub fn signal_pending(pid: u32) -> bool {
+    let status = match std::fs::read_to_string(alloc::format!("/proc/{}/status", pid)) {
+        Ok(status) => status,
+        Err(_) => return true,
+    };
+    let mask = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|x| u64::from_str_radix(x.trim(), 16).ok())
+            .unwrap_or(0)
+    };
+    (mask("SigPnd:") | mask("ShdPnd:")) & !mask("SigBlk:") != 0
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn signal_pending(_pid: u32) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{rc::Rc, vec, vec::Vec};
+    use core::cell::RefCell;
+
+    use super::*;
+
+    /// The replies sent, (id, errno), 0 is ok
+    type Sent = Rc<RefCell<Vec<(u64, c_int)>>>;
+
+    struct TestReply(u64, Sent);
+
+    impl DbfsLockReply for TestReply {
+        fn ok(self) {
+            self.1.borrow_mut().push((self.0, 0));
+        }
+
+        fn error(self, errno: c_int) {
+            self.1.borrow_mut().push((self.0, errno));
+        }
+    }
+
+    fn lock(owner: u64, start: u64, end: u64, typ: i32) -> DbfsLock {
+        DbfsLock {
+            owner,
+            start,
+            end,
+            typ,
+            pid: owner as u32,
+        }
+    }
+
+    fn locks(table: &DbfsLockTable<TestReply>, ino: u64) -> Vec<(u64, u64, u64, i32)> {
+        let mut locks = table.locks.get(&ino).cloned().unwrap_or_default();
+        locks.sort_by_key(|x| (x.owner, x.start));
+        locks
+            .iter()
+            .map(|x| (x.owner, x.start, x.end, x.typ))
+            .collect()
+    }
+
+    #[test]
+    fn flock_is_kept_by_the_kernel() {
+        use fuser::consts::FUSE_FLOCK_LOCKS;
+        assert_eq!(DBFS_LOCK_CAPABILITIES & FUSE_FLOCK_LOCKS, 0);
+        // a POSIX lock of the whole file, the same a flock would come as
+        let mut table = DbfsLockTable::<TestReply>::default();
+        table
+            .setlk(1, lock(1, 0, i64::MAX as u64, libc::F_WRLCK))
+            .unwrap();
+        assert!(matches!(
+            table.setlk(1, lock(2, 0, i64::MAX as u64, libc::F_WRLCK)),
+            Err(DbfsError::WouldBlock)
+        ));
+    }
+
+    #[test]
+    fn unlock_splits_the_lock() {
+        let mut table = DbfsLockTable::<TestReply>::default();
+        table.setlk(1, lock(1, 0, 99, libc::F_WRLCK)).unwrap();
+        table.setlk(1, lock(1, 40, 59, libc::F_UNLCK)).unwrap();
+        assert_eq!(
+            locks(&table, 1),
+            vec![(1, 0, 39, libc::F_WRLCK), (1, 60, 99, libc::F_WRLCK)]
+        );
+        // a lock in the middle of another type splits it too
+        table.setlk(1, lock(1, 10, 19, libc::F_RDLCK)).unwrap();
+        assert_eq!(
+            locks(&table, 1),
+            vec![
+                (1, 0, 9, libc::F_WRLCK),
+                (1, 10, 19, libc::F_RDLCK),
+                (1, 20, 39, libc::F_WRLCK),
+                (1, 60, 99, libc::F_WRLCK)
+            ]
+        );
+        table.release_owner(1, 1);
+        assert!(locks(&table, 1).is_empty());
+    }
+
+    #[test]
+    fn upgrade_waits_for_the_other_readers() {
+        let mut table = DbfsLockTable::<TestReply>::default();
+        table.setlk(1, lock(1, 0, 9, libc::F_RDLCK)).unwrap();
+        table.setlk(1, lock(1, 0, 9, libc::F_WRLCK)).unwrap();
+        assert_eq!(locks(&table, 1), vec![(1, 0, 9, libc::F_WRLCK)]);
+        // a write lock becomes a read lock, then another reader blocks the upgrade
+        table.setlk(1, lock(1, 0, 9, libc::F_RDLCK)).unwrap();
+        table.setlk(1, lock(2, 5, 5, libc::F_RDLCK)).unwrap();
+        assert!(matches!(
+            table.setlk(1, lock(1, 0, 9, libc::F_WRLCK)),
+            Err(DbfsError::WouldBlock)
+        ));
+        assert_eq!(
+            locks(&table, 1),
+            vec![(1, 0, 9, libc::F_RDLCK), (2, 5, 5, libc::F_RDLCK)]
+        );
+    }
+
+    #[test]
+    fn conflicts() {
+        let mut table = DbfsLockTable::<TestReply>::default();
+        table.setlk(1, lock(1, 0, 9, libc::F_RDLCK)).unwrap();
+        table.setlk(1, lock(2, 0, 9, libc::F_RDLCK)).unwrap();
+        assert!(matches!(
+            table.setlk(1, lock(3, 5, 5, libc::F_WRLCK)),
+            Err(DbfsError::WouldBlock)
+        ));
+        assert_eq!(table.getlk(1, lock(3, 5, 5, libc::F_WRLCK)).owner, 1);
+        // the ranges around and the other files are free
+        assert_eq!(
+            table.getlk(1, lock(3, 10, 20, libc::F_WRLCK)).typ,
+            libc::F_UNLCK
+        );
+        table.setlk(1, lock(3, 10, 20, libc::F_WRLCK)).unwrap();
+        table.setlk(2, lock(3, 0, 9, libc::F_WRLCK)).unwrap();
+        assert!(matches!(
+            table.setlk(1, lock(1, 0, 9, 42)),
+            Err(DbfsError::InvalidArgument)
+        ));
+    }
+
+    #[test]
+    fn waiters_wake_in_order() {
+        let sent = Sent::default();
+        let mut table = DbfsLockTable::default();
+        table.setlk(1, lock(1, 0, 9, libc::F_WRLCK)).unwrap();
+        table.setlk_wait(
+            1,
+            lock(2, 0, 9, libc::F_WRLCK),
+            2,
+            TestReply(2, sent.clone()),
+        );
+        table.setlk_wait(
+            1,
+            lock(3, 0, 9, libc::F_WRLCK),
+            3,
+            TestReply(3, sent.clone()),
+        );
+        assert!(sent.borrow().is_empty());
+        table.setlk(1, lock(1, 0, 9, libc::F_UNLCK)).unwrap();
+        assert_eq!(*sent.borrow(), vec![(2, 0)]);
+        assert_eq!(locks(&table, 1), vec![(2, 0, 9, libc::F_WRLCK)]);
+        table.release_owner(1, 2);
+        assert_eq!(*sent.borrow(), vec![(2, 0), (3, 0)]);
+        assert!(!table.has_waiters());
+    }
+
+    #[test]
+    fn released_and_interrupted_waiters_get_eintr() {
+        let sent = Sent::default();
+        let mut table = DbfsLockTable::default();
+        table.setlk(1, lock(1, 0, 9, libc::F_WRLCK)).unwrap();
+        table.setlk_wait(
+            1,
+            lock(2, 0, 9, libc::F_WRLCK),
+            2,
+            TestReply(2, sent.clone()),
+        );
+        table.setlk_wait(
+            1,
+            lock(3, 0, 9, libc::F_WRLCK),
+            3,
+            TestReply(3, sent.clone()),
+        );
+        table.release_owner(1, 2);
+        table.interrupt(|pid| pid == 3);
+        assert_eq!(*sent.borrow(), vec![(2, libc::EINTR), (3, libc::EINTR)]);
+        // the lock isn't given to the owners which left
+        table.release_owner(1, 1);
+        assert!(locks(&table, 1).is_empty());
+        assert_eq!(sent.borrow().len(), 2);
+    }
+}
@@ -6,7 +6,12 @@ use alloc::{
     string::{String, ToString},
     sync::Arc,
 };
-use core::fmt::Display;
+use core::{
+    cmp::max,
+    fmt::Display,
+    str::FromStr,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+};
 use std::{fs::OpenOptions, path::Path};
 
 use downcast::_std::{
@@ -18,25 +23,64 @@ use jammdb::{
     Bucket, Data, DbFile, File, FileExt, IOResult, IndexByPageID, MemoryMap, MetaData, OpenOption,
     PathLike, DB,
 };
+use log::{error, info};
 use rvfs::warn;
-use spin::Once;
+use spin::Mutex;
+
+use crate::{
+    clone_db,
+    common::{get_u64, DbfsError, DbfsResult, DbfsTimeSpec},
+    fs_type::{dbfs_common_mount, dbfs_common_root_inode, dbfs_common_umount},
+    init_dbfs,
+    tx::{dbfs_common_group_commit, dbfs_tx},
+    usize, SLICE_SIZE,
+};
+
+/// The max size of the image, it grows up to it as the db needs pages.
+/// 0 means the `S` of [`MyOpenOptions`]
+static IMAGE_CAPACITY: AtomicUsize = AtomicUsize::new(0);
 
-use crate::{common::DbfsTimeSpec, fs_type::dbfs_common_root_inode, init_dbfs, usize, SLICE_SIZE};
+/// Set the max size of the image, it overrides the `S` of [`MyOpenOptions`]
+pub fn set_image_capacity(capacity: usize) {
+    IMAGE_CAPACITY.store(capacity, Ordering::SeqCst);
+}
+
+pub fn image_capacity() -> usize {
+    IMAGE_CAPACITY.load(Ordering::SeqCst)
+}
+
+fn check_capacity(end: u64) -> IOResult<()> {
+    let capacity = IMAGE_CAPACITY.load(Ordering::SeqCst);
+    if capacity != 0 && end > capacity as u64 {
+        warn!("the image is full, capacity: {}, need: {}", capacity, end);
+        return Err(core2::io::Error::new(
+            core2::io::ErrorKind::Other,
+            "no space",
+        ));
+    }
+    Ok(())
+}
+
+/// Open the image without writing to it
+static IMAGE_READ_ONLY: AtomicBool = AtomicBool::new(false);
 
 pub struct MyOpenOptions<const S: usize> {
     read: bool,
     write: bool,
     create: bool,
-    #[allow(unused)]
     size: usize,
 }
 impl<const S: usize> OpenOption for MyOpenOptions<S> {
     fn new() -> Self {
+        let size = match IMAGE_CAPACITY.load(Ordering::SeqCst) {
+            0 => S,
+            capacity => capacity,
+        };
         MyOpenOptions {
             read: false,
             write: false,
             create: false,
-            size: S,
+            size,
         }
     }
 
@@ -51,17 +95,19 @@ impl<const S: usize> OpenOption for MyOpenOptions<S> {
     }
 
     fn open<T: ToString + PathLike>(&mut self, path: &T) -> IOResult<File> {
+        let read_only = IMAGE_READ_ONLY.load(Ordering::SeqCst);
         let file = OpenOptions::new()
             .read(self.read)
-            .write(self.write)
-            .create(self.create)
+            .write(self.write && !read_only)
+            .create(self.create && !read_only)
             .open(path.to_string())
-            .unwrap();
-        file.set_len(S as u64).unwrap();
-        println!(
-            "file size is {}GB",
-            file.metadata().unwrap().len() / 1024 / 1024 / 1024
-        );
+            .map_err(|_x| core2::io::Error::new(core2::io::ErrorKind::Other, "open error"))?;
+        // the image grows on demand, an existing one is never truncated
+        if IMAGE_CAPACITY.load(Ordering::SeqCst) == 0 {
+            set_image_capacity(self.size);
+        }
+        let capacity = IMAGE_CAPACITY.load(Ordering::SeqCst);
+        info!("image capacity is {}MB", capacity / 1024 / 1024);
         Ok(File::new(Box::new(FakeFile::new(file))))
     }
 
@@ -109,17 +155,20 @@ impl core2::io::Read for FakeFile {
 }
 impl core2::io::Write for FakeFile {
     fn write(&mut self, buf: &[u8]) -> core2::io::Result<usize> {
+        let pos = self
+            .file
+            .stream_position()
+            .map_err(|_x| core2::io::Error::new(core2::io::ErrorKind::Other, "seek error"))?;
+        check_capacity(pos + buf.len() as u64)?;
         self.file
             .write(buf)
             .map_err(|_x| core2::io::Error::new(core2::io::ErrorKind::Other, "write error"))
     }
 
-    /// TODO: The first place for update
     fn flush(&mut self) -> core2::io::Result<()> {
-        // self.file
-        //     .flush()
-        //     .map_err(|_x| core2::io::Error::new(core2::io::ErrorKind::Other, "flush error"))
-        Ok(())
+        self.file
+            .flush()
+            .map_err(|_x| core2::io::Error::new(core2::io::ErrorKind::Other, "flush error"))
     }
 }
 
@@ -132,6 +181,7 @@ impl FileExt for FakeFile {
         if self.size > new_size as usize {
             return Ok(());
         } else {
+            check_capacity(new_size)?;
             // panic!("Don't need allocate, the new size is {}MB, old size is {}",new_size/1024/1024,self.size/1024/1024);
             let res = self
                 .file
@@ -157,12 +207,14 @@ impl FileExt for FakeFile {
         Ok(meta)
     }
 
-    /// TODO: The first place for update
+    /// It is called when the db commits, so the image is only synced in [`Durability::EveryCommit`]
     fn sync_all(&self) -> IOResult<()> {
-        // self.file
-        //     .sync_all()
-        //     .map_err(|_x| core2::io::Error::new(core2::io::ErrorKind::Other, "sync_all error"))
-        Ok(())
+        if durability() != Durability::EveryCommit {
+            return Ok(());
+        }
+        self.file
+            .sync_all()
+            .map_err(|_x| core2::io::Error::new(core2::io::ErrorKind::Other, "sync_all error"))
     }
 
     /// no meaning
@@ -178,6 +230,67 @@ impl FileExt for FakeFile {
 
 impl DbFile for FakeFile {}
 
+/// When the data written to the image reaches the disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Leave it to the host, the data may be lost on power loss
+    None,
+    /// Sync the image on fsync, fsyncdir and O_SYNC/O_DSYNC writes
+    OnFsync,
+    /// Sync the image on every commit of the db
+    EveryCommit,
+}
+
+impl FromStr for Durability {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Durability::None),
+            "on-fsync" => Ok(Durability::OnFsync),
+            "every-commit" => Ok(Durability::EveryCommit),
+            _ => Err(format!(
+                "unknown durability {}, expected none, on-fsync or every-commit",
+                s
+            )),
+        }
+    }
+}
+
+static DURABILITY: AtomicU8 = AtomicU8::new(Durability::OnFsync as u8);
+
+/// Set the durability of the mount, the default is [`Durability::OnFsync`]
+pub fn set_durability(durability: Durability) {
+    DURABILITY.store(durability as u8, Ordering::SeqCst);
+}
+
+pub fn durability() -> Durability {
+    match DURABILITY.load(Ordering::SeqCst) {
+        0 => Durability::None,
+        1 => Durability::OnFsync,
+        _ => Durability::EveryCommit,
+    }
+}
+
+/// Write the image back to the disk
+///
+/// The pages are written through the file, the mmap is read only so there
+/// is nothing to flush in it.
+pub fn dbfs_fuse_sync_image(datasync: bool) -> DbfsResult<()> {
+    let db = clone_db();
+    let mut file = db.file();
+    let file = &mut file.file;
+    let fake_file = file
+        .downcast_mut::<FakeFile>()
+        .map_err(|_| DbfsError::NotSupported)?;
+    fake_file.file.flush().map_err(|_| DbfsError::Io)?;
+    let res = if datasync {
+        fake_file.file.sync_data()
+    } else {
+        fake_file.file.sync_all()
+    };
+    res.map_err(|_| DbfsError::Io)
+}
+
 #[derive(Debug, Clone)]
 pub struct FakePath {
     path: std::path::PathBuf,
@@ -213,33 +326,45 @@ struct IndexByPageIDImpl {
     map: memmap2::Mmap,
 }
 
-static MMAP: Once<Arc<IndexByPageIDImpl>> = Once::new();
+/// The map covers the capacity of the image, so it is only remapped when the
+/// image is resized beyond it
+static MMAP: Mutex<Option<Arc<IndexByPageIDImpl>>> = Mutex::new(None);
 
 impl MemoryMap for FakeMMap {
     fn do_map(&self, file: &mut File) -> IOResult<Arc<dyn IndexByPageID>> {
-        if !MMAP.is_completed() {
-            let file = &file.file;
-            let fake_file = file.downcast_ref::<FakeFile>().unwrap();
-            let res = mmap(&fake_file.file, false);
-            if res.is_err() {
-                warn!("mmap res: {:?}", res);
-                return Err(core2::io::Error::new(
-                    core2::io::ErrorKind::Other,
-                    "not support",
-                ));
-            }
-            let map = res.unwrap();
-            let map = Arc::new(IndexByPageIDImpl { map });
-            MMAP.call_once(|| map);
+        let file = &file.file;
+        let fake_file = file.downcast_ref::<FakeFile>().unwrap();
+        let len = fake_file
+            .file
+            .metadata()
+            .map_err(|_x| core2::io::Error::new(core2::io::ErrorKind::Other, "metadata error"))?
+            .len() as usize;
+        let mut map = MMAP.lock();
+        match map.as_ref() {
+            Some(map) if map.map.len() >= len => return Ok(map.clone()),
+            _ => {}
         }
-        Ok(MMAP.get().unwrap().clone())
+        let res = mmap(&fake_file.file, false);
+        if res.is_err() {
+            warn!("mmap res: {:?}", res);
+            return Err(core2::io::Error::new(
+                core2::io::ErrorKind::Other,
+                "not support",
+            ));
+        }
+        // the transactions using the old map keep it alive
+        let new = Arc::new(IndexByPageIDImpl { map: res.unwrap() });
+        *map = Some(new.clone());
+        Ok(new)
     }
 }
 
 /// populate
 fn mmap(file: &std::fs::File, populate: bool) -> Result<memmap2::Mmap, ()> {
     use memmap2::MmapOptions;
-    let size = file.metadata().unwrap().len() as usize;
+    let len = file.metadata().unwrap().len() as usize;
+    // map the pages the image can grow to, the ones after the end are not touched
+    let size = max(len, IMAGE_CAPACITY.load(Ordering::SeqCst));
     let mut options = MmapOptions::new();
     if populate {
         options.populate();
@@ -287,30 +412,222 @@ pub fn init_dbfs_fuse<T: AsRef<Path>>(path: T, size: u64) {
     let path = path.as_ref().to_str().unwrap();
     let path = FakePath::new(path);
     let db = DB::open::<MyOpenOptions<FILE_SIZE>, _>(Arc::new(FakeMMap), path).unwrap();
-    init_db(&db, size);
+    init_db(&db, size).unwrap();
     // test_dbfs(&db);
     init_dbfs(db);
+    dbfs_common_mount().unwrap();
+    dbfs_fuse_load_capacity().unwrap();
     let uid = unsafe { libc::getuid() };
     let gid = unsafe { libc::getgid() };
     let time = DbfsTimeSpec::from(SystemTime::now());
     dbfs_common_root_inode(uid, gid, time).unwrap();
 }
 
-pub fn init_db(db: &DB, size: u64) {
-    let tx = db.tx(true).unwrap();
+/// Open an existing image without making it the global db, for the tools
+/// inspecting the image
+pub fn dbfs_fuse_open_image<T: AsRef<Path>>(path: T, read_only: bool) -> DbfsResult<DB> {
+    use super::FILE_SIZE;
+    let path = path.as_ref();
+    if !path.exists() {
+        return Err(DbfsError::NotFound);
+    }
+    let name = path.to_str().ok_or(DbfsError::InvalidArgument)?;
+    // the real capacity is loaded from the super block later
+    let len = std::fs::metadata(path).map_err(|_| DbfsError::Io)?.len() as usize;
+    match read_only {
+        true => set_image_capacity(len),
+        false => set_image_capacity(max(len, FILE_SIZE)),
+    }
+    IMAGE_READ_ONLY.store(read_only, Ordering::SeqCst);
+    let db = DB::open::<MyOpenOptions<FILE_SIZE>, _>(Arc::new(FakeMMap), FakePath::new(name));
+    IMAGE_READ_ONLY.store(false, Ordering::SeqCst);
+    Ok(db?)
+}
+
+/// Use the capacity recorded in the super block of the global db.
+///
+/// `disk_size` is the space left for the files, it is the capacity of the images made
+/// before `capacity` was recorded.
+pub fn dbfs_fuse_load_capacity() -> DbfsResult<()> {
+    let tx = dbfs_tx(false)?;
+    let bucket = tx.get_bucket("super_blk")?;
+    let capacity = match bucket.get_kv("capacity") {
+        Some(_) => get_u64(&bucket, 0, "capacity")?,
+        None => get_u64(&bucket, 0, "disk_size")?,
+    };
+    set_image_capacity(capacity as usize);
+    Ok(())
+}
+
+/// The size of the image file, the pages the db has used
+pub fn dbfs_fuse_image_size() -> DbfsResult<usize> {
+    let db = clone_db();
+    let file = db.file();
+    let fake_file = file
+        .file
+        .downcast_ref::<FakeFile>()
+        .map_err(|_| DbfsError::NotSupported)?;
+    let meta = fake_file.file.metadata().map_err(|_| DbfsError::Io)?;
+    Ok(meta.len() as usize)
+}
+
+/// Change the capacity of the image, a mounted one is resized through
+/// [`super::attr::CAPACITY_XATTR`].
+///
+/// Shrinking below the pages the db has used is refused. `disk_size` changes with
+/// the capacity, the space of the files is kept.
+pub fn dbfs_fuse_resize(capacity: usize) -> DbfsResult<()> {
+    let used = dbfs_fuse_image_size()?;
+    if capacity < used {
+        error!("can't resize the image to {}, {} is used", capacity, used);
+        return Err(DbfsError::NoSpace);
+    }
+    let tx = dbfs_tx(true)?;
+    let bucket = tx.get_bucket("super_blk")?;
+    let disk_size = get_u64(&bucket, 0, "disk_size")?;
+    let old = match bucket.get_kv("capacity") {
+        Some(_) => get_u64(&bucket, 0, "capacity")?,
+        None => disk_size,
+    };
+    let capacity = capacity as u64;
+    let disk_size = match capacity >= old {
+        true => disk_size + (capacity - old),
+        false => disk_size.saturating_sub(old - capacity),
+    };
+    bucket.put("capacity", capacity.to_be_bytes())?;
+    bucket.put("disk_size", disk_size.to_be_bytes())?;
+    tx.commit()?;
+    dbfs_common_group_commit()?;
+    set_image_capacity(capacity as usize);
+    Ok(())
+}
+
+/// Write the super block of a new image, an image which has one is left alone
+pub fn init_db(db: &DB, size: u64) -> DbfsResult<()> {
+    let tx = db.tx(true)?;
     let bucket = tx.get_bucket("super_blk");
     let bucket = if bucket.is_ok() {
-        return;
+        return Ok(());
     } else {
-        tx.create_bucket("super_blk").unwrap()
+        tx.create_bucket("super_blk")?
     };
-    bucket.put("continue_number", 1usize.to_be_bytes()).unwrap();
-    bucket.put("magic", 1111u32.to_be_bytes()).unwrap();
-    bucket
-        .put("blk_size", (SLICE_SIZE as u32).to_be_bytes())
-        .unwrap();
-    bucket.put("disk_size", size.to_be_bytes()).unwrap(); //16MB
-    tx.commit().unwrap()
+    bucket.put("continue_number", 1usize.to_be_bytes())?;
+    bucket.put("magic", 1111u32.to_be_bytes())?;
+    bucket.put("blk_size", (SLICE_SIZE as u32).to_be_bytes())?;
+    bucket.put("disk_size", size.to_be_bytes())?; //16MB
+    bucket.put("capacity", size.to_be_bytes())?;
+    bucket.put("label", "")?;
+    bucket.put("uuid", rand::random::<[u8; 16]>())?;
+    tx.commit()?;
+    Ok(())
+}
+
+pub use crate::fs_type::LABEL_MAX_LEN;
+
+/// The settings of a new image
+#[derive(Debug, Clone)]
+pub struct MkfsOptions {
+    /// The size of the image in bytes
+    pub capacity: usize,
+    /// It must be the `SLICE_SIZE` the crate is built with
+    pub slice_size: usize,
+    pub label: String,
+    /// A random one is generated if it is None
+    pub uuid: Option<[u8; 16]>,
+    /// The owner of the root directory
+    pub uid: u32,
+    pub gid: u32,
+    /// Overwrite the image if it exists
+    pub force: bool,
+}
+
+impl Default for MkfsOptions {
+    fn default() -> Self {
+        Self {
+            capacity: super::FILE_SIZE,
+            slice_size: SLICE_SIZE,
+            label: String::new(),
+            uuid: None,
+            uid: 0,
+            gid: 0,
+            force: false,
+        }
+    }
+}
+
+/// Create a formatted image at `path`, return the uuid of it
+///
+/// The image becomes the global db of the process, so it can only be called once.
+pub fn dbfs_fuse_mkfs<T: AsRef<Path>>(path: T, options: &MkfsOptions) -> DbfsResult<[u8; 16]> {
+    use super::FILE_SIZE;
+    let path = path.as_ref();
+    if options.slice_size != SLICE_SIZE {
+        error!(
+            "the slice size must be {}, got {}",
+            SLICE_SIZE, options.slice_size
+        );
+        return Err(DbfsError::InvalidArgument);
+    }
+    if options.label.len() > LABEL_MAX_LEN {
+        return Err(DbfsError::NameTooLong);
+    }
+    if options.capacity < SLICE_SIZE {
+        return Err(DbfsError::InvalidArgument);
+    }
+    if path.exists() {
+        if !options.force {
+            error!("the image {:?} exists", path);
+            return Err(DbfsError::FileExists);
+        }
+        std::fs::remove_file(path).map_err(|_| DbfsError::Io)?;
+    }
+    let uuid = options.uuid.unwrap_or_else(rand::random);
+    let name = path.to_str().ok_or(DbfsError::InvalidArgument)?;
+    set_image_capacity(options.capacity);
+    let db = DB::open::<MyOpenOptions<FILE_SIZE>, _>(Arc::new(FakeMMap), FakePath::new(name))?;
+    init_db(&db, options.capacity as u64)?;
+    let tx = db.tx(true)?;
+    let bucket = tx.get_bucket("super_blk")?;
+    bucket.put("label", options.label.as_bytes())?;
+    bucket.put("uuid", uuid)?;
+    tx.commit()?;
+    init_dbfs(db);
+    dbfs_common_mount()?;
+    let time = DbfsTimeSpec::from(SystemTime::now());
+    dbfs_common_root_inode(options.uid, options.gid, time)?;
+    // write back the next inode number
+    dbfs_common_umount()?;
+    dbfs_fuse_sync_image(false)?;
+    Ok(uuid)
+}
+
+/// Parse the uuid like `8b5cbd6e-0f3a-4c4c-9a51-1d0ad4a4f1b2`
+pub fn parse_uuid(s: &str) -> DbfsResult<[u8; 16]> {
+    let hex = s.replace('-', "");
+    if hex.len() != 32 || !hex.is_ascii() {
+        return Err(DbfsError::InvalidArgument);
+    }
+    let mut uuid = [0u8; 16];
+    for (i, byte) in uuid.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| DbfsError::InvalidArgument)?;
+    }
+    Ok(uuid)
+}
+
+pub fn format_uuid(uuid: &[u8; 16]) -> String {
+    let hex = uuid
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
 }
 
 pub fn test_dbfs(db: &DB) {
@@ -356,3 +673,27 @@ fn show_bucket(tab: usize, bucket: Bucket) {
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fuse::test_db;
+
+    fn disk_size() -> u64 {
+        let tx = dbfs_tx(false).unwrap();
+        let bucket = tx.get_bucket("super_blk").unwrap();
+        get_u64(&bucket, 0, "disk_size").unwrap()
+    }
+
+    #[test]
+    fn resize_changes_disk_size_by_the_capacity() {
+        let _db = test_db();
+        let capacity = image_capacity();
+        let size = disk_size();
+        dbfs_fuse_resize(capacity + 64 * 1024 * 1024).unwrap();
+        assert_eq!(disk_size(), size + 64 * 1024 * 1024);
+        dbfs_fuse_resize(capacity).unwrap();
+        assert_eq!(disk_size(), size);
+        assert_eq!(image_capacity(), capacity);
+    }
+}
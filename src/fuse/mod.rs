@@ -1,91 +1,537 @@
 pub mod attr;
 pub mod file;
+pub mod handle;
 pub mod inode;
+pub mod ioctl;
 pub mod link;
+pub mod lock;
 pub mod mkfs;
+pub mod pool;
 pub mod sblk;
+pub mod tool;
+pub mod tree;
 
 extern crate std;
 
-use alloc::{sync::Arc, vec};
-use std::{alloc::Layout, ffi::OsStr, time::Duration};
+use alloc::{collections::BTreeMap, sync::Arc, vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
 
-use downcast::_std::{path::Path, time::SystemTime};
+use downcast::_std::time::SystemTime;
 use fuser::{
-    consts::FOPEN_DIRECT_IO, fuse_forget_one, FileAttr, Filesystem, KernelConfig, ReplyAttr,
-    ReplyCreate, ReplyData, ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyOpen,
-    ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow,
+    consts::{
+        FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE, FUSE_ASYNC_READ, FUSE_ATOMIC_O_TRUNC,
+        FUSE_DO_READDIRPLUS, FUSE_EXPORT_SUPPORT, FUSE_POSIX_ACL, FUSE_READDIRPLUS_AUTO,
+        FUSE_WRITEBACK_CACHE,
+    },
+    fuse_forget_one, FileAttr, Filesystem, KernelConfig, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyLseek,
+    ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow,
 };
 use jammdb::DB;
-use libc::{c_int, ENOENT};
-use log::{error, info, trace};
+use libc::{c_int, EACCES};
+use log::{error, info, trace, warn};
 pub use mkfs::init_dbfs_fuse;
 
 use crate::{
-    common::DbfsTimeSpec,
-    fs_type::dbfs_common_root_inode,
+    cache::{
+        dbfs_common_cache_flush, dbfs_common_cache_flush_all, dbfs_common_cache_resize,
+        DEFAULT_CACHE_SIZE,
+    },
+    common::{DbfsError, DbfsResult, DbfsTimeSpec},
+    fs_type::{dbfs_common_mount, dbfs_common_root_inode},
     fuse::{
         attr::{
             dbfs_fuse_access, dbfs_fuse_chmod, dbfs_fuse_chown, dbfs_fuse_getattr,
             dbfs_fuse_getxattr, dbfs_fuse_listxattr, dbfs_fuse_removexattr, dbfs_fuse_setxattr,
-            dbfs_fuse_statfs, dbfs_fuse_utimens,
+            dbfs_fuse_statfs, dbfs_fuse_utimens, request_ids, set_id_map,
         },
         file::{
-            dbfs_fuse_copy_file_range, dbfs_fuse_open, dbfs_fuse_opendir, dbfs_fuse_read,
-            dbfs_fuse_readdir, dbfs_fuse_readdirplus, dbfs_fuse_releasedir, dbfs_fuse_write,
+            dbfs_fuse_copy_file_range, dbfs_fuse_fsync, dbfs_fuse_fsyncdir, dbfs_fuse_lseek,
+            dbfs_fuse_open, dbfs_fuse_opendir, dbfs_fuse_read_reply, dbfs_fuse_readdir,
+            dbfs_fuse_readdirplus, dbfs_fuse_releasedir, dbfs_fuse_write,
         },
+        handle::{DbfsFileHandle, DbfsHandleTable},
         inode::{
             dbfs_fuse_create, dbfs_fuse_fallocate, dbfs_fuse_lookup, dbfs_fuse_mkdir,
             dbfs_fuse_mknod, dbfs_fuse_rename, dbfs_fuse_rmdir, dbfs_fuse_truncate,
         },
+        ioctl::dbfs_fuse_ioctl,
         link::{dbfs_fuse_link, dbfs_fuse_readlink, dbfs_fuse_symlink, dbfs_fuse_unlink},
-        mkfs::{init_db, FakeMMap, FakePath, MyOpenOptions},
+        lock::{lock_table, signal_pending, DbfsLock, DbfsLockTable, DBFS_LOCK_CAPABILITIES},
+        mkfs::{
+            dbfs_fuse_load_capacity, dbfs_fuse_open_image, init_db, set_durability,
+            set_image_capacity, Durability, FakeMMap, FakePath, MyOpenOptions,
+        },
+        pool::{write_lock, DbfsWorkerPool},
         sblk::dbfs_fuse_destroy,
     },
-    init_cache, init_dbfs, BUDDY_ALLOCATOR,
+    init_cache_with_size, init_dbfs,
+    inode::dbfs_common_attr,
+    tx::{dbfs_common_group_commit, dbfs_common_group_commit_setup},
+    MAX_BUF_SIZE,
 };
 
 const TTL: Duration = Duration::from_secs(1); // 1 second
-                                              // const FILE_SIZE: u64 = 1024 * 1024 * 1024; // 1 GiB
-                                              // const FILE_SIZE: u64 = 9999999999999999;
+/// The interval of committing the dirty data and the grouped operations
+const COMMIT_INTERVAL: Duration = Duration::from_secs(5);
+// const FILE_SIZE: u64 = 1024 * 1024 * 1024; // 1 GiB
+// const FILE_SIZE: u64 = 9999999999999999;
 const FILE_SIZE: usize = 1024 * 1024 * 1024 * 20; // 6GB
 
+/// The capabilities and the limits asked from the kernel in init
+#[derive(Debug, Clone)]
+struct KernelOptions {
+    writeback_cache: bool,
+    keep_cache: bool,
+    /// 0 keeps the default of fuser
+    max_write: u32,
+    max_readahead: u32,
+    max_background: u16,
+    readdirplus_auto: bool,
+    atomic_o_trunc: bool,
+    posix_acl: bool,
+    export_support: bool,
+}
+
+impl Default for KernelOptions {
+    fn default() -> Self {
+        Self {
+            writeback_cache: false,
+            keep_cache: true,
+            max_write: 0,
+            max_readahead: 0,
+            max_background: 0,
+            readdirplus_auto: false,
+            atomic_o_trunc: true,
+            posix_acl: false,
+            export_support: false,
+        }
+    }
+}
+
 pub struct DbfsFuse {
+    attr_ttl: Duration,
+    entry_ttl: Duration,
+    commit_interval: Duration,
     direct_io: bool,
     _suid_support: bool,
+    /// The capabilities which the kernel agreed on after init
+    kernel: KernelOptions,
+    /// The mtime and the size of the files when they were opened last time
+    cache_stamps: BTreeMap<u64, (u64, u32, usize)>,
+    handles: DbfsHandleTable,
+    /// The handle of the next opened directory, readdir keeps its position for each one
+    next_dir: u64,
+    /// Shared with the thread which interrupts the waiting setlk
+    locks: Arc<Mutex<DbfsLockTable>>,
+    threads: usize,
+    /// The workers of the reads and the writes, they run on the session thread if it is None.
+    /// They are started by init, after the mount helper has forked
+    pool: Option<DbfsWorkerPool>,
+    /// Serialize the writes of the workers
+    writer: Arc<Mutex<()>>,
+    /// The image is mounted read-only, nothing is written back to it
+    read_only: bool,
+    /// Set by destroy, it stops the periodic commit
+    stopped: Arc<AtomicBool>,
 }
 
 impl DbfsFuse {
-    pub fn new(direct_io: bool, _suid_support: bool) -> Self {
-        {
-            Self {
-                direct_io,
-                _suid_support: false,
-            }
+    pub fn builder() -> DbfsFuseBuilder {
+        DbfsFuseBuilder::default()
+    }
+
+    /// The handle of an open, the kernel handles O_APPEND and reads the pages of the
+    /// files opened for writing when it caches the writes
+    fn new_handle(&self, req: &Request<'_>, ino: u64, flags: i32) -> DbfsFileHandle {
+        let (uid, gid) = request_ids(req);
+        let mut handle = DbfsFileHandle::new(ino as usize, flags, uid, gid);
+        if self.kernel.writeback_cache {
+            handle.read |= handle.write;
+            handle.append = false;
+        }
+        handle
+    }
+
+    /// The flags of an open, the pages of the file are kept if it hasn't changed
+    /// since the last open
+    fn open_flags(&mut self, ino: u64) -> u32 {
+        if self.direct_io {
+            return FOPEN_DIRECT_IO;
+        }
+        if !self.kernel.keep_cache {
+            return 0;
+        }
+        let stamp = match dbfs_common_attr(ino as usize) {
+            Ok(attr) => (attr.mtime.sec, attr.mtime.nsec, attr.size),
+            Err(_) => return 0,
+        };
+        match self.cache_stamps.insert(ino, stamp) {
+            Some(old) if old == stamp => FOPEN_KEEP_CACHE,
+            _ => 0,
+        }
+    }
+
+    /// Run the job on a worker, the checks of the handles have been done by the caller
+    fn spawn<F: FnOnce() + Send + 'static>(&self, job: F) {
+        match &self.pool {
+            Some(pool) => pool.spawn(job),
+            None => job(),
         }
     }
 }
 
-impl Filesystem for DbfsFuse {
-    fn init(&mut self, _req: &Request<'_>, _config: &mut KernelConfig) -> Result<(), c_int> {
-        let path = "./my-database.db";
-        let db =
+/// The settings of a [`DbfsFuse`], the image is opened by [`DbfsFuseBuilder::build`]
+#[derive(Debug, Clone)]
+pub struct DbfsFuseBuilder {
+    path: PathBuf,
+    create: bool,
+    read_only: bool,
+    capacity: usize,
+    attr_ttl: Duration,
+    entry_ttl: Duration,
+    buddy_cache_size: usize,
+    write_cache_size: usize,
+    commit_interval: Duration,
+    group_commit: usize,
+    durability: Durability,
+    direct_io: bool,
+    suid_support: bool,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    threads: usize,
+    kernel: KernelOptions,
+}
+
+impl Default for DbfsFuseBuilder {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("./my-database.db"),
+            create: true,
+            read_only: false,
+            capacity: FILE_SIZE,
+            attr_ttl: TTL,
+            entry_ttl: TTL,
+            buddy_cache_size: MAX_BUF_SIZE,
+            write_cache_size: DEFAULT_CACHE_SIZE,
+            commit_interval: COMMIT_INTERVAL,
+            group_commit: 0,
+            durability: Durability::OnFsync,
+            direct_io: false,
+            suid_support: false,
+            uid: None,
+            gid: None,
+            threads: 1,
+            kernel: KernelOptions::default(),
+        }
+    }
+}
+
+impl DbfsFuseBuilder {
+    /// The path of the image
+    pub fn path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.path = path.as_ref().to_path_buf();
+        self
+    }
+    /// Create the image if it doesn't exist, or fail with [`DbfsError::NotFound`]
+    pub fn create(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+    /// The max size of a new image in bytes, an existing image uses the one in its super block
+    /// Open the image read-only, it must have been made by mkfs
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+    /// How long the kernel caches the attributes
+    pub fn attr_ttl(mut self, ttl: Duration) -> Self {
+        self.attr_ttl = ttl;
+        self
+    }
+    /// How long the kernel caches the names
+    pub fn entry_ttl(mut self, ttl: Duration) -> Self {
+        self.entry_ttl = ttl;
+        self
+    }
+    /// The size of the buddy allocator used by the db
+    pub fn buddy_cache_size(mut self, size: usize) -> Self {
+        self.buddy_cache_size = size;
+        self
+    }
+    /// The size of the write-back cache of the file data, 0 disables it
+    pub fn write_cache_size(mut self, size: usize) -> Self {
+        self.write_cache_size = size;
+        self
+    }
+    /// The interval of committing the dirty data and the grouped operations
+    pub fn commit_interval(mut self, interval: Duration) -> Self {
+        self.commit_interval = interval;
+        self
+    }
+    /// Commit this many operations in one transaction, 0 disables group commit
+    pub fn group_commit(mut self, batch: usize) -> Self {
+        self.group_commit = batch;
+        self
+    }
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+    pub fn direct_io(mut self, direct_io: bool) -> Self {
+        self.direct_io = direct_io;
+        self
+    }
+    pub fn suid_support(mut self, suid_support: bool) -> Self {
+        self.suid_support = suid_support;
+        self
+    }
+    /// Show the files owned by the owner of the root directory as owned by `uid`,
+    /// the build fails if either of them is root
+    pub fn uid(mut self, uid: Option<u32>) -> Self {
+        self.uid = uid;
+        self
+    }
+    /// Show the files of the group of the root directory as owned by `gid`,
+    /// the build fails if either of them is root
+    pub fn gid(mut self, gid: Option<u32>) -> Self {
+        self.gid = gid;
+        self
+    }
+
+    /// The number of the threads handling the reads and the writes,
+    /// 1 handles all the requests on the session thread
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Let the kernel cache the writes in its pages and write them back later
+    pub fn writeback_cache(mut self, writeback_cache: bool) -> Self {
+        self.kernel.writeback_cache = writeback_cache;
+        self
+    }
+    /// Keep the pages of a file on open if it hasn't changed since the last open
+    pub fn keep_cache(mut self, keep_cache: bool) -> Self {
+        self.kernel.keep_cache = keep_cache;
+        self
+    }
+    /// The max size of a write request in bytes, 0 keeps the default
+    pub fn max_write(mut self, size: u32) -> Self {
+        self.kernel.max_write = size;
+        self
+    }
+    /// The max size of the readahead of the kernel in bytes, 0 keeps the default
+    pub fn max_readahead(mut self, size: u32) -> Self {
+        self.kernel.max_readahead = size;
+        self
+    }
+    /// The max number of the background requests, 0 keeps the default
+    pub fn max_background(mut self, count: u16) -> Self {
+        self.kernel.max_background = count;
+        self
+    }
+    /// Let the kernel choose between readdir and readdirplus
+    pub fn readdirplus_auto(mut self, readdirplus_auto: bool) -> Self {
+        self.kernel.readdirplus_auto = readdirplus_auto;
+        self
+    }
+    /// Truncate the file in open instead of a setattr after it
+    pub fn atomic_o_trunc(mut self, atomic_o_trunc: bool) -> Self {
+        self.kernel.atomic_o_trunc = atomic_o_trunc;
+        self
+    }
+    /// Let the kernel check the POSIX ACLs stored in the xattrs, it turns on
+    /// default_permissions. The default ACLs aren't inherited by the new files
+    pub fn posix_acl(mut self, posix_acl: bool) -> Self {
+        self.kernel.posix_acl = posix_acl;
+        self
+    }
+    /// Allow the mount to be exported by NFS, the lookups of "." and ".." are answered
+    pub fn export_support(mut self, export_support: bool) -> Self {
+        self.kernel.export_support = export_support;
+        self
+    }
+
+    /// Open the image and initialize the filesystem, it can only be done once
+    pub fn build(self) -> DbfsResult<DbfsFuse> {
+        if !self.create && !self.path.exists() {
+            error!("the image {:?} doesn't exist", self.path);
+            return Err(DbfsError::NotFound);
+        }
+        let db = if self.read_only {
+            dbfs_fuse_open_image(&self.path, true)
+        } else {
+            let path = self.path.to_str().ok_or(DbfsError::InvalidArgument)?;
+            set_image_capacity(self.capacity);
             DB::open::<MyOpenOptions<FILE_SIZE>, FakePath>(Arc::new(FakeMMap), FakePath::new(path))
-                .map_err(|_| -1)?; // TODO: error handling
-        init_db(&db, FILE_SIZE as u64);
+                .map_err(DbfsError::from)
+        }
+        .map_err(|e| {
+            error!("open the image {:?} failed: {:?}", self.path, e);
+            e
+        })?;
+        // the super block is only written to a new image
+        if !self.read_only {
+            init_db(&db, self.capacity as u64)?;
+        }
         init_dbfs(db);
-        init_cache();
+        dbfs_common_mount()?;
+        dbfs_fuse_load_capacity()?;
+        init_cache_with_size(self.buddy_cache_size);
+        dbfs_common_cache_resize(self.write_cache_size)?;
+        dbfs_common_group_commit_setup(self.group_commit)?;
+        set_durability(self.durability);
         let uid = unsafe { libc::getuid() };
         let gid = unsafe { libc::getgid() };
         let time = DbfsTimeSpec::from(SystemTime::now());
-        dbfs_common_root_inode(uid, gid, time).map_err(|_| -1)?;
+        if self.read_only {
+            // the root can't be made, the image must have one
+            dbfs_common_attr(1)?;
+        } else {
+            dbfs_common_root_inode(uid, gid, time)?;
+        }
+        if self.uid.is_some() || self.gid.is_some() {
+            let root = dbfs_common_attr(1)?;
+            set_id_map(
+                self.uid.map(|uid| (root.uid, uid)),
+                self.gid.map(|gid| (root.gid, gid)),
+            )?;
+        }
+        Ok(DbfsFuse {
+            attr_ttl: self.attr_ttl,
+            entry_ttl: self.entry_ttl,
+            commit_interval: self.commit_interval,
+            direct_io: self.direct_io,
+            _suid_support: self.suid_support,
+            kernel: self.kernel,
+            cache_stamps: BTreeMap::new(),
+            handles: DbfsHandleTable::default(),
+            next_dir: 1,
+            locks: Arc::new(Mutex::new(DbfsLockTable::default())),
+            threads: self.threads,
+            pool: None,
+            writer: Arc::new(Mutex::new(())),
+            read_only: self.read_only,
+            stopped: Arc::new(AtomicBool::new(false)),
+        })
+    }
+}
+
+impl Filesystem for DbfsFuse {
+    fn init(&mut self, _req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+        // the kernel sends the POSIX locks to us, it keeps the flock ones itself
+        let mut capabilities = DBFS_LOCK_CAPABILITIES | FUSE_ASYNC_READ;
+        let kernel = &mut self.kernel;
+        for (enabled, capability) in [
+            (kernel.writeback_cache, FUSE_WRITEBACK_CACHE),
+            (
+                kernel.readdirplus_auto,
+                FUSE_DO_READDIRPLUS | FUSE_READDIRPLUS_AUTO,
+            ),
+            (kernel.atomic_o_trunc, FUSE_ATOMIC_O_TRUNC),
+            (kernel.posix_acl, FUSE_POSIX_ACL),
+            (kernel.export_support, FUSE_EXPORT_SUPPORT),
+        ] {
+            if enabled {
+                capabilities |= capability;
+            }
+        }
+        if let Err(x) = config.add_capabilities(capabilities) {
+            warn!("the kernel doesn't support the capabilities: {:#x}", x);
+            // the others still work without them
+            capabilities &= !x;
+            let _ = config.add_capabilities(capabilities);
+        }
+        kernel.writeback_cache = capabilities & FUSE_WRITEBACK_CACHE != 0;
+        kernel.readdirplus_auto = capabilities & FUSE_READDIRPLUS_AUTO != 0;
+        kernel.atomic_o_trunc = capabilities & FUSE_ATOMIC_O_TRUNC != 0;
+        kernel.posix_acl = capabilities & FUSE_POSIX_ACL != 0;
+        kernel.export_support = capabilities & FUSE_EXPORT_SUPPORT != 0;
+        // the values out of the range of the kernel are replaced by the nearest one
+        if kernel.max_write != 0 {
+            if let Err(x) = config.set_max_write(kernel.max_write) {
+                warn!("max_write {} is out of range, use {}", kernel.max_write, x);
+                kernel.max_write = x;
+                let _ = config.set_max_write(x);
+            }
+        }
+        if kernel.max_readahead != 0 {
+            if let Err(x) = config.set_max_readahead(kernel.max_readahead) {
+                warn!(
+                    "max_readahead {} is out of range, use {}",
+                    kernel.max_readahead, x
+                );
+                kernel.max_readahead = x;
+                let _ = config.set_max_readahead(x);
+            }
+        }
+        if kernel.max_background != 0 {
+            if let Err(x) = config.set_max_background(kernel.max_background) {
+                warn!(
+                    "max_background {} is out of range, use {}",
+                    kernel.max_background, x
+                );
+                kernel.max_background = x;
+                let _ = config.set_max_background(x);
+            }
+        }
+        info!("the kernel options: {:?}", kernel);
+        if self.threads > 1 {
+            self.pool = Some(DbfsWorkerPool::new(self.threads));
+        }
+        // fuser doesn't pass the interrupts on, look for the waiters which got a signal
+        let locks = Arc::downgrade(&self.locks);
+        std::thread::spawn(move || {
+            while let Some(locks) = locks.upgrade() {
+                let mut table = lock_table(&locks);
+                if table.has_waiters() {
+                    table.interrupt(signal_pending);
+                }
+                drop(table);
+                drop(locks);
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        });
+        if self.read_only {
+            return Ok(());
+        }
+        let interval = self.commit_interval;
+        let stopped = self.stopped.clone();
+        // commit the dirty data and the grouped operations periodically
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            // destroy commits the rest itself
+            if stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            let res = dbfs_common_cache_flush_all().and_then(|_| dbfs_common_group_commit());
+            if let Err(e) = res {
+                error!("periodic commit failed: {:?}", e);
+            }
+        });
         Ok(())
     }
     /// Clean up filesystem
     fn destroy(&mut self) {
         // we need write back the metadata
         // 1. continue_number to super_block
-        dbfs_fuse_destroy();
+        // the jobs of the workers are done first
+        self.pool.take();
+        self.stopped.store(true, Ordering::SeqCst);
+        if !self.read_only {
+            dbfs_fuse_destroy();
+        }
     }
     /// The lookup() method is called when the kernel wants to know about a file.
     ///
@@ -97,19 +543,22 @@ impl Filesystem for DbfsFuse {
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         let res = dbfs_fuse_lookup(parent, name.to_str().unwrap());
         match res {
-            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Ok(attr) => reply.entry(&self.entry_ttl, &attr, 0),
             Err(x) => {
-                reply.error(x as i32);
+                reply.error(x.errno());
             }
         }
     }
-    fn forget(&mut self, _req: &Request<'_>, _ino: u64, _nlookup: u64) {
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, _nlookup: u64) {
         info!("forget");
+        // the pages of the inode are gone with it
+        self.cache_stamps.remove(&ino);
     }
 
     fn batch_forget(&mut self, _req: &Request<'_>, nodes: &[fuse_forget_one]) {
         for node in nodes {
             trace!("batch_forget: {}", node.nodeid);
+            self.cache_stamps.remove(&node.nodeid);
         }
     }
     fn getattr(
@@ -122,9 +571,9 @@ impl Filesystem for DbfsFuse {
         // 如果不需要使用 `fh` 参数，可以直接忽略它：
         let res = dbfs_fuse_getattr(ino);
         match res {
-            Ok(attr) => reply.attr(&TTL, &attr),
+            Ok(attr) => reply.attr(&self.attr_ttl, &attr),
             Err(x) => {
-                reply.error(x as i32);
+                reply.error(x.errno());
             }
         }
     }
@@ -140,34 +589,40 @@ impl Filesystem for DbfsFuse {
         atime: Option<TimeOrNow>,
         mtime: Option<TimeOrNow>,
         _ctime: Option<SystemTime>,
-        _fh: Option<u64>,
+        fh: Option<u64>,
         _crtime: Option<SystemTime>,
         _chgtime: Option<SystemTime>,
         _bkuptime: Option<SystemTime>,
         _flags: Option<u32>,
         reply: ReplyAttr,
     ) {
+        let _guard = write_lock(&self.writer);
         if let Some(mode) = mode {
             let res = dbfs_fuse_chmod(req, ino, mode);
             match res {
-                Ok(attr) => reply.attr(&TTL, &attr.into()),
-                Err(x) => reply.error(x as i32),
+                Ok(attr) => reply.attr(&self.attr_ttl, &attr.into()),
+                Err(x) => reply.error(x.errno()),
             }
             return;
         }
         if uid.is_some() || gid.is_some() {
             let res = dbfs_fuse_chown(req, ino, uid, gid);
             match res {
-                Ok(attr) => reply.attr(&TTL, &attr.into()),
-                Err(x) => reply.error(x as i32),
+                Ok(attr) => reply.attr(&self.attr_ttl, &attr.into()),
+                Err(x) => reply.error(x.errno()),
             }
             return;
         }
         if let Some(size) = size {
-            let res = dbfs_fuse_truncate(req, ino, size);
+            // ftruncate needs a handle open for writing
+            let res = match fh {
+                Some(fh) => self.handles.writable(fh, ino).map(|_| ()),
+                None => Ok(()),
+            };
+            let res = res.and_then(|_| dbfs_fuse_truncate(req, ino, size));
             match res {
-                Ok(attr) => reply.attr(&TTL, &attr.into()),
-                Err(x) => reply.error(x as i32),
+                Ok(attr) => reply.attr(&self.attr_ttl, &attr.into()),
+                Err(x) => reply.error(x.errno()),
             }
             return;
         }
@@ -177,9 +632,9 @@ impl Filesystem for DbfsFuse {
             match res {
                 Ok(attr) => {
                     let attr: FileAttr = attr.into();
-                    reply.attr(&TTL, &attr)
+                    reply.attr(&self.attr_ttl, &attr)
                 }
-                Err(x) => reply.error(x as i32),
+                Err(x) => reply.error(x.errno()),
             }
             return;
         }
@@ -193,7 +648,7 @@ impl Filesystem for DbfsFuse {
         let res = dbfs_fuse_readlink(ino);
         match res {
             Ok(data) => reply.data(&data),
-            Err(x) => reply.error(x as i32),
+            Err(x) => reply.error(x.errno()),
         }
     }
 
@@ -209,10 +664,11 @@ impl Filesystem for DbfsFuse {
         rdev: u32,
         reply: ReplyEntry,
     ) {
+        let _guard = write_lock(&self.writer);
         let res = dbfs_fuse_mknod(req, parent, name.to_str().unwrap(), mode, rdev);
         match res {
-            Ok(attr) => reply.entry(&TTL, &attr.into(), 0),
-            Err(x) => reply.error(x as i32),
+            Ok(attr) => reply.entry(&self.entry_ttl, &attr.into(), 0),
+            Err(x) => reply.error(x.errno()),
         }
     }
 
@@ -228,30 +684,33 @@ impl Filesystem for DbfsFuse {
         _umask: u32,
         reply: ReplyEntry,
     ) {
+        let _guard = write_lock(&self.writer);
         let res = dbfs_fuse_mkdir(req, parent, name.to_str().unwrap(), mode);
         match res {
-            Ok(attr) => reply.entry(&TTL, &attr, 0),
-            Err(_) => reply.error(ENOENT),
+            Ok(attr) => reply.entry(&self.entry_ttl, &attr, 0),
+            Err(x) => reply.error(x.errno()),
         }
     }
 
     /// Remove a file
     fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _guard = write_lock(&self.writer);
         let res = dbfs_fuse_unlink(req, parent, name.to_str().unwrap());
         match res {
             Ok(_) => reply.ok(),
             Err(x) => {
                 // panic!("unlink panic");
-                reply.error(x as i32)
+                reply.error(x.errno())
             }
         }
     }
     /// Remove the given directory. This should succeed only if the directory is empty (except for "." and "..").
     fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _guard = write_lock(&self.writer);
         let res = dbfs_fuse_rmdir(req, parent, name.to_str().unwrap());
         match res {
             Ok(_) => reply.ok(),
-            Err(x) => reply.error(x as i32),
+            Err(x) => reply.error(x.errno()),
         }
     }
     /// Create a symbolic link
@@ -263,10 +722,11 @@ impl Filesystem for DbfsFuse {
         link: &Path,
         reply: ReplyEntry,
     ) {
+        let _guard = write_lock(&self.writer);
         let res = dbfs_fuse_symlink(req, parent, name.to_str().unwrap(), link.to_str().unwrap());
         match res {
-            Ok(attr) => reply.entry(&TTL, &attr.into(), 0),
-            Err(x) => reply.error(x as i32),
+            Ok(attr) => reply.entry(&self.entry_ttl, &attr.into(), 0),
+            Err(x) => reply.error(x.errno()),
         }
     }
 
@@ -276,6 +736,7 @@ impl Filesystem for DbfsFuse {
     // If RENAME_NOREPLACE is specified, the filesystem must not overwrite newname if it exists
     // and return an error instead. If RENAME_EXCHANGE is specified, the filesystem must
     // atomically exchange the two files, i.e. both must exist and neither may be deleted.
+    // RENAME_WHITEOUT leaves a whiteout at the old name for overlayfs.
     fn rename(
         &mut self,
         req: &Request<'_>,
@@ -286,6 +747,7 @@ impl Filesystem for DbfsFuse {
         flags: u32,
         reply: ReplyEmpty,
     ) {
+        let _guard = write_lock(&self.writer);
         let res = dbfs_fuse_rename(
             req,
             parent,
@@ -296,7 +758,7 @@ impl Filesystem for DbfsFuse {
         );
         match res {
             Ok(_) => reply.ok(),
-            Err(x) => reply.error(x as i32),
+            Err(x) => reply.error(x.errno()),
         }
     }
 
@@ -309,12 +771,13 @@ impl Filesystem for DbfsFuse {
         newname: &OsStr,
         reply: ReplyEntry,
     ) {
+        let _guard = write_lock(&self.writer);
         let res = dbfs_fuse_link(req, ino, newparent, newname.to_str().unwrap());
         match res {
-            Ok(attr) => reply.entry(&TTL, &attr.into(), 0),
+            Ok(attr) => reply.entry(&self.entry_ttl, &attr.into(), 0),
             Err(e) => {
                 error!("link error: {:?}", e);
-                reply.error(e as i32)
+                reply.error(e.errno())
             }
         }
     }
@@ -330,99 +793,119 @@ impl Filesystem for DbfsFuse {
     /// The filesystem should thus either ignore the O_APPEND flag (and let the kernel handle it), or return an error (indicating that reliably O_APPEND is not available).
     fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
         let res = dbfs_fuse_open(req, ino, flags);
-        match res {
-            Ok(_) => {
-                let open_flags = if self.direct_io { FOPEN_DIRECT_IO } else { 0 };
-                reply.opened(0, open_flags);
+        if let Err(x) = res {
+            reply.error(x);
+            return;
+        }
+        let handle = self.new_handle(req, ino, flags);
+        // the kernel only passes O_TRUNC if it doesn't truncate the file itself
+        if handle.write && flags & libc::O_TRUNC != 0 {
+            let _guard = write_lock(&self.writer);
+            if let Err(x) = dbfs_fuse_truncate(req, ino, 0) {
+                reply.error(x.errno());
+                return;
             }
-            Err(_) => reply.error(res.err().unwrap()),
         }
+        let fh = self.handles.insert(handle);
+        let open_flags = self.open_flags(ino);
+        reply.opened(fh, open_flags);
     }
 
     fn read(
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
         _flags: i32,
         _lock_owner: Option<u64>,
         reply: ReplyData,
     ) {
-        let _data = vec![0u8; size as usize];
-        let ptr = BUDDY_ALLOCATOR
-            .lock()
-            .alloc(Layout::from_size_align(size as usize, 8).unwrap())
-            .unwrap();
-        let data =
-            unsafe { std::slice::from_raw_parts_mut(ptr.as_ptr() as *mut u8, size as usize) };
-        let res = dbfs_fuse_read(ino, offset, data);
-        match res {
-            Ok(x) => reply.data(&data[..x]),
-            Err(_) => reply.error(ENOENT),
+        if let Err(x) = self.handles.readable(fh, ino) {
+            reply.error(x.errno());
+            return;
         }
-        BUDDY_ALLOCATOR
-            .lock()
-            .dealloc(ptr, Layout::from_size_align(size as usize, 8).unwrap());
-        // dbfs_fuse_special_read(ino as usize, offset, size as usize, reply).unwrap();
+        self.spawn(move || dbfs_fuse_read_reply(ino, offset, size, reply));
     }
 
     fn write(
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         data: &[u8],
         _write_flags: u32,
-        _flags: i32,
+        flags: i32,
         _lock_owner: Option<u64>,
         reply: ReplyWrite,
     ) {
-        let res = dbfs_fuse_write(ino, offset, data);
-        match res {
-            Ok(x) => reply.written(x as u32),
-            Err(_) => reply.error(ENOENT),
-        }
+        let append = match self.handles.writable(fh, ino) {
+            Ok(handle) => handle.append,
+            Err(x) => {
+                reply.error(x.errno());
+                return;
+            }
+        };
+        let writer = self.writer.clone();
+        // the data belongs to the request, it is copied for the worker
+        let data = data.to_vec();
+        self.spawn(move || {
+            let _guard = write_lock(&writer);
+            // the end of the file is found under the lock so appends don't overlap
+            let offset = match append {
+                true => dbfs_common_attr(ino as usize).map(|x| x.size as i64),
+                false => Ok(offset),
+            };
+            let res = offset.and_then(|offset| dbfs_fuse_write(ino, offset, &data, flags));
+            match res {
+                Ok(x) => reply.written(x as u32),
+                Err(x) => reply.error(x.errno()),
+            }
+        });
     }
 
     fn flush(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
+        ino: u64,
         _fh: u64,
-        _lock_owner: u64,
+        lock_owner: u64,
         reply: ReplyEmpty,
     ) {
-        reply.ok();
+        // close releases the POSIX locks of the process
+        lock_table(&self.locks).release_owner(ino, lock_owner);
+        match dbfs_common_cache_flush(ino as usize) {
+            Ok(_) => reply.ok(),
+            Err(x) => reply.error(x.errno()),
+        }
     }
     fn release(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
-        _fh: u64,
+        ino: u64,
+        fh: u64,
         _flags: i32,
         _lock_owner: Option<u64>,
         _flush: bool,
         reply: ReplyEmpty,
     ) {
-        reply.ok();
+        self.handles.remove(fh);
+        match dbfs_common_cache_flush(ino as usize) {
+            Ok(_) => reply.ok(),
+            Err(x) => reply.error(x.errno()),
+        }
     }
 
     ///Synchronize file contents
     ///
     /// If the datasync parameter is non-zero, then only the user data should be flushed, not the meta data.
-    fn fsync(
-        &mut self,
-        _req: &Request<'_>,
-        _ino: u64,
-        _fh: u64,
-        _datasync: bool,
-        reply: ReplyEmpty,
-    ) {
-        reply.ok();
-        error!("fsync not implemented");
+    fn fsync(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, datasync: bool, reply: ReplyEmpty) {
+        self.spawn(move || match dbfs_fuse_fsync(ino, datasync) {
+            Ok(_) => reply.ok(),
+            Err(x) => reply.error(x.errno()),
+        });
     }
 
     /// Open directory
@@ -434,10 +917,12 @@ impl Filesystem for DbfsFuse {
         let res = dbfs_fuse_opendir(req, ino, flags);
         match res {
             Ok(_) => {
+                let fh = self.next_dir;
+                self.next_dir += 1;
                 let open_flags = if self.direct_io { FOPEN_DIRECT_IO } else { 0 };
-                reply.opened(0, open_flags);
+                reply.opened(fh, open_flags);
             }
-            Err(x) => reply.error(x as i32),
+            Err(x) => reply.error(x.errno()),
         }
     }
 
@@ -445,22 +930,23 @@ impl Filesystem for DbfsFuse {
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         reply: ReplyDirectory,
     ) {
-        dbfs_fuse_readdir(ino, offset, reply)
+        self.spawn(move || dbfs_fuse_readdir(ino, fh, offset, reply));
     }
 
     fn readdirplus(
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         reply: ReplyDirectoryPlus,
     ) {
-        dbfs_fuse_readdirplus(ino, offset, reply)
+        let ttl = self.entry_ttl;
+        self.spawn(move || dbfs_fuse_readdirplus(ino, fh, offset, &ttl, reply));
     }
 
     /// Release directory
@@ -470,22 +956,27 @@ impl Filesystem for DbfsFuse {
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         _flags: i32,
         reply: ReplyEmpty,
     ) {
-        dbfs_fuse_releasedir(ino).unwrap();
-        reply.ok()
+        match dbfs_fuse_releasedir(ino, fh) {
+            Ok(_) => reply.ok(),
+            Err(x) => reply.error(x.errno()),
+        }
     }
     fn fsyncdir(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
+        ino: u64,
         _fh: u64,
-        _datasync: bool,
+        datasync: bool,
         reply: ReplyEmpty,
     ) {
-        reply.ok()
+        match dbfs_fuse_fsyncdir(ino, datasync) {
+            Ok(_) => reply.ok(),
+            Err(x) => reply.error(x.errno()),
+        }
     }
 
     /// Get file system statistics
@@ -506,7 +997,7 @@ impl Filesystem for DbfsFuse {
                     stat.f_frsize as u32,  // fragment size
                 );
             }
-            Err(_) => reply.error(ENOENT),
+            Err(x) => reply.error(x.errno()),
         }
     }
     /// Set extended attributes
@@ -520,10 +1011,11 @@ impl Filesystem for DbfsFuse {
         position: u32,
         reply: ReplyEmpty,
     ) {
+        let _guard = write_lock(&self.writer);
         let res = dbfs_fuse_setxattr(req, ino, name.to_str().unwrap(), value, flags, position);
         match res {
             Ok(_) => reply.ok(),
-            Err(x) => reply.error(x as i32),
+            Err(x) => reply.error(x.errno()),
         }
     }
 
@@ -546,7 +1038,7 @@ impl Filesystem for DbfsFuse {
                     reply.data(&buf[..x]);
                 }
             }
-            Err(x) => reply.error(x as i32),
+            Err(x) => reply.error(x.errno()),
         }
     }
 
@@ -559,18 +1051,19 @@ impl Filesystem for DbfsFuse {
                 if size == 0 {
                     reply.size(x as u32);
                 } else {
-                    reply.data(&buf);
+                    reply.data(&buf[..x]);
                 }
             }
-            Err(x) => reply.error(x as i32),
+            Err(x) => reply.error(x.errno()),
         }
     }
     /// Remove extended attributes
     fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _guard = write_lock(&self.writer);
         let res = dbfs_fuse_removexattr(req, ino, name.to_str().unwrap());
         match res {
             Ok(_) => reply.ok(),
-            Err(x) => reply.error(x as i32),
+            Err(x) => reply.error(x.errno()),
         }
     }
 
@@ -581,21 +1074,80 @@ impl Filesystem for DbfsFuse {
                 if bool {
                     reply.ok();
                 } else {
-                    reply.error(ENOENT);
+                    reply.error(EACCES);
                 }
             }
-            Err(x) => reply.error(x as i32),
+            Err(x) => reply.error(x.errno()),
         }
     }
     // fn bmap(&mut self, _req: &Request<'_>, _ino: u64, _blocksize: u32, _idx: u64, reply: ReplyBmap) {
     //     todo!()
     // }
-    // fn getlk(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: i32, _pid: u32, reply: ReplyLock) {
-    //     todo!()
-    // }
-    // fn setlk(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: i32, _pid: u32, _sleep: bool, reply: ReplyEmpty) {
-    //     todo!()
-    // }
+    /// Test for a POSIX file lock
+    fn getlk(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        if let Err(x) = self.handles.get(fh, ino) {
+            reply.error(x.errno());
+            return;
+        }
+        let lock = DbfsLock {
+            owner: lock_owner,
+            start,
+            end,
+            typ,
+            pid,
+        };
+        let lock = lock_table(&self.locks).getlk(ino, lock);
+        reply.locked(lock.start, lock.end, lock.typ, lock.pid);
+    }
+
+    /// Acquire, modify or release a POSIX file lock
+    ///
+    /// If `sleep` is set, the reply is delayed until the lock can be taken.
+    fn setlk(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        // the kernel checks the access mode for fcntl
+        if let Err(x) = self.handles.get(fh, ino) {
+            reply.error(x.errno());
+            return;
+        }
+        let lock = DbfsLock {
+            owner: lock_owner,
+            start,
+            end,
+            typ,
+            pid,
+        };
+        if sleep {
+            lock_table(&self.locks).setlk_wait(ino, lock, req.pid(), reply);
+            return;
+        }
+        match lock_table(&self.locks).setlk(ino, lock) {
+            Ok(_) => reply.ok(),
+            Err(x) => reply.error(x.errno()),
+        }
+    }
 
     // macos
     // fn exchange(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, _options: u64, reply: ReplyEmpty) {
@@ -617,10 +1169,18 @@ impl Filesystem for DbfsFuse {
         flags: i32,
         reply: ReplyCreate,
     ) {
-        let res = dbfs_fuse_create(req, parent, name.to_str().unwrap(), mode, flags);
+        let res = {
+            let _guard = write_lock(&self.writer);
+            dbfs_fuse_create(req, parent, name.to_str().unwrap(), mode, flags)
+        };
         match res {
-            Ok(attr) => reply.created(&TTL, &attr, 0, 0, 0),
-            Err(x) => reply.error(x as i32),
+            Ok(attr) => {
+                let handle = self.new_handle(req, attr.ino, flags);
+                let fh = self.handles.insert(handle);
+                let open_flags = self.open_flags(attr.ino);
+                reply.created(&self.entry_ttl, &attr, 0, fh, open_flags)
+            }
+            Err(x) => reply.error(x.errno()),
         }
     }
 
@@ -629,23 +1189,49 @@ impl Filesystem for DbfsFuse {
     //
     // }
 
-    // fn ioctl(
-    //     &mut self,
-    //     _req: &Request<'_>,
-    //     _ino: u64,
-    //     _fh: u64,
-    //     _flags: u32,
-    //     _cmd: u32,
-    //     _in_data: &[u8],
-    //     _out_size: u32,
-    //     reply: ReplyIoctl,
-    // ) {
-    //     todo!()
-    // }
+    /// Control device
+    ///
+    /// The Linux VFS answers FS_IOC_FIEMAP itself when the filesystem has no fiemap
+    /// inode operation, so the mounted files are mapped with `dbfs_fuse_fiemap` instead.
+    fn ioctl(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        // the directories come with the fh of opendir, which isn't in the handle table
+        _fh: u64,
+        _flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        let _guard = write_lock(&self.writer);
+        let res = dbfs_fuse_ioctl(req, ino, cmd, in_data, out_size);
+        match res {
+            Ok(data) => reply.ioctl(0, &data),
+            Err(x) => reply.error(x.errno()),
+        }
+    }
 
-    // fn lseek(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, _whence: i32, reply: ReplyLseek) {
-    //     todo!()
-    // }
+    /// Find the next data or hole after the offset
+    fn lseek(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        if let Err(x) = self.handles.get(fh, ino) {
+            reply.error(x.errno());
+            return;
+        }
+        self.spawn(move || match dbfs_fuse_lseek(ino, offset, whence) {
+            Ok(x) => reply.offset(x),
+            Err(x) => reply.error(x.errno()),
+        });
+    }
 
     // macos
     // fn setvolname(&mut self, _req: &Request<'_>, _name: &OsStr, reply: ReplyEmpty) {
@@ -661,16 +1247,21 @@ impl Filesystem for DbfsFuse {
         &mut self,
         req: &Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         length: i64,
         mode: i32,
         reply: ReplyEmpty,
     ) {
+        if let Err(x) = self.handles.writable(fh, ino) {
+            reply.error(x.errno());
+            return;
+        }
+        let _guard = write_lock(&self.writer);
         let res = dbfs_fuse_fallocate(req, ino, offset as u64, length as u64, mode as u32);
         match res {
             Ok(_) => reply.ok(),
-            Err(x) => reply.error(x as i32),
+            Err(x) => reply.error(x.errno()),
         }
     }
 
@@ -682,15 +1273,21 @@ impl Filesystem for DbfsFuse {
         &mut self,
         req: &Request<'_>,
         ino_in: u64,
-        _fh_in: u64,
+        fh_in: u64,
         offset_in: i64,
         ino_out: u64,
-        _fh_out: u64,
+        fh_out: u64,
         offset_out: i64,
         len: u64,
         _flags: u32,
         reply: ReplyWrite,
     ) {
+        let res = self.handles.readable(fh_in, ino_in);
+        if let Err(x) = res.and_then(|_| self.handles.writable(fh_out, ino_out)) {
+            reply.error(x.errno());
+            return;
+        }
+        let _guard = write_lock(&self.writer);
         let res = dbfs_fuse_copy_file_range(
             req,
             ino_in,
@@ -701,7 +1298,29 @@ impl Filesystem for DbfsFuse {
         );
         match res {
             Ok(x) => reply.written(x as u32),
-            Err(x) => reply.error(x as i32),
+            Err(x) => reply.error(x.errno()),
         }
     }
 }
+
+/// The global db of the tests, it is built once on an image which is removed at once.
+///
+/// The tests holding the guard run one by one, group commit is global
+#[cfg(test)]
+pub(crate) fn test_db() -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    static DB: spin::Once<()> = spin::Once::new();
+    DB.call_once(|| {
+        let path = std::env::temp_dir().join(alloc::format!("dbfs-test-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let fs = DbfsFuse::builder()
+            .path(&path)
+            .capacity(256 * 1024 * 1024)
+            .build()
+            .unwrap();
+        // the db keeps the file open and mapped
+        std::fs::remove_file(&path).unwrap();
+        core::mem::forget(fs);
+    });
+    LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
@@ -0,0 +1,192 @@
+//! The worker threads of a multi-threaded [`super::DbfsFuse`].
+//!
+//! The session thread checks the handles and the locks, then the reads, the writes and
+//! the readdirs run here with the reply moved to the worker. The reads use their own
+//! read-only transactions so they run in parallel, the writes are serialized by
+//! [`write_lock`].
+use alloc::{boxed::Box, vec::Vec};
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::JoinHandle,
+};
+
+use log::error;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+pub struct DbfsWorkerPool {
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl DbfsWorkerPool {
+    pub fn new(threads: usize) -> Self {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads)
+            .map(|i| {
+                let receiver = receiver.clone();
+                std::thread::Builder::new()
+                    .name(alloc::format!("dbfs-worker-{}", i))
+                    .spawn(move || worker(receiver))
+                    .unwrap()
+            })
+            .collect();
+        Self {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    pub fn spawn<F: FnOnce() + Send + 'static>(&self, job: F) {
+        if let Some(sender) = &self.sender {
+            if sender.send(Box::new(job)).is_err() {
+                error!("the workers of dbfs have exited");
+            }
+        }
+    }
+
+    /// Wait for the jobs which have been spawned
+    pub fn join(&mut self) {
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for DbfsWorkerPool {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+/// The lock the writes hold, it is still usable after a writer panicked
+/// since the data is in the db
+pub fn write_lock(writer: &Mutex<()>) -> MutexGuard<'_, ()> {
+    writer.lock().unwrap_or_else(|x| x.into_inner())
+}
+
+fn worker(receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        let job = {
+            let receiver = receiver.lock().unwrap_or_else(|x| x.into_inner());
+            receiver.recv()
+        };
+        match job {
+            // the reply of a panicked job is dropped, which sends EIO
+            Ok(job) => {
+                if catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    error!("a job of dbfs panicked");
+                }
+            }
+            // the pool is dropped
+            Err(_) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{format, vec};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{
+        common::{DbfsPermission, DbfsResult},
+        dbfs_common_cache_flush_all,
+        file::{dbfs_common_read, dbfs_common_write},
+        fuse::{
+            test_db,
+            tool::{dbfs_fuse_list_dir, now},
+        },
+        inode::{dbfs_common_attr, dbfs_common_create},
+        SLICE_SIZE,
+    };
+
+    const FILES: usize = 8;
+    const ROUNDS: usize = 4;
+    const RECORD: usize = 100;
+
+    /// The data crosses the slices and doesn't start at one
+    fn pattern(file: usize, round: usize) -> Vec<u8> {
+        (0..SLICE_SIZE * 3 / 2 + 17)
+            .map(|i| ((file * 31 + round * 7 + i) % 251) as u8)
+            .collect()
+    }
+
+    fn check(ino: usize, file: usize, shared: usize) -> DbfsResult<bool> {
+        let mut ok = true;
+        for round in 0..ROUNDS {
+            let data = pattern(file, round);
+            let mut buf = vec![0u8; data.len()];
+            let count = dbfs_common_read(ino, &mut buf, (round * data.len()) as u64)?;
+            ok &= count == data.len() && buf == data;
+        }
+        let mut buf = vec![0u8; FILES * ROUNDS * RECORD];
+        let count = dbfs_common_read(shared, &mut buf, 0)?;
+        let records = buf[..count]
+            .iter()
+            .filter(|&&x| x == file as u8 + 1)
+            .count();
+        Ok(ok && count == buf.len() && records == ROUNDS * RECORD)
+    }
+
+    #[test]
+    fn workers_read_and_write_the_files_in_parallel() {
+        let _db = test_db();
+        let mode = DbfsPermission::S_IFREG | DbfsPermission::from_bits_truncate(0o644);
+        let create = |name: &str| dbfs_common_create(1, name, 0, 0, now(), mode, None, None);
+        let files = (0..FILES)
+            .map(|i| create(&format!("pool-file{}", i)).unwrap().ino)
+            .collect::<Vec<_>>();
+        let shared = create("pool-shared").unwrap().ino;
+
+        let writer = Arc::new(Mutex::new(()));
+        let failed = Arc::new(AtomicUsize::new(0));
+        let mut pool = DbfsWorkerPool::new(4);
+        for round in 0..ROUNDS {
+            for (file, &ino) in files.iter().enumerate() {
+                let writer = writer.clone();
+                let failed = failed.clone();
+                pool.spawn(move || {
+                    let res = (|| -> DbfsResult<bool> {
+                        let data = pattern(file, round);
+                        let offset = (round * data.len()) as u64;
+                        {
+                            let _writer = write_lock(&writer);
+                            dbfs_common_write(ino, &data, offset)?;
+                        }
+                        let mut buf = vec![0u8; data.len()];
+                        let count = dbfs_common_read(ino, &mut buf, offset)?;
+                        let name = format!("pool-file{}", file);
+                        let listed = dbfs_fuse_list_dir("/")?.iter().any(|(x, _)| *x == name);
+                        // the end of the shared file is found under the lock
+                        let _writer = write_lock(&writer);
+                        let size = dbfs_common_attr(shared)?.size;
+                        dbfs_common_write(shared, &[file as u8 + 1; RECORD], size as u64)?;
+                        Ok(listed && count == data.len() && buf == data)
+                    })();
+                    if !matches!(res, Ok(true)) {
+                        failed.fetch_add(1, Ordering::SeqCst);
+                    }
+                });
+            }
+        }
+        pool.join();
+        assert_eq!(failed.load(Ordering::SeqCst), 0);
+
+        // the data is the same in the cache and after it is committed
+        for (file, &ino) in files.iter().enumerate() {
+            assert!(check(ino, file, shared).unwrap());
+        }
+        dbfs_common_cache_flush_all().unwrap();
+        for (file, &ino) in files.iter().enumerate() {
+            assert!(check(ino, file, shared).unwrap());
+        }
+    }
+}
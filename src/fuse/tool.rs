@@ -0,0 +1,177 @@
+//! Path based operations on an image which isn't mounted, for the tools.
+//!
+//! The operations are done with the credentials given by the caller and the image
+//! becomes the global db of the process, so only one image can be attached.
+use alloc::{
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use std::io::{Read, Write};
+
+use downcast::_std::time::SystemTime;
+
+use crate::{
+    attr::dbfs_common_chmod,
+    common::{
+        DbfsAttr, DbfsDirEntry, DbfsError, DbfsFileType, DbfsPermission, DbfsResult, DbfsTimeSpec,
+    },
+    file::{dbfs_common_read, dbfs_common_readdir, dbfs_common_write},
+    fs_type::{dbfs_common_mount, dbfs_common_umount},
+    fuse::mkfs::{dbfs_fuse_load_capacity, dbfs_fuse_open_image, dbfs_fuse_sync_image},
+    init_cache, init_dbfs,
+    inode::{
+        dbfs_common_attr, dbfs_common_create, dbfs_common_lookup, dbfs_common_rename,
+        dbfs_common_rmdir, dbfs_common_truncate,
+    },
+    link::dbfs_common_unlink,
+    SLICE_SIZE,
+};
+
+/// The size of the buffer used to copy the data
+pub(crate) const COPY_SIZE: usize = 16 * SLICE_SIZE;
+
+/// Open the image and make it the global db
+pub fn dbfs_fuse_attach_image<T: AsRef<std::path::Path>>(path: T) -> DbfsResult<()> {
+    let db = dbfs_fuse_open_image(path, false)?;
+    init_dbfs(db);
+    dbfs_common_mount()?;
+    dbfs_fuse_load_capacity()?;
+    init_cache();
+    Ok(())
+}
+
+/// Write back the image attached by [`dbfs_fuse_attach_image`]
+pub fn dbfs_fuse_detach_image() -> DbfsResult<()> {
+    dbfs_common_umount()?;
+    dbfs_fuse_sync_image(false)
+}
+
+pub(crate) fn now() -> DbfsTimeSpec {
+    DbfsTimeSpec::from(SystemTime::now())
+}
+
+/// Split the path into the parent directory and the name
+pub(crate) fn split_path(path: &str) -> DbfsResult<(usize, &str)> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(DbfsError::InvalidArgument);
+    }
+    Ok((dbfs_fuse_resolve(parent)?, name))
+}
+
+/// Get the inode number of the path, which is relative to the root
+pub fn dbfs_fuse_resolve(path: &str) -> DbfsResult<usize> {
+    let mut ino = 1;
+    for name in path.split('/').filter(|x| !x.is_empty()) {
+        ino = dbfs_common_lookup(ino, name)?.ino;
+    }
+    Ok(ino)
+}
+
+pub fn dbfs_fuse_stat_path(path: &str) -> DbfsResult<DbfsAttr> {
+    dbfs_common_attr(dbfs_fuse_resolve(path)?)
+}
+
+/// List the entries of the directory without `.` and `..`
+pub fn dbfs_fuse_list_dir(path: &str) -> DbfsResult<Vec<(String, DbfsAttr)>> {
+    let ino = dbfs_fuse_resolve(path)?;
+    let mut list = vec![];
+    let mut entries = vec![DbfsDirEntry::default(); 16];
+    let mut offset = 0;
+    loop {
+        let count = dbfs_common_readdir(ino, 0, &mut entries, offset, true)?;
+        if count == 0 {
+            return Ok(list);
+        }
+        for entry in entries[..count].iter_mut() {
+            offset = entry.offset + 1;
+            if entry.name == "." || entry.name == ".." {
+                continue;
+            }
+            let attr = entry.attr.take().ok_or(DbfsError::NoData)?;
+            list.push((entry.name.to_string(), attr));
+        }
+    }
+}
+
+/// Copy the data of the file to `writer`
+pub fn dbfs_fuse_read_file<W: Write>(path: &str, writer: &mut W) -> DbfsResult<usize> {
+    let ino = dbfs_fuse_resolve(path)?;
+    if dbfs_common_attr(ino)?.kind == DbfsFileType::Directory {
+        return Err(DbfsError::InvalidArgument);
+    }
+    let mut buf = vec![0u8; COPY_SIZE];
+    let mut offset = 0;
+    loop {
+        let count = dbfs_common_read(ino, &mut buf, offset as u64)?;
+        if count == 0 {
+            return Ok(offset);
+        }
+        writer.write_all(&buf[..count]).map_err(|_| DbfsError::Io)?;
+        offset += count;
+    }
+}
+
+/// Create the file or truncate it, then copy the data of `reader` to it
+pub fn dbfs_fuse_write_file<R: Read>(
+    uid: u32,
+    gid: u32,
+    path: &str,
+    reader: &mut R,
+    mode: u16,
+) -> DbfsResult<usize> {
+    let ino = match dbfs_fuse_resolve(path) {
+        Ok(ino) => {
+            dbfs_common_truncate(uid, gid, ino, now(), 0)?;
+            ino
+        }
+        Err(DbfsError::NotFound) => {
+            let (parent, name) = split_path(path)?;
+            let mut permission = DbfsPermission::from_bits_truncate(mode & 0o7777);
+            permission |= DbfsPermission::S_IFREG;
+            dbfs_common_create(parent, name, uid, gid, now(), permission, None, None)?.ino
+        }
+        Err(e) => return Err(e),
+    };
+    let mut buf = vec![0u8; COPY_SIZE];
+    let mut offset = 0;
+    loop {
+        let count = reader.read(&mut buf).map_err(|_| DbfsError::Io)?;
+        if count == 0 {
+            return Ok(offset);
+        }
+        dbfs_common_write(ino, &buf[..count], offset as u64)?;
+        offset += count;
+    }
+}
+
+pub fn dbfs_fuse_make_dir(uid: u32, gid: u32, path: &str, mode: u16) -> DbfsResult<DbfsAttr> {
+    let (parent, name) = split_path(path)?;
+    let mut permission = DbfsPermission::from_bits_truncate(mode & 0o7777);
+    permission |= DbfsPermission::S_IFDIR;
+    dbfs_common_create(parent, name, uid, gid, now(), permission, None, None)
+}
+
+/// Remove the file or the empty directory
+pub fn dbfs_fuse_remove(uid: u32, gid: u32, path: &str) -> DbfsResult<()> {
+    let (parent, name) = split_path(path)?;
+    let attr = dbfs_common_lookup(parent, name)?;
+    if attr.kind == DbfsFileType::Directory {
+        dbfs_common_rmdir(uid, gid, parent, name, now())
+    } else {
+        dbfs_common_unlink(uid, gid, parent, name, None, now())
+    }
+}
+
+pub fn dbfs_fuse_move(uid: u32, gid: u32, from: &str, to: &str) -> DbfsResult<()> {
+    let (old_dir, old_name) = split_path(from)?;
+    let (new_dir, new_name) = split_path(to)?;
+    dbfs_common_rename(uid, gid, old_dir, old_name, new_dir, new_name, 0, now())
+}
+
+pub fn dbfs_fuse_chmod_path(uid: u32, gid: u32, path: &str, mode: u16) -> DbfsResult<DbfsAttr> {
+    let ino = dbfs_fuse_resolve(path)?;
+    dbfs_common_chmod(uid, gid, ino, mode & 0o7777, now())
+}
@@ -0,0 +1,442 @@
+//! Import a file tree to an image and export it, for building the images
+//! without mounting them.
+//!
+//! It works on the image attached by [`dbfs_fuse_attach_image`](super::tool::dbfs_fuse_attach_image).
+use alloc::{
+    collections::BTreeMap,
+    ffi::CString,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use std::{
+    fs,
+    io::Read,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::Path,
+};
+
+use crate::{
+    attr::{
+        dbfs_common_chmod, dbfs_common_chown, dbfs_common_getxattr, dbfs_common_listxattr,
+        dbfs_common_setxattr, dbfs_common_utimens,
+    },
+    cache::dbfs_common_cache_flush_all,
+    common::{DbfsAttr, DbfsError, DbfsFileType, DbfsPermission, DbfsResult, DbfsTimeSpec},
+    file::{dbfs_common_read, dbfs_common_write},
+    fuse::tool::{dbfs_fuse_list_dir, dbfs_fuse_resolve, now, COPY_SIZE},
+    inode::{dbfs_common_attr, dbfs_common_create, dbfs_common_link, dbfs_common_rmdir},
+    link::{dbfs_common_readlink, dbfs_common_unlink},
+    tx::{dbfs_common_group_commit, dbfs_common_group_commit_setup},
+};
+
+/// A file of the tree imported to or exported from an image
+#[derive(Debug, Clone, Default)]
+pub struct DbfsTreeEntry {
+    /// The path relative to the root, the root itself is ""
+    pub path: String,
+    /// The mode including the file type
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: DbfsTimeSpec,
+    pub mtime: DbfsTimeSpec,
+    /// The device number of the device files, in the encoding of the kernel
+    pub rdev: u32,
+    /// The target of the symlink
+    pub link_target: Option<String>,
+    /// The path of the entry this one is a hard link to
+    pub hard_link: Option<String>,
+    pub xattrs: Vec<(String, Vec<u8>)>,
+}
+
+impl DbfsTreeEntry {
+    fn kind(&self) -> u32 {
+        self.mode & DbfsPermission::S_IFMT.bits() as u32
+    }
+    pub fn is_dir(&self) -> bool {
+        self.kind() == DbfsPermission::S_IFDIR.bits() as u32
+    }
+    pub fn is_file(&self) -> bool {
+        self.kind() == DbfsPermission::S_IFREG.bits() as u32
+    }
+}
+
+/// Create the entries of a tree in the attached image
+///
+/// The entries are committed in batches, the parents must be added before
+/// their children.
+pub struct DbfsImporter {
+    /// The times of the directories are set at last, adding the children changes them
+    dirs: Vec<(usize, DbfsTimeSpec, DbfsTimeSpec)>,
+    /// The inodes of the added entries, and if they were created by the importer.
+    /// All the children of a created directory are in it
+    inos: BTreeMap<String, (usize, bool)>,
+}
+
+impl DbfsImporter {
+    /// Commit `batch` operations in one transaction
+    pub fn new(batch: usize) -> DbfsResult<Self> {
+        dbfs_common_group_commit_setup(batch)?;
+        Ok(Self {
+            dirs: vec![],
+            inos: BTreeMap::new(),
+        })
+    }
+
+    /// Add an entry, `data` is the content of a regular file
+    pub fn add<R: Read>(&mut self, entry: &DbfsTreeEntry, data: &mut R) -> DbfsResult<()> {
+        let path = entry.path.trim_matches('/');
+        let ctime = now();
+        if let Some(target) = &entry.hard_link {
+            let ino = self.resolve(target.trim_matches('/'))?;
+            let (parent, name) = self.split(path)?;
+            dbfs_common_link(0, 0, ino, parent, name, ctime)?;
+            self.inos.insert(path.to_string(), (ino, true));
+            return Ok(());
+        }
+        let ino = match self.resolve(path) {
+            // keep the directory and its children
+            Ok(ino) if entry.is_dir() && dbfs_common_attr(ino)?.kind == DbfsFileType::Directory => {
+                dbfs_common_chmod(0, 0, ino, entry.mode as u16, ctime)?;
+                dbfs_common_chown(0, 0, ino, Some(entry.uid), Some(entry.gid), ctime)?;
+                let created = matches!(self.inos.get(path), Some((_, true)));
+                self.inos.insert(path.to_string(), (ino, created));
+                ino
+            }
+            Ok(ino) => {
+                self.remove(path, ino, ctime)?;
+                self.create(path, entry)?
+            }
+            Err(DbfsError::NotFound) => self.create(path, entry)?,
+            Err(e) => return Err(e),
+        };
+        if entry.is_file() {
+            let mut buf = vec![0u8; COPY_SIZE];
+            let mut offset = 0;
+            loop {
+                let count = data.read(&mut buf).map_err(|_| DbfsError::Io)?;
+                if count == 0 {
+                    break;
+                }
+                dbfs_common_write(ino, &buf[..count], offset)?;
+                offset += count as u64;
+            }
+        }
+        for (key, value) in entry.xattrs.iter() {
+            dbfs_common_setxattr(0, 0, ino, key, value, ctime)?;
+        }
+        if entry.is_dir() {
+            self.dirs.push((ino, entry.atime, entry.mtime));
+        } else {
+            dbfs_common_utimens(0, 0, ino, Some(entry.atime), Some(entry.mtime), ctime)?;
+        }
+        Ok(())
+    }
+
+    /// Resolve a path without reading the image if the importer has added it or its parent
+    fn resolve(&self, path: &str) -> DbfsResult<usize> {
+        if let Some((ino, _)) = self.inos.get(path) {
+            return Ok(*ino);
+        }
+        let parent = path.rsplit_once('/').map_or("", |x| x.0);
+        match self.inos.get(parent) {
+            Some((_, true)) if !path.is_empty() => Err(DbfsError::NotFound),
+            _ => dbfs_fuse_resolve(path),
+        }
+    }
+
+    /// Split the path into the parent directory and the name, like `split_path` of the tools
+    fn split<'a>(&self, path: &'a str) -> DbfsResult<(usize, &'a str)> {
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(DbfsError::InvalidArgument);
+        }
+        Ok((self.resolve(parent)?, name))
+    }
+
+    fn remove(&mut self, path: &str, ino: usize, ctime: DbfsTimeSpec) -> DbfsResult<()> {
+        let (parent, name) = self.split(path)?;
+        if dbfs_common_attr(ino)?.kind == DbfsFileType::Directory {
+            dbfs_common_rmdir(0, 0, parent, name, ctime)?;
+        } else {
+            dbfs_common_unlink(0, 0, parent, name, None, ctime)?;
+        }
+        let children = format!("{}/", path);
+        self.inos
+            .retain(|x, _| x != path && !x.starts_with(&children));
+        Ok(())
+    }
+
+    fn create(&mut self, path: &str, entry: &DbfsTreeEntry) -> DbfsResult<usize> {
+        let (parent, name) = self.split(path)?;
+        let permission = DbfsPermission::from_bits_truncate(entry.mode as u16);
+        let dev = match permission & DbfsPermission::S_IFMT {
+            DbfsPermission::S_IFCHR | DbfsPermission::S_IFBLK => Some(entry.rdev),
+            DbfsPermission::S_IFIFO | DbfsPermission::S_IFSOCK => Some(0),
+            _ => None,
+        };
+        let target = entry.link_target.as_deref();
+        let attr = dbfs_common_create(
+            parent,
+            name,
+            entry.uid,
+            entry.gid,
+            now(),
+            permission,
+            target,
+            dev,
+        )?;
+        self.inos.insert(path.to_string(), (attr.ino, true));
+        Ok(attr.ino)
+    }
+
+    /// Set the times of the directories and commit the rest entries
+    pub fn finish(self) -> DbfsResult<()> {
+        let ctime = now();
+        for (ino, atime, mtime) in self.dirs.into_iter().rev() {
+            dbfs_common_utimens(0, 0, ino, Some(atime), Some(mtime), ctime)?;
+        }
+        dbfs_common_cache_flush_all()?;
+        dbfs_common_group_commit()?;
+        dbfs_common_group_commit_setup(0)
+    }
+}
+
+fn host_xattrs(path: &Path) -> DbfsResult<Vec<(String, Vec<u8>)>> {
+    let name = CString::new(path.as_os_str().as_bytes()).map_err(|_| DbfsError::InvalidArgument)?;
+    let mut names = vec![0u8; 64 * 1024];
+    let len = unsafe { libc::llistxattr(name.as_ptr(), names.as_mut_ptr() as _, names.len()) };
+    if len < 0 {
+        // the filesystem may not support xattrs
+        return Ok(vec![]);
+    }
+    let mut xattrs = vec![];
+    let mut value = vec![0u8; 64 * 1024];
+    for key in names[..len as usize]
+        .split(|x| *x == 0)
+        .filter(|x| !x.is_empty())
+    {
+        let key = CString::new(key).unwrap();
+        let len = unsafe {
+            libc::lgetxattr(
+                name.as_ptr(),
+                key.as_ptr(),
+                value.as_mut_ptr() as _,
+                value.len(),
+            )
+        };
+        if len < 0 {
+            continue;
+        }
+        let key = key.into_string().map_err(|_| DbfsError::InvalidArgument)?;
+        xattrs.push((key, value[..len as usize].to_vec()));
+    }
+    Ok(xattrs)
+}
+
+/// Import the tree of a host directory to `dest` of the image
+pub fn dbfs_fuse_import_dir(
+    host: &Path,
+    dest: &str,
+    importer: &mut DbfsImporter,
+) -> DbfsResult<()> {
+    let mut links = BTreeMap::new();
+    import_host_path(
+        host,
+        dest.trim_matches('/').to_string(),
+        importer,
+        &mut links,
+    )
+}
+
+fn import_host_path(
+    host: &Path,
+    path: String,
+    importer: &mut DbfsImporter,
+    links: &mut BTreeMap<(u64, u64), String>,
+) -> DbfsResult<()> {
+    let meta = fs::symlink_metadata(host).map_err(|_| DbfsError::NotFound)?;
+    let mut entry = DbfsTreeEntry {
+        path: path.clone(),
+        mode: meta.mode(),
+        uid: meta.uid(),
+        gid: meta.gid(),
+        atime: DbfsTimeSpec::new(meta.atime() as u64, meta.atime_nsec() as u32),
+        mtime: DbfsTimeSpec::new(meta.mtime() as u64, meta.mtime_nsec() as u32),
+        // the encoding of glibc is the same as the kernel for the 32 bits numbers
+        rdev: meta.rdev() as u32,
+        ..Default::default()
+    };
+    if !meta.is_dir() && meta.nlink() > 1 {
+        match links.get(&(meta.dev(), meta.ino())) {
+            Some(first) => entry.hard_link = Some(first.clone()),
+            None => {
+                links.insert((meta.dev(), meta.ino()), path.clone());
+            }
+        }
+    }
+    if entry.hard_link.is_none() {
+        entry.xattrs = host_xattrs(host)?;
+    }
+    if meta.file_type().is_symlink() {
+        let target = fs::read_link(host).map_err(|_| DbfsError::Io)?;
+        entry.link_target = Some(target.to_string_lossy().to_string());
+    }
+    if entry.is_file() && entry.hard_link.is_none() {
+        let mut file = fs::File::open(host).map_err(|_| DbfsError::Io)?;
+        importer.add(&entry, &mut file)?;
+    } else {
+        importer.add(&entry, &mut std::io::empty())?;
+    }
+    if meta.is_dir() {
+        let mut children = fs::read_dir(host)
+            .map_err(|_| DbfsError::Io)?
+            .map(|x| x.map(|x| x.file_name()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| DbfsError::Io)?;
+        // the same tree makes the same image
+        children.sort();
+        for name in children {
+            let name = name.to_str().ok_or(DbfsError::InvalidArgument)?;
+            let child = match path.is_empty() {
+                true => name.to_string(),
+                false => format!("{}/{}", path, name),
+            };
+            import_host_path(&host.join(name), child, importer, links)?;
+        }
+    }
+    Ok(())
+}
+
+/// Read the data of a file in the image
+pub struct DbfsFileReader {
+    ino: usize,
+    offset: u64,
+}
+
+impl Read for DbfsFileReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = dbfs_common_read(self.ino, buf, self.offset)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.as_str()))?;
+        self.offset += count as u64;
+        Ok(count)
+    }
+}
+
+fn image_xattrs(ino: usize) -> DbfsResult<Vec<(String, Vec<u8>)>> {
+    let mut names = vec![0u8; dbfs_common_listxattr(0, 0, ino, &mut [])?];
+    if names.is_empty() {
+        return Ok(vec![]);
+    }
+    let len = dbfs_common_listxattr(0, 0, ino, &mut names)?;
+    let mut xattrs = vec![];
+    for key in names[..len].split(|x| *x == 0).filter(|x| !x.is_empty()) {
+        let key = core::str::from_utf8(key).map_err(|_| DbfsError::corrupted(ino, key))?;
+        let mut value = vec![0u8; dbfs_common_getxattr(0, 0, ino, key, &mut [])?];
+        if !value.is_empty() {
+            dbfs_common_getxattr(0, 0, ino, key, &mut value)?;
+        }
+        xattrs.push((key.to_string(), value));
+    }
+    Ok(xattrs)
+}
+
+/// Visit the entries of the image, the parents are visited before their children
+pub fn dbfs_fuse_export<F>(mut visit: F) -> DbfsResult<()>
+where
+    F: FnMut(&DbfsTreeEntry, &mut DbfsFileReader) -> DbfsResult<()>,
+{
+    let mut links = BTreeMap::new();
+    let root = dbfs_common_attr(1)?;
+    export_path(String::new(), &root, &mut visit, &mut links)
+}
+
+fn export_path<F>(
+    path: String,
+    attr: &DbfsAttr,
+    visit: &mut F,
+    links: &mut BTreeMap<usize, String>,
+) -> DbfsResult<()>
+where
+    F: FnMut(&DbfsTreeEntry, &mut DbfsFileReader) -> DbfsResult<()>,
+{
+    let mut entry = DbfsTreeEntry {
+        path: path.clone(),
+        mode: attr.perm as u32,
+        uid: attr.uid,
+        gid: attr.gid,
+        atime: attr.atime,
+        mtime: attr.mtime,
+        rdev: attr.rdev,
+        ..Default::default()
+    };
+    let is_dir = attr.kind == DbfsFileType::Directory;
+    if !is_dir && attr.nlink > 1 {
+        match links.get(&attr.ino) {
+            Some(first) => entry.hard_link = Some(first.clone()),
+            None => {
+                links.insert(attr.ino, path.clone());
+            }
+        }
+    }
+    if entry.hard_link.is_none() {
+        entry.xattrs = image_xattrs(attr.ino)?;
+        if attr.kind == DbfsFileType::Symlink {
+            let mut buf = vec![0u8; 4096];
+            let len = dbfs_common_readlink(attr.ino, &mut buf)?;
+            entry.link_target = Some(String::from_utf8_lossy(&buf[..len]).to_string());
+        }
+    }
+    let mut reader = DbfsFileReader {
+        ino: attr.ino,
+        offset: 0,
+    };
+    visit(&entry, &mut reader)?;
+    if is_dir {
+        for (name, child) in dbfs_fuse_list_dir(&path)? {
+            let child_path = match path.is_empty() {
+                true => name,
+                false => format!("{}/{}", path, name),
+            };
+            export_path(child_path, &child, visit, links)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fuse::test_db, inode::dbfs_common_lookup};
+
+    fn entry(path: &str, kind: DbfsPermission) -> DbfsTreeEntry {
+        DbfsTreeEntry {
+            path: path.to_string(),
+            mode: (kind | DbfsPermission::from_bits_truncate(0o755)).bits() as u32,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn importer_commits_many_entries_together() {
+        let _db = test_db();
+        let mut importer = DbfsImporter::new(16).unwrap();
+        for (path, kind) in [
+            ("import-tree", DbfsPermission::S_IFDIR),
+            ("import-tree/a", DbfsPermission::S_IFREG),
+            ("import-tree/b", DbfsPermission::S_IFREG),
+        ] {
+            importer
+                .add(&entry(path, kind), &mut std::io::empty())
+                .unwrap();
+        }
+        // the creates and the times of the files, nothing has been committed yet
+        assert_eq!(dbfs_common_group_commit().unwrap(), 5);
+        importer.finish().unwrap();
+        let dir = dbfs_fuse_resolve("import-tree").unwrap();
+        assert!(dbfs_common_lookup(dir, "a").is_ok());
+        assert!(dbfs_common_lookup(dir, "b").is_ok());
+    }
+}
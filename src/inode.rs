@@ -1,6 +1,7 @@
-use alloc::{borrow::ToOwned, format, string::ToString, sync::Arc, vec, vec::Vec};
-use core::{cmp::min, sync::atomic::AtomicUsize};
+use alloc::{borrow::ToOwned, format, string::ToString, sync::Arc, vec};
+use core::sync::atomic::AtomicUsize;
 
+use jammdb::Bucket;
 use log::{debug, error};
 use rvfs::{
     ddebug,
@@ -11,16 +12,22 @@ use rvfs::{
 };
 
 use crate::{
-    attr::clear_suid_sgid,
-    clone_db,
+    attr::{
+        clear_suid_sgid, dbfs_common_getxattr, dbfs_common_listxattr, dbfs_common_removexattr,
+        dbfs_common_setxattr,
+    },
+    cache::{cached_size, dbfs_common_cache_flush},
     common::{
-        generate_data_key, generate_data_key_with_number, DbfsAttr, DbfsError, DbfsFileType,
-        DbfsPermission, DbfsResult, DbfsTimeSpec, ACCESS_W_OK, RENAME_EXCHANGE,
+        check_mutable, generate_data_key, generate_data_key_with_number, get_flags, get_mode,
+        get_time, get_u16, get_u32, get_u64, get_usize, parse_entry_ino, DbfsAttr, DbfsError,
+        DbfsFileType, DbfsPermission, DbfsResult, DbfsTimeSpec, ACCESS_W_OK, FS_IMMUTABLE_FL,
+        RENAME_EXCHANGE, RENAME_NOREPLACE, RENAME_WHITEOUT,
     },
-    dbfs_time_spec,
+    current_credential,
     file::{DBFS_DIR_FILE_OPS, DBFS_FILE_FILE_OPS, DBFS_SYMLINK_FILE_OPS},
     link::{dbfs_common_readlink, dbfs_common_unlink},
-    u16, u32, u64, usize, SLICE_SIZE,
+    tx::dbfs_tx,
+    SLICE_SIZE,
 };
 
 pub static DBFS_INODE_NUMBER: AtomicUsize = AtomicUsize::new(1);
@@ -39,6 +46,7 @@ pub const DBFS_DIR_INODE_OPS: InodeOps = {
     ops.list_attr = dbfs_listattr;
     ops.remove_attr = dbfs_removeattr;
     ops.rename = dbfs_rename;
+    ops.mknod = dbfs_mknod;
     ops
 };
 
@@ -47,6 +55,7 @@ pub const DBFS_FILE_INODE_OPS: InodeOps = {
     ops.set_attr = dbfs_setattr;
     ops.get_attr = dbfs_getattr;
     ops.list_attr = dbfs_listattr;
+    ops.remove_attr = dbfs_removeattr;
     ops.truncate = dbfs_truncate;
     ops
 };
@@ -55,16 +64,45 @@ pub const DBFS_SYMLINK_INODE_OPS: InodeOps = {
     ops.set_attr = dbfs_setattr;
     ops.get_attr = dbfs_getattr;
     ops.list_attr = dbfs_listattr;
+    ops.remove_attr = dbfs_removeattr;
     ops.readlink = dbfs_readlink;
     ops.follow_link = dbfs_followlink;
     ops
 };
+/// The inode ops of FIFO, socket and device files, their data is handled by the kernel
+pub const DBFS_SPECIAL_INODE_OPS: InodeOps = {
+    let mut ops = InodeOps::empty();
+    ops.set_attr = dbfs_setattr;
+    ops.get_attr = dbfs_getattr;
+    ops.list_attr = dbfs_listattr;
+    ops.remove_attr = dbfs_removeattr;
+    ops
+};
 
 fn dbfs_create(dir: Arc<Inode>, dentry: Arc<DirEntry>, mode: FileMode) -> StrResult<()> {
-    dbfs_rvfs_create(dir, dentry, mode, InodeMode::S_FILE, None)
+    dbfs_rvfs_create(dir, dentry, mode, InodeMode::S_FILE, None, None)
 }
 fn dbfs_mkdir(dir: Arc<Inode>, dentry: Arc<DirEntry>, mode: FileMode) -> StrResult<()> {
-    dbfs_rvfs_create(dir, dentry, mode, InodeMode::S_DIR, None)
+    dbfs_rvfs_create(dir, dentry, mode, InodeMode::S_DIR, None, None)
+}
+fn dbfs_mknod(
+    dir: Arc<Inode>,
+    dentry: Arc<DirEntry>,
+    type_: InodeMode,
+    mode: FileMode,
+    dev: u32,
+) -> StrResult<()> {
+    match type_ {
+        InodeMode::S_FILE | InodeMode::S_FIFO | InodeMode::S_SOCK => {
+            dbfs_rvfs_create(dir, dentry, mode, type_, None, None)
+        }
+        InodeMode::S_CHARDEV | InodeMode::S_BLKDEV => {
+            dbfs_rvfs_create(dir, dentry, mode, type_, None, Some(dev))
+        }
+        // mknod(2) can't create directories
+        InodeMode::S_DIR => Err(DbfsError::PermissionDenied.as_str()),
+        _ => Err(DbfsError::InvalidArgument.as_str()),
+    }
 }
 
 fn dbfs_link(
@@ -77,8 +115,8 @@ fn dbfs_link(
     let name = new_dentry.access_inner().d_name.clone();
     let new_ino = dir.number;
 
-    let _ = dbfs_common_link(0, 0, ino, new_ino, &name, DbfsTimeSpec::default())
-        .map_err(|_| "DbfsError::NotFound")?;
+    let (uid, gid) = current_credential();
+    let _ = dbfs_common_link(uid, gid, ino, new_ino, &name, DbfsTimeSpec::default())?;
 
     // update old inode data in memory
     // update hard_links
@@ -97,8 +135,13 @@ pub fn dbfs_common_link(
     name: &str,
     ctime: DbfsTimeSpec,
 ) -> DbfsResult<DbfsAttr> {
+    // an immutable or append-only file can't get a new name
+    check_mutable(dbfs_common_attr(ino)?.flags)?;
     // checkout permission
-    let attr = dbfs_common_attr(new_ino).map_err(|_| DbfsError::NotFound)?;
+    let attr = dbfs_common_attr(new_ino)?;
+    if attr.flags & FS_IMMUTABLE_FL != 0 {
+        return Err(DbfsError::PermissionDenied);
+    }
     if !checkout_access(
         attr.uid,
         attr.gid,
@@ -110,17 +153,15 @@ pub fn dbfs_common_link(
         return Err(DbfsError::AccessError);
     }
 
-    let db = clone_db();
     // update new inode data in db
-    let tx = db.tx(true)?;
-    let bucket = tx.get_bucket(new_ino.to_be_bytes())?;
+    let tx = dbfs_tx(true)?;
+    let bucket = tx.check(|tx| Ok(tx.get_bucket(new_ino.to_be_bytes())?))?;
 
     let key = generate_data_key(name);
     let value = format!("{}", ino);
-    bucket.put(key, value).unwrap();
+    bucket.put(key, value)?;
 
-    let size = bucket.get_kv("size".to_string()).unwrap();
-    let size = usize!(size.value());
+    let size = get_usize(&bucket, new_ino, "size")?;
     bucket.put("size", (size + 1).to_be_bytes())?;
 
     // update ctime/mtime
@@ -132,15 +173,14 @@ pub fn dbfs_common_link(
     // set the new dentry's inode to old inode
 
     let old_bucket = tx.get_bucket(ino.to_be_bytes())?;
-    let hard_links = old_bucket.get_kv("hard_links".to_string()).unwrap();
-    let mut hard_links = u32!(hard_links.value());
+    let mut hard_links = get_u32(&old_bucket, ino, "hard_links")?;
     hard_links += 1;
     old_bucket.put("hard_links", hard_links.to_be_bytes())?;
     // update ctime: last change time
     old_bucket.put("ctime", ctime.to_be_bytes())?;
 
     tx.commit()?;
-    let dbfs_attr = dbfs_common_attr(ino).map_err(|_| DbfsError::NotFound)?;
+    let dbfs_attr = dbfs_common_attr(ino)?;
     Ok(dbfs_attr)
 }
 
@@ -150,17 +190,17 @@ fn dbfs_unlink(dir: Arc<Inode>, dentry: Arc<DirEntry>) -> StrResult<()> {
     let name = &dentry.access_inner().d_name;
 
     warn!("dbfs_unlink: dir.number={}, name={}", number, name);
+    let (uid, gid) = current_credential();
     dbfs_common_unlink(
-        0,
-        0,
+        uid,
+        gid,
         number,
         name,
         Some(inode.number),
         DbfsTimeSpec::default(),
-    )
-    .map_err(|_| "dbfs_common_unlink failed")?;
-    let mut inner = inode.access_inner();
-    inner.hard_links -= 1;
+    )?;
+    inode.access_inner().hard_links -= 1;
+    dir.access_inner().file_size -= 1;
     Ok(())
 }
 
@@ -171,20 +211,21 @@ fn dbfs_symlink(dir: Arc<Inode>, dentry: Arc<DirEntry>, target: &str) -> StrResu
         FileMode::FMODE_READ,
         InodeMode::S_SYMLINK,
         Some(target),
+        None,
     )
 }
 
 fn dbfs_lookup(dir: Arc<Inode>, dentry: Arc<DirEntry>) -> StrResult<()> {
     let number = dir.number;
     let name = dentry.access_inner().d_name.clone();
-    let res = dbfs_common_lookup(number, &name).map_err(|_| "dbfs_common_lookup failed")?;
+    let res = dbfs_common_lookup(number, &name)?;
     let inode_mode = InodeMode::from(res.kind);
     // create a inode according to the data in db
     let n_inode = create_tmp_inode_from_sb_blk(
         dir.super_blk.upgrade().unwrap().clone(),
         res.ino,
         inode_mode,
-        0,
+        res.rdev,
         inode_ops_from_inode_mode(inode_mode),
         file_ops_from_inode_mode(inode_mode),
         None,
@@ -198,59 +239,49 @@ fn dbfs_lookup(dir: Arc<Inode>, dentry: Arc<DirEntry>) -> StrResult<()> {
 }
 
 pub fn dbfs_common_lookup(dir: usize, name: &str) -> DbfsResult<DbfsAttr> {
-    let db = clone_db();
-    let tx = db.tx(false)?;
-    let bucket = tx.get_bucket(dir.to_be_bytes())?;
-
-    let key = generate_data_key(name);
-    let value = bucket.get_kv(key);
-    if value.is_none() {
-        return Err(DbfsError::NotFound);
-    }
-    let kv = value.unwrap();
-    let value = kv.value();
-    let str = core::str::from_utf8(value).unwrap();
-    let number = str.parse::<usize>().unwrap();
+    let number = {
+        let tx = dbfs_tx(false)?;
+        let bucket = tx.get_bucket(dir.to_be_bytes())?;
 
+        let key = generate_data_key(name);
+        let kv = bucket.get_kv(key).ok_or(DbfsError::NotFound)?;
+        parse_entry_ino(dir, &kv)?
+    };
     dbfs_common_attr(number)
 }
 
 pub fn dbfs_common_attr(number: usize) -> DbfsResult<DbfsAttr> {
-    let db = clone_db();
-    let tx = db.tx(false)?;
+    // the dirty data may extend the file
+    let cached_size = cached_size(number);
+    let tx = dbfs_tx(false)?;
     let bucket = tx.get_bucket(number.to_be_bytes())?;
-    let size = bucket.get_kv("size").unwrap();
-    let size = usize!(size.value());
-
-    let mode = bucket.get_kv("mode").unwrap();
-    let mode = u16!(mode.value());
-    let mode = DbfsPermission::from_bits(mode).unwrap();
-    let file_type = DbfsFileType::from(mode);
-
-    let n_links = bucket.get_kv("hard_links").unwrap();
-    let n_links = u32!(n_links.value());
-    let uid = bucket.get_kv("uid").unwrap();
-    let uid = u32!(uid.value());
-    let gid = bucket.get_kv("gid").unwrap();
-    let gid = u32!(gid.value());
-
-    let blksize = bucket.get_kv("block_size").unwrap();
-    let blksize = u32!(blksize.value());
+    let size = match cached_size {
+        Some(size) => size,
+        None => get_usize(&bucket, number, "size")?,
+    };
+
+    let (file_type, mode) = get_mode(&bucket, number)?;
+
+    let n_links = get_u32(&bucket, number, "hard_links")?;
+    let uid = get_u32(&bucket, number, "uid")?;
+    let gid = get_u32(&bucket, number, "gid")?;
+
+    let blksize = get_u32(&bucket, number, "block_size")?;
+    if blksize == 0 {
+        return Err(DbfsError::corrupted(number, "block_size"));
+    }
     let blocks = (size + blksize as usize - 1) / blksize as usize;
 
-    let atime = bucket.get_kv("atime").unwrap();
-    let atime = dbfs_time_spec!(atime.value());
-    let mtime = bucket.get_kv("mtime").unwrap();
-    let mtime = dbfs_time_spec!(mtime.value());
-    let ctime = bucket.get_kv("ctime").unwrap();
-    let ctime = dbfs_time_spec!(ctime.value());
+    let atime = get_time(&bucket, number, "atime")?;
+    let mtime = get_time(&bucket, number, "mtime")?;
+    let ctime = get_time(&bucket, number, "ctime")?;
 
     let rdev = if file_type == DbfsFileType::CharDevice || file_type == DbfsFileType::BlockDevice {
-        let dev = bucket.get_kv("dev").unwrap();
-        u32!(dev.value())
+        get_u32(&bucket, number, "dev")?
     } else {
         0
     };
+    let flags = get_flags(&bucket, number)?;
 
     error!(
         "[[dbfs_common_attr]]: number={}, size={}, mode={:?}, n_links={}, rdev={}",
@@ -274,7 +305,7 @@ pub fn dbfs_common_attr(number: usize) -> DbfsResult<DbfsAttr> {
         rdev,
         blksize,
         padding: 0,
-        flags: 0,
+        flags,
     };
     Ok(dbfs_attr)
 }
@@ -282,9 +313,10 @@ pub fn dbfs_common_attr(number: usize) -> DbfsResult<DbfsAttr> {
 fn dbfs_rmdir(dir: Arc<Inode>, dentry: Arc<DirEntry>) -> StrResult<()> {
     let number = dir.number;
     let name = &dentry.access_inner().d_name;
-    dbfs_common_rmdir(0, 0, number, name, DbfsTimeSpec::default()).map_err(|x| {
+    let (uid, gid) = current_credential();
+    dbfs_common_rmdir(uid, gid, number, name, DbfsTimeSpec::default()).map_err(|x| {
         warn!("dbfs_common_rmdir failed: {:?}", x);
-        "dbfs_common_rmdir failed"
+        x.as_str()
     })?;
     dir.access_inner().file_size -= 1;
     Ok(())
@@ -294,86 +326,43 @@ fn dbfs_rmdir(dir: Arc<Inode>, dentry: Arc<DirEntry>) -> StrResult<()> {
 /// if the key is already exist, it will be overwrite
 /// if the key is not exist, it will be created
 fn dbfs_setattr(dentry: Arc<DirEntry>, key: &str, val: &[u8]) -> StrResult<()> {
-    let db = clone_db();
-    let tx = db.tx(true).unwrap();
     let number = dentry.access_inner().d_inode.number;
-    let bucket = tx.get_bucket(number.to_be_bytes()).unwrap();
-    let key = format!("attr:{}", key);
-    bucket.put(key, val).unwrap();
-    tx.commit().unwrap();
+    let (uid, gid) = current_credential();
+    dbfs_common_setxattr(uid, gid, number, key, val, DbfsTimeSpec::default())?;
     Ok(())
 }
 fn dbfs_removeattr(dentry: Arc<DirEntry>, key: &str) -> StrResult<()> {
-    let db = clone_db();
-    let tx = db.tx(true).unwrap();
     let number = dentry.access_inner().d_inode.number;
-    let bucket = tx.get_bucket(number.to_be_bytes()).unwrap();
-    let key = format!("attr:{}", key);
-    let res = bucket.delete(key);
-    let res = if res.is_err() {
-        Err("not found")
-    } else {
-        Ok(())
-    };
-    tx.commit().unwrap();
-    res
+    let (uid, gid) = current_credential();
+    dbfs_common_removexattr(uid, gid, number, key, DbfsTimeSpec::default())?;
+    Ok(())
 }
 fn dbfs_getattr(dentry: Arc<DirEntry>, key: &str, buf: &mut [u8]) -> StrResult<usize> {
-    let db = clone_db();
-    let tx = db.tx(false).unwrap();
     let number = dentry.access_inner().d_inode.number;
-    let bucket = tx.get_bucket(number.to_be_bytes()).unwrap();
-    let key = format!("attr:{}", key);
-    let value = bucket.get_kv(key);
-    let value = if value.is_none() {
-        return Err("not found");
-    } else {
-        value.unwrap()
-    };
-    let value = value.value();
-    let len = min(value.len(), buf.len());
-    buf[..len].copy_from_slice(&value[..len]);
-    Ok(value.len())
+    let (uid, gid) = current_credential();
+    Ok(dbfs_common_getxattr(uid, gid, number, key, buf)?)
 }
 
 fn dbfs_listattr(dentry: Arc<DirEntry>, buf: &mut [u8]) -> StrResult<usize> {
-    let db = clone_db();
-    let tx = db.tx(false).unwrap();
     let number = dentry.access_inner().d_inode.number;
-    let bucket = tx.get_bucket(number.to_be_bytes()).unwrap();
-    let mut len = 0;
-    let mut total_attr_buf = 0;
-    for kv in bucket.kv_pairs() {
-        let key = kv.key();
-        if key.starts_with("attr:".as_bytes()) {
-            let key = key.splitn(2, |c| *c == b':').collect::<Vec<&[u8]>>();
-            let key = key[1];
-            let key_len = key.len();
-            total_attr_buf += key_len + 1;
-            if len + key_len >= buf.len() {
-                continue;
-            }
-            buf[len..len + key_len].copy_from_slice(key);
-            buf[len + key_len] = 0;
-            len += key_len + 1;
-        }
-    }
-    Ok(total_attr_buf)
+    let (uid, gid) = current_credential();
+    Ok(dbfs_common_listxattr(uid, gid, number, buf)?)
 }
 
 fn dbfs_readlink(dentry: Arc<DirEntry>, buf: &mut [u8]) -> StrResult<usize> {
     let number = dentry.access_inner().d_inode.number;
-    dbfs_common_readlink(number, buf).map_err(|_| "not a symlink")
+    Ok(dbfs_common_readlink(number, buf)?)
 }
 
 fn dbfs_followlink(dentry: Arc<DirEntry>, lookup_data: &mut LookUpData) -> StrResult<()> {
-    let db = clone_db();
-    let tx = db.tx(false).unwrap();
+    let tx = dbfs_tx(false)?;
     let number = dentry.access_inner().d_inode.number;
-    let bucket = tx.get_bucket(number.to_be_bytes()).unwrap();
-    let value = bucket.get_kv("data").unwrap();
-    let value = value.value();
-    let str = core::str::from_utf8(value).unwrap();
+    let bucket = tx
+        .get_bucket(number.to_be_bytes())
+        .map_err(DbfsError::from)?;
+    let value = bucket.get_kv("data").ok_or(DbfsError::InvalidArgument)?;
+    let str =
+        core::str::from_utf8(value.value()).map_err(|_| DbfsError::corrupted(number, "data"))?;
     lookup_data.symlink_names.push(str.to_string());
     Ok(())
 }
@@ -384,58 +373,23 @@ fn dbfs_rename(
     new_dir: Arc<Inode>,
     new_dentry: Arc<DirEntry>,
 ) -> StrResult<()> {
-    let db = clone_db();
-    let tx = db.tx(false).unwrap();
-    let old_number = old_dir.number;
-
-    let old_bucket = tx.get_bucket(old_number.to_be_bytes()).unwrap();
+    let (uid, gid) = current_credential();
     let old_name = old_dentry.access_inner().d_name.clone();
-
-    // let kv = old_bucket.kv_pairs().find(|kv| {
-    //     kv.key().starts_with("data".as_bytes()) && kv.value().starts_with(old_name.as_bytes())
-    // });
-
-    let key = generate_data_key(&old_name);
-    let kv = old_bucket.get_kv(key);
-
-    let new_number = new_dir.number;
-    if let Some(kv) = kv {
-        let key = kv.key();
-        let value = kv.value();
-        let str = core::str::from_utf8(value).unwrap();
-        let _number = str.parse::<usize>().unwrap();
-
-        let new_name = new_dentry.access_inner().d_name.clone();
-        let new_value = format!("{}", new_number);
-        let tx = db.tx(true).unwrap();
-        let old_bucket = tx.get_bucket(old_number.to_be_bytes()).unwrap();
-        if new_number == old_number {
-            // in the same bucket
-            // update old bucket
-            old_bucket.put(key, new_value).unwrap();
-        } else {
-            // in different bucket
-            let new_bucket = tx.get_bucket(new_number.to_be_bytes()).unwrap();
-            // update old bucket
-            old_bucket.delete(key).unwrap();
-            let size = old_bucket.get_kv("size").unwrap();
-            let size = usize!(size.value());
-            old_bucket.put("size", (size - 1).to_string()).unwrap();
-
-            // update new bucket
-            let new_key = generate_data_key(&new_name);
-            new_bucket.put(new_key, new_value).unwrap();
-            // update size
-            let size = new_bucket.get_kv("size").unwrap();
-            let size = usize!(size.value());
-            new_bucket.put("size", (size + 1).to_string()).unwrap();
-
-            old_dir.access_inner().file_size -= 1;
-            new_dir.access_inner().file_size += 1;
-        }
-        tx.commit().unwrap();
-    } else {
-        return Err("dbfs_rename: old_dentry not found");
+    let new_name = new_dentry.access_inner().d_name.clone();
+    dbfs_common_rename(
+        uid,
+        gid,
+        old_dir.number,
+        &old_name,
+        new_dir.number,
+        &new_name,
+        0,
+        DbfsTimeSpec::default(),
+    )?;
+    // the target may be replaced, so reload the size of the dirs
+    old_dir.access_inner().file_size = dbfs_common_attr(old_dir.number)?.size;
+    if new_dir.number != old_dir.number {
+        new_dir.access_inner().file_size = dbfs_common_attr(new_dir.number)?.size;
     }
     Ok(())
 }
@@ -444,8 +398,8 @@ fn dbfs_truncate(inode: Arc<Inode>) -> StrResult<()> {
     let number = inode.number;
     let inode_inner = inode.access_inner();
     let f_size = inode_inner.file_size;
-    let _res = dbfs_common_truncate(0, 0, number, DbfsTimeSpec::default(), f_size)
-        .map_err(|_| "dbfs_truncate: truncate failed")?;
+    let (uid, gid) = current_credential();
+    let _res = dbfs_common_truncate(uid, gid, number, DbfsTimeSpec::default(), f_size)?;
     Ok(())
 }
 
@@ -456,6 +410,10 @@ pub fn permission_from_mode(_mode: FileMode, inode_mode: InodeMode) -> DbfsPermi
         InodeMode::S_FILE => permission |= DbfsPermission::S_IFREG,
         InodeMode::S_DIR => permission |= DbfsPermission::S_IFDIR,
         InodeMode::S_SYMLINK => permission |= DbfsPermission::S_IFLNK,
+        InodeMode::S_FIFO => permission |= DbfsPermission::S_IFIFO,
+        InodeMode::S_CHARDEV => permission |= DbfsPermission::S_IFCHR,
+        InodeMode::S_BLKDEV => permission |= DbfsPermission::S_IFBLK,
+        InodeMode::S_SOCK => permission |= DbfsPermission::S_IFSOCK,
         _ => {}
     }
     permission
@@ -467,28 +425,29 @@ fn dbfs_rvfs_create(
     mode: FileMode,
     inode_mode: InodeMode,
     target_path: Option<&str>,
+    dev: Option<u32>,
 ) -> StrResult<()> {
     let dir_number = dir.number;
     let name = dentry.access_inner().d_name.to_owned();
     let permission = permission_from_mode(mode, inode_mode);
+    let (uid, gid) = current_credential();
 
     let attr = dbfs_common_create(
         dir_number,
         &name,
-        0,
-        0,
+        uid,
+        gid,
         DbfsTimeSpec::default(),
         permission,
         target_path,
-        None,
-    )
-    .map_err(|_| "dbfs_rvfs_create: dbfs_common_create failed")?;
+        dev,
+    )?;
 
     let n_inode = create_tmp_inode_from_sb_blk(
         dir.super_blk.upgrade().unwrap().clone(),
         attr.ino,
         inode_mode,
-        0,
+        attr.rdev,
         inode_ops_from_inode_mode(inode_mode),
         file_ops_from_inode_mode(inode_mode),
         None,
@@ -545,6 +504,32 @@ fn creation_gid(p_gid: u32, p_mode: DbfsPermission, gid: u32) -> u32 {
     gid
 }
 
+/// Check the permission of the create, return the gid, the mode of the parent and the type
+fn check_create(
+    parent: &Bucket<'_, '_>,
+    dir: usize,
+    uid: u32,
+    gid: u32,
+    permission: DbfsPermission,
+    target_path: Option<&str>,
+) -> DbfsResult<(u32, u16, DbfsFileType)> {
+    let p_uid = get_u32(parent, dir, "uid")?;
+    let p_gid = get_u32(parent, dir, "gid")?;
+    let p_mode = get_u16(parent, dir, "mode")?;
+    if !checkout_access(p_uid, p_gid, p_mode & 0o777, uid, gid, 0o2) {
+        return Err(DbfsError::AccessError);
+    }
+    // an append-only directory can still get new entries
+    if get_flags(parent, dir)? & FS_IMMUTABLE_FL != 0 {
+        return Err(DbfsError::PermissionDenied);
+    }
+    let kind = DbfsFileType::try_from(permission)?;
+    if kind == DbfsFileType::Symlink && target_path.is_none() {
+        return Err(DbfsError::InvalidArgument);
+    }
+    Ok((p_gid, p_mode, kind))
+}
+
 pub fn dbfs_common_create(
     dir: usize,
     name: &str,
@@ -557,36 +542,26 @@ pub fn dbfs_common_create(
 ) -> DbfsResult<DbfsAttr> {
     ddebug!("dbfs_common_create");
     let new_number = DBFS_INODE_NUMBER.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
-    let db = clone_db();
-    let tx = db.tx(true)?;
+    let tx = dbfs_tx(true)?;
 
     // find the dir
-    let parent = tx.get_bucket(dir.to_be_bytes())?;
-
-    // check the permission
-    let p_uid = parent.get_kv("uid").unwrap();
-    let p_uid = u32!(p_uid.value());
-    let p_gid = parent.get_kv("gid").unwrap();
-    let p_gid = u32!(p_gid.value());
-    let p_mode = parent.get_kv("mode").unwrap();
-    let p_mode = u16!(p_mode.value());
-    let bool = checkout_access(p_uid, p_gid, p_mode & 0o777, uid, gid, 0o2);
-    if !bool {
-        return Err(DbfsError::AccessError);
-    }
+    let (parent, (p_gid, p_mode, kind)) = tx.check(|tx| {
+        let parent = tx.get_bucket(dir.to_be_bytes())?;
+        let checked = check_create(&parent, dir, uid, gid, permission, target_path)?;
+        Ok((parent, checked))
+    })?;
 
-    let size = parent.get_kv("size").unwrap();
-    let size = usize!(size.value());
+    let size = get_usize(&parent, dir, "size")?;
     // update the size of the dir
-    parent.put("size", (size + 1).to_be_bytes()).unwrap();
+    parent.put("size", (size + 1).to_be_bytes())?;
 
     let key = generate_data_key(name);
     let value = format!("{}", new_number);
-    parent.put(key, value).unwrap(); // add a new entry to the dir
+    parent.put(key, value)?; // add a new entry to the dir
 
     // update dir ctime/mtime
-    parent.put("ctime", c_time.to_be_bytes()).unwrap();
-    parent.put("mtime", c_time.to_be_bytes()).unwrap();
+    parent.put("ctime", c_time.to_be_bytes())?;
+    parent.put("mtime", c_time.to_be_bytes())?;
 
     let mut mode = permission;
     if uid != 0 {
@@ -597,16 +572,16 @@ pub fn dbfs_common_create(
     let p_mode = DbfsPermission::from_bits_truncate(p_mode);
 
     {
-        if permission.contains(DbfsPermission::S_IFDIR) {
+        if permission & DbfsPermission::S_IFMT == DbfsPermission::S_IFDIR {
             // for dir, set the S_ISGID bit if the parent dir has the S_ISGID bit set
-            if p_mode.contains(DbfsPermission::S_IFDIR) {
+            if p_mode.contains(DbfsPermission::S_ISGID) {
                 mode |= DbfsPermission::S_ISGID;
             }
         }
     }
 
     // set the gid of inode
-    let gid = creation_gid(p_gid, permission, gid);
+    let gid = creation_gid(p_gid, p_mode, gid);
 
     // create a new inode
 
@@ -616,21 +591,16 @@ pub fn dbfs_common_create(
     new_inode.put("mode", mode.bits().to_be_bytes())?;
     // set the size of inode to 0
 
-    let (hard_link, file_size, dev) = if permission.contains(DbfsPermission::S_IFSOCK)
-        || permission.contains(DbfsPermission::S_IFCHR)
-        || permission.contains(DbfsPermission::S_IFBLK)
-        || permission.contains(DbfsPermission::S_IFIFO)
-    {
-        (1u32, 0usize, dev)
-    } else if permission.contains(DbfsPermission::S_IFDIR) {
-        (2, 2, None)
-    } else if permission.contains(DbfsPermission::S_IFLNK) {
-        assert!(target_path.is_some());
-        (1, target_path.as_ref().unwrap().len(), None)
-    } else {
-        (1, 0, None)
+    let (hard_link, file_size, dev) = match kind {
+        DbfsFileType::Socket
+        | DbfsFileType::CharDevice
+        | DbfsFileType::BlockDevice
+        | DbfsFileType::NamedPipe => (1u32, 0usize, dev),
+        DbfsFileType::Directory => (2, 2, None),
+        DbfsFileType::Symlink => (1, target_path.map_or(0, |x| x.len()), None),
+        DbfsFileType::RegularFile => (1, 0, None),
     };
-    if permission.contains(DbfsPermission::S_IFDIR) {
+    if kind == DbfsFileType::Directory {
         // new_inode.put("next_number", 2u32.to_be_bytes())?;
         let dot_value = format!("{}", new_number);
         new_inode.put(generate_data_key("."), dot_value)?;
@@ -647,12 +617,12 @@ pub fn dbfs_common_create(
     new_inode.put("ctime", c_time.to_be_bytes())?;
 
     new_inode.put("block_size", (SLICE_SIZE as u32).to_be_bytes())?;
-    if permission.contains(DbfsPermission::S_IFLNK) {
-        new_inode.put("data", target_path.unwrap())?;
+    if let (DbfsFileType::Symlink, Some(target_path)) = (kind, target_path) {
+        new_inode.put("data", target_path)?;
     }
 
-    if dev.is_some() {
-        new_inode.put("dev", dev.as_ref().unwrap().to_be_bytes())?;
+    if let Some(dev) = dev {
+        new_inode.put("dev", dev.to_be_bytes())?;
     }
 
     tx.commit()?;
@@ -670,7 +640,7 @@ pub fn dbfs_common_create(
         mtime: DbfsTimeSpec::from(c_time),
         ctime: DbfsTimeSpec::from(c_time),
         crtime: DbfsTimeSpec::default(),
-        kind: DbfsFileType::from(permission),
+        kind,
         perm: mode.bits(),
         nlink: hard_link,
         uid,
@@ -686,15 +656,11 @@ pub fn dbfs_common_create(
 }
 
 pub fn dbfs_common_access(p_uid: u32, p_gid: u32, ino: usize, mask: i32) -> DbfsResult<bool> {
-    let db = clone_db();
-    let tx = db.tx(false)?;
+    let tx = dbfs_tx(false)?;
     let inode = tx.get_bucket(ino.to_be_bytes())?;
-    let mode = inode.get_kv("mode").unwrap();
-    let mode = u16!(mode.value());
-    let uid = inode.get_kv("uid").unwrap();
-    let uid = u32!(uid.value());
-    let gid = inode.get_kv("gid").unwrap();
-    let gid = u32!(gid.value());
+    let mode = get_u16(&inode, ino, "mode")?;
+    let uid = get_u32(&inode, ino, "uid")?;
+    let gid = get_u32(&inode, ino, "gid")?;
     let res = checkout_access(p_uid, p_gid, mode, uid, gid, mask as u16);
     Ok(res)
 }
@@ -707,15 +673,14 @@ pub fn dbfs_common_truncate(
     f_size: usize,
 ) -> DbfsResult<DbfsAttr> {
     warn!("dbfs_truncate: set size to {}", f_size);
-    let mut attr = dbfs_common_attr(ino).map_err(|_| DbfsError::NotFound)?;
+    dbfs_common_cache_flush(ino)?;
+    let mut attr = dbfs_common_attr(ino)?;
     // checkout permission
     if !checkout_access(attr.uid, attr.gid, attr.perm, r_uid, r_gid, ACCESS_W_OK) {
         return Err(DbfsError::AccessError);
     }
+    check_mutable(attr.flags)?;
 
-    let db = clone_db();
-    let tx = db.tx(true)?;
-    let bucket = tx.get_bucket(ino.to_be_bytes()).unwrap();
     let start = f_size / SLICE_SIZE;
     let offset = f_size % SLICE_SIZE;
 
@@ -724,17 +689,21 @@ pub fn dbfs_common_truncate(
     // if current file size > f_size, free blocks
 
     let current_block = current_size / SLICE_SIZE;
+    let gap = f_size.saturating_sub(current_size); // newsize - oldsize
+    let tx = dbfs_tx(true)?;
+    let (bucket, sb_blk, disk_size) = tx.check(|tx| {
+        let bucket = tx.get_bucket(ino.to_be_bytes())?;
+        let sb_blk = tx.get_bucket("super_blk".as_bytes())?;
+        let disk_size = get_u64(&sb_blk, 0, "disk_size")?;
+        if current_block < start && disk_size < gap as u64 {
+            return Err(DbfsError::NoSpace);
+        }
+        Ok((bucket, sb_blk, disk_size))
+    })?;
     if current_block < start {
         // We don't need to allocate new blocks
         // When write or read occurs, it will allocate new blocks or ignore
         // We need set the size of the file
-        let sb_blk = tx.get_bucket("super_blk".as_bytes()).unwrap();
-        let disk_size = sb_blk.get_kv("disk_size").unwrap();
-        let disk_size = u64!(disk_size.value());
-        let gap = f_size.saturating_sub(current_size); // newsize - oldsize
-        if disk_size < gap as u64 {
-            return Err(DbfsError::NoSpace);
-        }
         let new_disk_size = disk_size - gap as u64;
         sb_blk.put("disk_size", new_disk_size.to_be_bytes())?;
     } else {
@@ -747,19 +716,12 @@ pub fn dbfs_common_truncate(
         }
         // fill the first data to zero
         let start_key = generate_data_key_with_number(start as u32);
-        let value = bucket.get_kv(&start_key);
-        if value.is_some() {
-            let value = value.unwrap();
+        if let Some(value) = bucket.get_kv(&start_key) {
             let mut value = value.value().to_vec();
             // set the data in offset to 0
-            for i in offset..SLICE_SIZE {
-                value[i] = 0;
-            }
-            bucket.put(start_key, value).unwrap();
+            value.iter_mut().skip(offset).for_each(|x| *x = 0);
+            bucket.put(start_key, value)?;
         }
-        let sb_blk = tx.get_bucket("super_blk".as_bytes()).unwrap();
-        let disk_size = sb_blk.get_kv("disk_size").unwrap();
-        let disk_size = u64!(disk_size.value());
         let additional_size = (current_block - start) * SLICE_SIZE; // 1 - 0
         let new_disk_size = disk_size + additional_size as u64;
         sb_blk.put("disk_size", new_disk_size.to_be_bytes())?;
@@ -790,50 +752,48 @@ pub fn dbfs_common_rmdir(
     name: &str,
     c_time: DbfsTimeSpec,
 ) -> DbfsResult<()> {
-    let db = clone_db();
-    let tx = db.tx(true)?;
-    let p_bucket = tx.get_bucket(p_ino.to_be_bytes())?;
-
     let key = generate_data_key(name);
-    let kv = p_bucket.get_kv(&key);
-    let kv = kv.unwrap();
-    let v_value = kv.value();
-    let str = core::str::from_utf8(v_value).unwrap();
-    let number = str.parse::<usize>().unwrap();
-    let bucket = tx.get_bucket(number.to_be_bytes()).unwrap();
-
-    // checkout the directory is empty
-    let size = bucket.get_kv("size").unwrap();
-    let size = usize!(size.value());
-    // if size > 2, it means the directory is not empty
-    //  Directories always have a self and parent link
-    error!("dbfs_rmdir {}: size {}", number, size);
-    if size > 2 {
-        return Err(DbfsError::NotEmpty);
-    }
-    let p_uid = p_bucket.get_kv("uid").unwrap();
-    let p_uid = u32!(p_uid.value());
-    let p_gid = p_bucket.get_kv("gid").unwrap();
-    let p_gid = u32!(p_gid.value());
-    let p_mode = p_bucket.get_kv("mode").unwrap();
-    let p_mode = u16!(p_mode.value());
-    let p_size = p_bucket.get_kv("size").unwrap();
-    let p_size = usize!(p_size.value());
-    if !checkout_access(p_uid, p_gid, p_mode & 0o777, r_uid, r_gid, ACCESS_W_OK) {
-        return Err(DbfsError::AccessError);
-    }
-    // "Sticky bit" handling
-    let uid = bucket.get_kv("uid").unwrap();
-    let uid = u32!(uid.value());
-    let p_perm = DbfsPermission::from_bits_truncate(p_mode);
-    if p_perm.contains(DbfsPermission::S_ISVTX) && r_uid != 0 && r_uid != p_uid && r_uid != uid {
-        return Err(DbfsError::AccessError);
-    }
+    let tx = dbfs_tx(true)?;
+    let (p_bucket, number, p_size) = tx.check(|tx| {
+        let p_bucket = tx.get_bucket(p_ino.to_be_bytes())?;
+
+        let kv = p_bucket.get_kv(&key).ok_or(DbfsError::NotFound)?;
+        let number = parse_entry_ino(p_ino, &kv)?;
+        let bucket = tx
+            .get_bucket(number.to_be_bytes())
+            .map_err(|_| DbfsError::corrupted(p_ino, kv.key()))?;
+
+        // checkout the directory is empty
+        let size = get_usize(&bucket, number, "size")?;
+        // if size > 2, it means the directory is not empty
+        //  Directories always have a self and parent link
+        error!("dbfs_rmdir {}: size {}", number, size);
+        if size > 2 {
+            return Err(DbfsError::NotEmpty);
+        }
+        let p_uid = get_u32(&p_bucket, p_ino, "uid")?;
+        let p_gid = get_u32(&p_bucket, p_ino, "gid")?;
+        let p_mode = get_u16(&p_bucket, p_ino, "mode")?;
+        let p_size = get_usize(&p_bucket, p_ino, "size")?;
+        if !checkout_access(p_uid, p_gid, p_mode & 0o777, r_uid, r_gid, ACCESS_W_OK) {
+            return Err(DbfsError::AccessError);
+        }
+        check_mutable(get_flags(&p_bucket, p_ino)?)?;
+        check_mutable(get_flags(&bucket, number)?)?;
+        // "Sticky bit" handling
+        let uid = get_u32(&bucket, number, "uid")?;
+        let p_perm = DbfsPermission::from_bits_truncate(p_mode);
+        if p_perm.contains(DbfsPermission::S_ISVTX) && r_uid != 0 && r_uid != p_uid && r_uid != uid
+        {
+            return Err(DbfsError::AccessError);
+        }
+        Ok((p_bucket, number, p_size))
+    })?;
     // update the parent directory
     p_bucket.put("mtime", c_time.to_be_bytes())?;
     p_bucket.put("ctime", c_time.to_be_bytes())?;
     // delete the directory
-    p_bucket.delete(kv.key())?;
+    p_bucket.delete(&key)?;
     p_bucket.put("size", (p_size - 1).to_be_bytes())?;
     // delete the inode
     tx.delete_bucket(number.to_be_bytes())?;
@@ -851,42 +811,41 @@ pub fn dbfs_common_fallocate(
     mode: u32,
     ctime: DbfsTimeSpec,
 ) -> DbfsResult<()> {
-    let db = clone_db();
-    let tx = db.tx(true)?;
-    let bucket = tx.get_bucket(ino.to_be_bytes()).unwrap();
-
-    let uid = bucket.get_kv("uid").unwrap();
-    let uid = u32!(uid.value());
-    let gid = bucket.get_kv("gid").unwrap();
-    let gid = u32!(gid.value());
-    let perm = bucket.get_kv("mode").unwrap();
-    let perm = u16!(perm.value());
-    let i_size = bucket.get_kv("size").unwrap();
-    let i_size = usize!(i_size.value());
-
-    // checkout permission
-    if !checkout_access(uid, gid, perm, r_uid, r_gid, ACCESS_W_OK) {
-        return Err(DbfsError::AccessError);
-    }
-
+    dbfs_common_cache_flush(ino)?;
     let f_size = offset + size;
     let start = f_size / SLICE_SIZE;
-    let current_size = i_size;
+    let tx = dbfs_tx(true)?;
+    let (bucket, sb_blk, disk_size, i_size) = tx.check(|tx| {
+        let bucket = tx.get_bucket(ino.to_be_bytes())?;
+
+        let uid = get_u32(&bucket, ino, "uid")?;
+        let gid = get_u32(&bucket, ino, "gid")?;
+        let perm = get_u16(&bucket, ino, "mode")?;
+        let i_size = get_usize(&bucket, ino, "size")?;
+
+        // checkout permission
+        if !checkout_access(uid, gid, perm, r_uid, r_gid, ACCESS_W_OK) {
+            return Err(DbfsError::AccessError);
+        }
+        check_mutable(get_flags(&bucket, ino)?)?;
+
+        let sb_blk = tx.get_bucket("super_blk".as_bytes())?;
+        let disk_size = get_u64(&sb_blk, 0, "disk_size")?;
+        let gap = f_size.saturating_sub(i_size); // newsize - oldsize
+        if i_size / SLICE_SIZE < start && disk_size < gap as u64 {
+            return Err(DbfsError::NoSpace);
+        }
+        Ok((bucket, sb_blk, disk_size, i_size))
+    })?;
+
     let current_block = i_size / SLICE_SIZE;
     if current_block < start {
         // We don't need to allocate new blocks
         // When write or read occurs, it will allocate new blocks or ignore
         // We need set the size of the file
-        let sb_blk = tx.get_bucket("super_blk".as_bytes()).unwrap();
-        let disk_size = sb_blk.get_kv("disk_size").unwrap();
-        let disk_size = u64!(disk_size.value());
-        let gap = f_size.saturating_sub(current_size); // newsize - oldsize
-        if disk_size < gap as u64 {
-            return Err(DbfsError::NoSpace);
-        }
+        let gap = f_size.saturating_sub(i_size);
         let new_disk_size = disk_size - gap as u64;
         sb_blk.put("disk_size", new_disk_size.to_be_bytes())?;
-    } else {
     }
     const FALLOC_FL_KEEP_SIZE: u32 = 0x01;
     if mode & FALLOC_FL_KEEP_SIZE == 0 {
@@ -911,24 +870,23 @@ pub fn dbfs_common_rename(
     flags: u32,
     ctime: DbfsTimeSpec,
 ) -> DbfsResult<()> {
-    let db = clone_db();
+    if flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE | RENAME_WHITEOUT) != 0 {
+        return Err(DbfsError::InvalidArgument);
+    }
+    // the target of an exchange always exists
+    if flags & RENAME_EXCHANGE != 0 && flags & (RENAME_NOREPLACE | RENAME_WHITEOUT) != 0 {
+        return Err(DbfsError::InvalidArgument);
+    }
     let (old_key, old_number, old_uid, old_gid, old_perm) = {
-        let tx = db.tx(false)?;
+        let tx = dbfs_tx(false)?;
         let old_dir_bucket = tx.get_bucket(old_dir.to_be_bytes())?;
 
         let key = generate_data_key(old_name);
-        let value = old_dir_bucket.get_kv(&key);
+        let value = old_dir_bucket.get_kv(&key).ok_or(DbfsError::NotFound)?;
 
-        if value.is_none() {
-            return Err(DbfsError::NotFound);
-        }
-
-        let old_dir_uid = old_dir_bucket.get_kv("uid").unwrap();
-        let old_dir_uid = u32!(old_dir_uid.value());
-        let old_dir_gid = old_dir_bucket.get_kv("gid").unwrap();
-        let old_dir_gid = u32!(old_dir_gid.value());
-        let old_dir_perm = old_dir_bucket.get_kv("mode").unwrap();
-        let old_dir_perm = u16!(old_dir_perm.value());
+        let old_dir_uid = get_u32(&old_dir_bucket, old_dir, "uid")?;
+        let old_dir_gid = get_u32(&old_dir_bucket, old_dir, "gid")?;
+        let old_dir_perm = get_u16(&old_dir_bucket, old_dir, "mode")?;
 
         if !checkout_access(
             old_dir_uid,
@@ -941,13 +899,13 @@ pub fn dbfs_common_rename(
             return Err(DbfsError::AccessError);
         }
 
-        let value = value.unwrap();
-        let v_value = value.value();
-        let str = core::str::from_utf8(v_value).unwrap();
-        let number = str.parse::<usize>().unwrap();
-        let bucket = tx.get_bucket(number.to_be_bytes()).unwrap();
-        let old_uid = bucket.get_kv("uid").unwrap();
-        let old_uid = u32!(old_uid.value());
+        let number = parse_entry_ino(old_dir, &value)?;
+        let bucket = tx
+            .get_bucket(number.to_be_bytes())
+            .map_err(|_| DbfsError::corrupted(old_dir, value.key()))?;
+        check_mutable(get_flags(&old_dir_bucket, old_dir)?)?;
+        check_mutable(get_flags(&bucket, number)?)?;
+        let old_uid = get_u32(&bucket, number, "uid")?;
 
         // "Sticky bit" handling
         let old_dir_perm = DbfsPermission::from_bits_truncate(old_dir_perm);
@@ -959,22 +917,17 @@ pub fn dbfs_common_rename(
             return Err(DbfsError::AccessError);
         }
 
-        let old_gid = bucket.get_kv("gid").unwrap();
-        let old_gid = u32!(old_gid.value());
-        let old_perm = bucket.get_kv("mode").unwrap();
-        let old_perm = u16!(old_perm.value());
+        let old_gid = get_u32(&bucket, number, "gid")?;
+        let old_perm = get_u16(&bucket, number, "mode")?;
 
         (value.key().to_owned(), number, old_uid, old_gid, old_perm)
     };
     let (new_key, new_number, new_perm, new_size) = {
-        let tx = db.tx(false)?;
+        let tx = dbfs_tx(false)?;
         let new_dir_bucket = tx.get_bucket(new_dir.to_be_bytes())?;
-        let new_dir_uid = new_dir_bucket.get_kv("uid").unwrap();
-        let new_dir_uid = u32!(new_dir_uid.value());
-        let new_dir_gid = new_dir_bucket.get_kv("gid").unwrap();
-        let new_dir_gid = u32!(new_dir_gid.value());
-        let new_dir_perm = new_dir_bucket.get_kv("mode").unwrap();
-        let new_dir_perm = u16!(new_dir_perm.value());
+        let new_dir_uid = get_u32(&new_dir_bucket, new_dir, "uid")?;
+        let new_dir_gid = get_u32(&new_dir_bucket, new_dir, "gid")?;
+        let new_dir_perm = get_u16(&new_dir_bucket, new_dir, "mode")?;
         if !checkout_access(
             new_dir_uid,
             new_dir_gid,
@@ -985,6 +938,9 @@ pub fn dbfs_common_rename(
         ) {
             return Err(DbfsError::AccessError);
         }
+        if get_flags(&new_dir_bucket, new_dir)? & FS_IMMUTABLE_FL != 0 {
+            return Err(DbfsError::PermissionDenied);
+        }
         // "Sticky bit" handling in new_parent
         // The new inode may not exist yet, so we have to check the parent
 
@@ -993,21 +949,27 @@ pub fn dbfs_common_rename(
         let key = generate_data_key(new_name);
         let value = new_dir_bucket.get_kv(&key);
 
-        if value.is_some() && new_dir_mode.contains(DbfsPermission::S_ISVTX) {
-            let value = value.unwrap();
-            let v_value = value.value();
-            let str = core::str::from_utf8(v_value).unwrap();
-            let number = str.parse::<usize>().unwrap();
-            let bucket = tx.get_bucket(number.to_be_bytes()).unwrap();
-            let new_uid = bucket.get_kv("uid").unwrap();
-            let new_uid = u32!(new_uid.value());
-            if r_uid != 0 && r_uid != new_dir_uid && r_uid != new_uid {
+        if let Some(value) = value {
+            if flags & RENAME_NOREPLACE != 0 {
+                return Err(DbfsError::FileExists);
+            }
+            let number = parse_entry_ino(new_dir, &value)?;
+            let bucket = tx
+                .get_bucket(number.to_be_bytes())
+                .map_err(|_| DbfsError::corrupted(new_dir, value.key()))?;
+            // the existing one is replaced
+            check_mutable(get_flags(&new_dir_bucket, new_dir)?)?;
+            check_mutable(get_flags(&bucket, number)?)?;
+            let new_uid = get_u32(&bucket, number, "uid")?;
+            if new_dir_mode.contains(DbfsPermission::S_ISVTX)
+                && r_uid != 0
+                && r_uid != new_dir_uid
+                && r_uid != new_uid
+            {
                 return Err(DbfsError::AccessError);
             }
-            let new_perm = bucket.get_kv("mode").unwrap();
-            let new_perm = u16!(new_perm.value());
-            let new_size = bucket.get_kv("size").unwrap();
-            let new_size = usize!(new_size.value());
+            let new_perm = get_u16(&bucket, number, "mode")?;
+            let new_size = get_usize(&bucket, number, "size")?;
 
             (value.key().to_owned(), Some(number), new_perm, new_size)
         } else {
@@ -1022,9 +984,12 @@ pub fn dbfs_common_rename(
             return Err(DbfsError::NotFound);
         }
         let new_number = new_number.unwrap();
-        let tx = db.tx(true)?;
-        let old_dir_bucket = tx.get_bucket(old_dir.to_be_bytes())?;
-        let new_dir_bucket = tx.get_bucket(new_dir.to_be_bytes())?;
+        let tx = dbfs_tx(true)?;
+        let (old_dir_bucket, new_dir_bucket) = tx.check(|tx| {
+            let old_dir_bucket = tx.get_bucket(old_dir.to_be_bytes())?;
+            let new_dir_bucket = tx.get_bucket(new_dir.to_be_bytes())?;
+            Ok((old_dir_bucket, new_dir_bucket))
+        })?;
 
         let value = format!("{}", old_number);
         new_dir_bucket.put(new_key, value)?; // new_dir insert old_name and number using new_key
@@ -1047,12 +1012,12 @@ pub fn dbfs_common_rename(
         // we know that the .. file is the second data
 
         let old_mode = DbfsPermission::from_bits_truncate(old_perm);
-        if old_mode.contains(DbfsPermission::S_IFDIR) {
+        if old_mode & DbfsPermission::S_IFMT == DbfsPermission::S_IFDIR {
             let value = format!("{}", new_dir);
-            old_bucket.put(generate_data_key("."), value)?;
+            old_bucket.put(generate_data_key(".."), value)?;
         }
         let new_mode = DbfsPermission::from_bits_truncate(new_perm);
-        if new_mode.contains(DbfsPermission::S_IFDIR) {
+        if new_mode & DbfsPermission::S_IFMT == DbfsPermission::S_IFDIR {
             let value = format!("{}", old_dir);
             new_bucket.put(generate_data_key(".."), value)?;
         }
@@ -1065,7 +1030,7 @@ pub fn dbfs_common_rename(
     // Only overwrite an existing directory if it's empty
     if new_number.is_some() {
         let perm = DbfsPermission::from_bits_truncate(new_perm);
-        if perm.contains(DbfsPermission::S_IFDIR) && new_size > 2 {
+        if perm & DbfsPermission::S_IFMT == DbfsPermission::S_IFDIR && new_size > 2 {
             return Err(DbfsError::NotEmpty);
         }
     }
@@ -1073,7 +1038,7 @@ pub fn dbfs_common_rename(
     // Only move an existing directory to a new parent, if we have write access to it,
     // because that will change the ".." link in it
     let old_mode = DbfsPermission::from_bits_truncate(old_perm);
-    if old_mode.contains(DbfsPermission::S_IFDIR)
+    if old_mode & DbfsPermission::S_IFMT == DbfsPermission::S_IFDIR
         &&  old_dir != new_dir  // different parent
         &&!checkout_access(
         old_uid,
@@ -1086,11 +1051,14 @@ pub fn dbfs_common_rename(
         return Err(DbfsError::AccessError);
     }
 
-    let tx = db.tx(true)?;
+    let tx = dbfs_tx(true)?;
     // let new_dir_bucket = tx.get_bucket(new_dir.to_be_bytes())?;
 
-    let old_dir_bucket = tx.get_bucket(old_dir.to_be_bytes())?;
-    let new_dir_bucket = tx.get_bucket(new_dir.to_be_bytes())?;
+    let (old_dir_bucket, new_dir_bucket) = tx.check(|tx| {
+        let old_dir_bucket = tx.get_bucket(old_dir.to_be_bytes())?;
+        let new_dir_bucket = tx.get_bucket(new_dir.to_be_bytes())?;
+        Ok((old_dir_bucket, new_dir_bucket))
+    })?;
 
     let old_dir_bucket = &old_dir_bucket;
 
@@ -1100,8 +1068,7 @@ pub fn dbfs_common_rename(
         &new_dir_bucket
     };
 
-    let new_dir_size = new_dir_bucket.get_kv("size").unwrap();
-    let mut new_dir_size = usize!(new_dir_size.value());
+    let mut new_dir_size = get_usize(new_dir_bucket, new_dir, "size")?;
 
     // If target already exists decrement its hardlink count
     if new_number.is_some() {
@@ -1116,15 +1083,14 @@ pub fn dbfs_common_rename(
         // 2.2 update the hardlink count
         let new_perm = DbfsPermission::from_bits_truncate(new_perm);
         let new_number = new_number.unwrap();
-        if new_perm.contains(DbfsPermission::S_IFDIR) {
+        if new_perm & DbfsPermission::S_IFMT == DbfsPermission::S_IFDIR {
             // dir don't have hardlink, so we delete it's bucket of inode
             tx.delete_bucket(new_number.to_be_bytes())?;
         } else {
             // file have hardlink, so we update the hardlink count
             let bucket = tx.get_bucket(new_number.to_be_bytes())?;
-            let hardlink = bucket.get_kv("hard_links").unwrap();
-            let hardlink = usize!(hardlink.value());
-            let hardlink = hardlink - 1;
+            let hardlink = get_u32(&bucket, new_number, "hard_links")?;
+            let hardlink = hardlink.saturating_sub(1);
             if hardlink == 0 {
                 tx.delete_bucket(new_number.to_be_bytes())?;
             } else {
@@ -1138,12 +1104,30 @@ pub fn dbfs_common_rename(
     // 3. delete the old_key
 
     old_dir_bucket.delete(old_key.as_slice())?;
-    // 3.1 update the size
-
-    let old_dir_size = old_dir_bucket.get_kv("size").unwrap();
-    let old_dir_size = usize!(old_dir_size.value());
-
-    old_dir_bucket.put("size", (old_dir_size - 1).to_be_bytes())?;
+    // 3.1 update the size, the whiteout takes the place of the old entry
+    let whiteout = flags & RENAME_WHITEOUT != 0;
+    if whiteout {
+        let number = DBFS_INODE_NUMBER.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
+        let dir_gid = get_u32(old_dir_bucket, old_dir, "gid")?;
+        let dir_mode =
+            DbfsPermission::from_bits_truncate(get_u16(old_dir_bucket, old_dir, "mode")?);
+        let gid = creation_gid(dir_gid, dir_mode, r_gid);
+        let inode = tx.create_bucket(number.to_be_bytes())?;
+        inode.put("mode", DbfsPermission::S_IFCHR.bits().to_be_bytes())?;
+        inode.put("size", 0usize.to_be_bytes())?;
+        inode.put("hard_links", 1u32.to_be_bytes())?;
+        inode.put("uid", r_uid.to_be_bytes())?;
+        inode.put("gid", gid.to_be_bytes())?;
+        inode.put("atime", ctime.to_be_bytes())?;
+        inode.put("mtime", ctime.to_be_bytes())?;
+        inode.put("ctime", ctime.to_be_bytes())?;
+        inode.put("block_size", (SLICE_SIZE as u32).to_be_bytes())?;
+        inode.put("dev", 0u32.to_be_bytes())?;
+        old_dir_bucket.put(old_key.clone(), format!("{}", number))?;
+    } else {
+        let old_dir_size = get_usize(old_dir_bucket, old_dir, "size")?;
+        old_dir_bucket.put("size", old_dir_size.saturating_sub(1).to_be_bytes())?;
+    }
 
     // debug!("we insert the old_number to new_dir :{:?}",old_number);
     // 4. insert the old_key to new_dir
@@ -1160,7 +1144,7 @@ pub fn dbfs_common_rename(
 
     // 4.1 update the size
     let new_dir_size = if old_dir == new_dir {
-        new_dir_size
+        new_dir_size + whiteout as usize
     } else {
         new_dir_size + 1
     };
@@ -1178,7 +1162,7 @@ pub fn dbfs_common_rename(
 
     // 7. update parent of old_bucket
     let old_mode = DbfsPermission::from_bits_truncate(old_perm);
-    if old_mode.contains(DbfsPermission::S_IFDIR) {
+    if old_mode & DbfsPermission::S_IFMT == DbfsPermission::S_IFDIR {
         let value = format!("{}", new_dir);
         old_bucket.put(generate_data_key(".."), value)?;
     }
@@ -1191,6 +1175,9 @@ fn inode_ops_from_inode_mode(inode_mode: InodeMode) -> InodeOps {
         InodeMode::S_FILE => DBFS_FILE_INODE_OPS,
         InodeMode::S_DIR => DBFS_DIR_INODE_OPS,
         InodeMode::S_SYMLINK => DBFS_SYMLINK_INODE_OPS,
+        InodeMode::S_FIFO | InodeMode::S_CHARDEV | InodeMode::S_BLKDEV | InodeMode::S_SOCK => {
+            DBFS_SPECIAL_INODE_OPS
+        }
         _ => InodeOps::empty(),
     }
 }
@@ -1203,3 +1190,85 @@ fn file_ops_from_inode_mode(inode_mode: InodeMode) -> FileOps {
         _ => FileOps::empty(),
     }
 }
+
+#[cfg(all(test, feature = "fuse"))]
+mod tests {
+    use super::*;
+    use crate::fuse::{test_db, tool::now};
+
+    fn create(dir: usize, name: &str, kind: DbfsPermission) -> usize {
+        let mode = kind | DbfsPermission::from_bits_truncate(0o755);
+        dbfs_common_create(dir, name, 0, 0, now(), mode, None, None)
+            .unwrap()
+            .ino
+    }
+
+    fn rename(dir: usize, old: &str, new_dir: usize, new: &str, flags: u32) -> DbfsResult<()> {
+        dbfs_common_rename(0, 0, dir, old, new_dir, new, flags, now())
+    }
+
+    #[test]
+    fn rename_noreplace() {
+        let _db = test_db();
+        let dir = create(1, "rename-noreplace", DbfsPermission::S_IFDIR);
+        let a = create(dir, "a", DbfsPermission::S_IFREG);
+        let b = create(dir, "b", DbfsPermission::S_IFREG);
+        assert!(matches!(
+            rename(dir, "a", dir, "b", RENAME_NOREPLACE),
+            Err(DbfsError::FileExists)
+        ));
+        assert_eq!(dbfs_common_lookup(dir, "a").unwrap().ino, a);
+        assert_eq!(dbfs_common_lookup(dir, "b").unwrap().ino, b);
+        rename(dir, "a", dir, "c", RENAME_NOREPLACE).unwrap();
+        assert_eq!(dbfs_common_lookup(dir, "c").unwrap().ino, a);
+        assert!(dbfs_common_lookup(dir, "a").is_err());
+        assert!(matches!(
+            rename(dir, "b", dir, "d", RENAME_NOREPLACE | RENAME_EXCHANGE),
+            Err(DbfsError::InvalidArgument)
+        ));
+    }
+
+    #[test]
+    fn rename_exchange() {
+        let _db = test_db();
+        let root = create(1, "rename-exchange", DbfsPermission::S_IFDIR);
+        let d1 = create(root, "d1", DbfsPermission::S_IFDIR);
+        let d2 = create(root, "d2", DbfsPermission::S_IFDIR);
+        let file = create(d1, "file", DbfsPermission::S_IFREG);
+        let sub = create(d2, "sub", DbfsPermission::S_IFDIR);
+        assert!(matches!(
+            rename(d1, "file", d2, "missing", RENAME_EXCHANGE),
+            Err(DbfsError::NotFound)
+        ));
+        rename(d1, "file", d2, "sub", RENAME_EXCHANGE).unwrap();
+        assert_eq!(dbfs_common_lookup(d1, "file").unwrap().ino, sub);
+        assert_eq!(dbfs_common_lookup(d2, "sub").unwrap().ino, file);
+        // the exchanged directory has a new parent
+        assert_eq!(dbfs_common_lookup(sub, "..").unwrap().ino, d1);
+        assert_eq!(dbfs_common_lookup(sub, ".").unwrap().ino, sub);
+        assert_eq!(dbfs_common_attr(d1).unwrap().size, 3);
+        assert_eq!(dbfs_common_attr(d2).unwrap().size, 3);
+    }
+
+    #[test]
+    fn rename_whiteout() {
+        let _db = test_db();
+        let dir = create(1, "rename-whiteout", DbfsPermission::S_IFDIR);
+        let other = create(1, "rename-whiteout-other", DbfsPermission::S_IFDIR);
+        let a = create(dir, "a", DbfsPermission::S_IFREG);
+        let b = create(dir, "b", DbfsPermission::S_IFREG);
+        rename(dir, "a", dir, "a2", RENAME_WHITEOUT).unwrap();
+        rename(dir, "b", other, "b", RENAME_WHITEOUT).unwrap();
+        assert_eq!(dbfs_common_lookup(dir, "a2").unwrap().ino, a);
+        assert_eq!(dbfs_common_lookup(other, "b").unwrap().ino, b);
+        for name in ["a", "b"] {
+            let whiteout = dbfs_common_lookup(dir, name).unwrap();
+            assert_eq!(whiteout.kind, DbfsFileType::CharDevice);
+            assert_eq!(whiteout.rdev, 0);
+            assert!(whiteout.ino != a && whiteout.ino != b);
+        }
+        // ".", "..", the two whiteouts and a2
+        assert_eq!(dbfs_common_attr(dir).unwrap().size, 5);
+        assert_eq!(dbfs_common_attr(other).unwrap().size, 3);
+    }
+}
@@ -1,6 +1,7 @@
 #![cfg_attr(not(test), no_std)]
 extern crate alloc;
 
+mod cache;
 mod dir;
 mod file;
 mod fs_type;
@@ -13,11 +14,20 @@ use core::{
 };
 
 use buddy_system_allocator::LockedHeap;
-pub use fs_type::DBFS;
+pub use cache::{
+    dbfs_common_cache_flush, dbfs_common_cache_flush_all, dbfs_common_cache_resize,
+    DEFAULT_CACHE_SIZE,
+};
+pub use common::{DbfsAttr, DbfsError, DbfsExtent, DbfsFileType, DbfsResult, DbfsTimeSpec};
+pub use fs_type::{
+    dbfs_common_label, dbfs_common_set_label, dbfs_common_uuid, DBFS, LABEL_MAX_LEN,
+};
 use jammdb::DB;
 use log::error;
 use spin::Once;
+pub use tx::{dbfs_common_group_commit, dbfs_common_group_commit_setup};
 pub mod extend;
+pub use file::dbfs_common_read_borrowed;
 #[cfg(feature = "fuse")]
 pub use file::FLAG;
 
@@ -30,6 +40,7 @@ extern crate std;
 mod attr;
 mod common;
 mod link;
+mod tx;
 
 struct SafeDb(DB);
 
@@ -60,6 +71,20 @@ fn clone_db() -> Arc<SafeDb> {
     DB.get().unwrap().clone()
 }
 
+static CREDENTIAL: Once<fn() -> (u32, u32)> = Once::new();
+
+/// Register the function which returns the uid and gid of the current task.
+///
+/// The rvfs callbacks use it to do the permission check, they act as root if
+/// it isn't registered.
+pub fn init_dbfs_credential(credential: fn() -> (u32, u32)) {
+    CREDENTIAL.call_once(|| credential);
+}
+
+fn current_credential() -> (u32, u32) {
+    CREDENTIAL.get().map_or((0, 0), |credential| credential())
+}
+
 #[macro_export]
 macro_rules! u32 {
     ($x:expr) => {
@@ -115,10 +140,14 @@ const MAX_BUF_SIZE: usize = 8 * 1024 * 1024; // 8MB
 pub const BUCKET_DATA_SIZE: usize = 128 * 1024 * 1024; // 512
 
 fn init_cache() {
-    error!("alloc {}MB for cache", 8);
+    init_cache_with_size(MAX_BUF_SIZE);
+}
+
+fn init_cache_with_size(size: usize) {
+    error!("alloc {}MB for cache", size / 1024 / 1024);
     unsafe {
-        let ptr = alloc(Layout::from_size_align_unchecked(MAX_BUF_SIZE, 8));
-        BUDDY_ALLOCATOR.lock().init(ptr as usize, MAX_BUF_SIZE);
+        let ptr = alloc(Layout::from_size_align_unchecked(size, 8));
+        BUDDY_ALLOCATOR.lock().init(ptr as usize, size);
     };
     error!("alloc ok");
 }
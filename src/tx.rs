@@ -0,0 +1,278 @@
+//! The transactions of DBFS
+//!
+//! In group commit mode the write operations share one transaction which is
+//! committed after `batch` operations, or when [`dbfs_common_group_commit`] is
+//! called by the timer of the caller, like the `commit=` option of ext4.
+//!
+//! The reads go through the shared transaction while it holds finished operations,
+//! so they see them without committing the group, the others use their own transactions.
+//!
+//! jammdb has no savepoints, so an operation failing halfway rolls back the whole
+//! group, as if the system crashed before the group was committed. The next
+//! [`dbfs_common_group_commit`] reports the lost operations with [`DbfsError::Io`].
+//! The checks of an operation run in `DbfsTx::check` before its first change, an
+//! operation refused by them has changed nothing and keeps the group.
+use core::{
+    cell::Cell,
+    ops::Deref,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+#[cfg(feature = "fuse")]
+use std::sync::{Mutex, MutexGuard};
+
+use jammdb::Tx;
+use log::error;
+#[cfg(not(feature = "fuse"))]
+use spin::{Mutex, MutexGuard};
+
+use crate::{
+    common::{DbfsError, DbfsResult},
+    DB,
+};
+
+pub(crate) struct GroupCommit {
+    /// The number of operations committed together, 0 disables group commit
+    batch: usize,
+    ops: usize,
+    tx: Option<Tx<'static>>,
+    /// A group has been rolled back since the last commit
+    lost: bool,
+}
+
+// The transaction is only accessed with the lock held
+unsafe impl Send for GroupCommit {}
+
+/// The lock is held across the db I/O, it blocks instead of spinning where std is available
+static GROUP_COMMIT: Mutex<GroupCommit> = Mutex::new(GroupCommit {
+    batch: 0,
+    ops: 0,
+    tx: None,
+    lost: false,
+});
+
+#[cfg(feature = "fuse")]
+fn group_lock() -> MutexGuard<'static, GroupCommit> {
+    // a panicking operation has rolled its group back when the lock is poisoned
+    GROUP_COMMIT.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(not(feature = "fuse"))]
+fn group_lock() -> MutexGuard<'static, GroupCommit> {
+    GROUP_COMMIT.lock()
+}
+
+/// The shared transaction holds finished operations, the reads go through it
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+impl GroupCommit {
+    /// Return the number of operations committed
+    fn commit(&mut self) -> DbfsResult<usize> {
+        let ops = core::mem::take(&mut self.ops);
+        PENDING.store(false, Ordering::SeqCst);
+        if let Some(tx) = self.tx.take() {
+            tx.commit()?;
+        }
+        Ok(ops)
+    }
+
+    fn rollback(&mut self) {
+        if self.ops != 0 {
+            error!(
+                "dbfs: an operation failed, {} operations of the group are lost",
+                self.ops
+            );
+            self.lost = true;
+        }
+        self.ops = 0;
+        PENDING.store(false, Ordering::SeqCst);
+        self.tx.take();
+    }
+}
+
+pub(crate) enum DbfsTx {
+    Single(Option<Tx<'static>>),
+    Group {
+        group: MutexGuard<'static, GroupCommit>,
+        done: bool,
+        /// The operation was refused by its checks
+        refused: Cell<bool>,
+    },
+    /// A read of the shared transaction
+    Shared(MutexGuard<'static, GroupCommit>),
+}
+
+impl Deref for DbfsTx {
+    type Target = Tx<'static>;
+    fn deref(&self) -> &Self::Target {
+        match self {
+            DbfsTx::Single(tx) => tx.as_ref().unwrap(),
+            DbfsTx::Group { group, .. } | DbfsTx::Shared(group) => group.tx.as_ref().unwrap(),
+        }
+    }
+}
+
+impl DbfsTx {
+    /// Finish the operation, in group commit mode the transaction is committed
+    /// when enough operations have been done
+    pub fn commit(mut self) -> DbfsResult<()> {
+        match &mut self {
+            DbfsTx::Single(tx) => Ok(tx.take().unwrap().commit()?),
+            DbfsTx::Group { group, done, .. } => {
+                *done = true;
+                group.ops += 1;
+                if group.ops >= group.batch {
+                    group.commit().map(|_| ())
+                } else {
+                    PENDING.store(true, Ordering::SeqCst);
+                    Ok(())
+                }
+            }
+            DbfsTx::Shared(_) => Ok(()),
+        }
+    }
+
+    /// Run the checks of an operation before its first change, the shared transaction
+    /// is kept if they fail
+    pub fn check<'a, T, F>(&'a self, f: F) -> DbfsResult<T>
+    where
+        F: FnOnce(&'a Tx<'static>) -> DbfsResult<T>,
+    {
+        let res = f(self);
+        if let (Err(_), DbfsTx::Group { refused, .. }) = (&res, self) {
+            refused.set(true);
+        }
+        res
+    }
+
+    /// The transaction outlives the operation, the values put must be owned by it
+    pub fn is_shared(&self) -> bool {
+        matches!(self, DbfsTx::Group { .. })
+    }
+}
+
+impl Drop for DbfsTx {
+    /// A single transaction is rolled back by jammdb, a shared one takes its group with it
+    fn drop(&mut self) {
+        if let DbfsTx::Group {
+            group,
+            done: false,
+            refused,
+        } = self
+        {
+            if !refused.get() {
+                group.rollback();
+            }
+        }
+    }
+}
+
+/// Begin a transaction, or join the shared one in group commit mode
+pub(crate) fn dbfs_tx(writable: bool) -> DbfsResult<DbfsTx> {
+    let db: &'static jammdb::DB = DB.get().unwrap();
+    if !writable {
+        if PENDING.load(Ordering::SeqCst) {
+            let group = group_lock();
+            if group.tx.is_some() {
+                return Ok(DbfsTx::Shared(group));
+            }
+        }
+        return Ok(DbfsTx::Single(Some(db.tx(false)?)));
+    }
+    let mut group = group_lock();
+    if group.batch == 0 {
+        drop(group);
+        return Ok(DbfsTx::Single(Some(db.tx(true)?)));
+    }
+    if group.tx.is_none() {
+        group.tx = Some(db.tx(true)?);
+    }
+    Ok(DbfsTx::Group {
+        group,
+        done: false,
+        refused: Cell::new(false),
+    })
+}
+
+/// Set the number of operations committed together, 0 disables group commit
+pub fn dbfs_common_group_commit_setup(batch: usize) -> DbfsResult<()> {
+    let mut group = group_lock();
+    group.commit()?;
+    group.batch = batch;
+    Ok(())
+}
+
+/// Commit the operations in the shared transaction, return the number of them.
+///
+/// The groups rolled back since the last call are reported here with [`DbfsError::Io`],
+/// after the operations of the current one are committed.
+pub fn dbfs_common_group_commit() -> DbfsResult<usize> {
+    let mut group = group_lock();
+    let ops = group.commit()?;
+    if core::mem::take(&mut group.lost) {
+        return Err(DbfsError::Io);
+    }
+    Ok(ops)
+}
+
+#[cfg(all(test, feature = "fuse"))]
+mod tests {
+    use super::*;
+    use crate::{
+        attr::{dbfs_common_chmod, dbfs_common_removexattr, dbfs_common_setxattr},
+        common::DbfsPermission,
+        fuse::{test_db, tool::now},
+        inode::{dbfs_common_create, dbfs_common_lookup, dbfs_common_rmdir},
+        link::dbfs_common_unlink,
+    };
+
+    fn create(dir: usize, name: &str, kind: DbfsPermission) -> usize {
+        let mode = kind | DbfsPermission::from_bits_truncate(0o755);
+        dbfs_common_create(dir, name, 0, 0, now(), mode, None, None)
+            .unwrap()
+            .ino
+    }
+
+    #[test]
+    fn refused_operations_keep_the_group() {
+        let _db = test_db();
+        let dir = create(1, "group-refused", DbfsPermission::S_IFDIR);
+        create(dir, "file", DbfsPermission::S_IFREG);
+        dbfs_common_group_commit_setup(16).unwrap();
+        let kept = create(1, "group-kept", DbfsPermission::S_IFREG);
+        assert!(matches!(
+            dbfs_common_rmdir(0, 0, 1, "group-refused", now()),
+            Err(DbfsError::NotEmpty)
+        ));
+        assert!(matches!(
+            dbfs_common_unlink(1000, 1000, dir, "file", None, now()),
+            Err(DbfsError::AccessError)
+        ));
+        assert!(matches!(
+            dbfs_common_removexattr(0, 0, kept, "user.missing", now()),
+            Err(DbfsError::NoData)
+        ));
+        // only the create is in the group
+        assert_eq!(dbfs_common_group_commit().unwrap(), 1);
+        dbfs_common_group_commit_setup(0).unwrap();
+        assert_eq!(dbfs_common_lookup(1, "group-kept").unwrap().ino, kept);
+        assert!(dbfs_common_lookup(dir, "file").is_ok());
+    }
+
+    #[test]
+    fn reads_see_the_group_without_committing_it() {
+        let _db = test_db();
+        dbfs_common_group_commit_setup(16).unwrap();
+        let ino = create(1, "group-read", DbfsPermission::S_IFREG);
+        assert_eq!(dbfs_common_lookup(1, "group-read").unwrap().ino, ino);
+        dbfs_common_chmod(0, 0, ino, 0o600, now()).unwrap();
+        dbfs_common_setxattr(0, 0, ino, "user.group", b"read", now()).unwrap();
+        assert_eq!(
+            dbfs_common_lookup(1, "group-read").unwrap().perm & 0o777,
+            0o600
+        );
+        // the create, the chmod and the setxattr are committed together
+        assert_eq!(dbfs_common_group_commit().unwrap(), 3);
+        dbfs_common_group_commit_setup(0).unwrap();
+    }
+}